@@ -1,128 +1,920 @@
-use anyhow::{anyhow, Context};
+use anyhow::{anyhow, bail, Context};
 use axum::{
-    extract::{Path, Query, State},
-    http::StatusCode,
+    body::{Body, Bytes},
+    extract::{
+        rejection::JsonRejection,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo, FromRequest, OptionalFromRequest, Path, Query, Request, State,
+    },
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::{self, Next},
     response::{IntoResponse, Response},
-    routing::{get, post, put},
+    routing::{delete, get, post, put},
     Json, Router,
 };
-use rusqlite::{params, Connection, OptionalExtension};
-use serde::{Deserialize, Serialize};
+use rusqlite::{
+    functions::FunctionFlags, params, params_from_iter, types::Value as SqlValue, Connection,
+    OpenFlags, OptionalExtension,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
     cmp::Reverse,
-    collections::HashSet,
+    collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet, VecDeque},
     env,
-    net::SocketAddr,
+    future::IntoFuture,
+    hash::{DefaultHasher, Hash as _, Hasher as _},
+    io::{self, IsTerminal as _, Write as _},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    ops::Deref,
+    os::unix::fs::FileTypeExt as _,
     path::{Path as FsPath, PathBuf},
-    sync::{Arc, Mutex, MutexGuard},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, TryLockError,
+    },
+    time::{Duration, Instant},
 };
 use tantivy::{
-    collector::TopDocs,
+    collector::{Count, TopDocs},
     doc,
-    query::QueryParser,
-    schema::{Field, Schema, Value, INDEXED, STORED, TEXT},
-    Index, IndexReader, IndexWriter, TantivyDocument, Term,
+    query::{
+        BooleanQuery, BoostQuery, ConstScoreQuery, FuzzyTermQuery, MoreLikeThisQuery, Occur,
+        Query as TantivyQuery, QueryParser, TermQuery,
+    },
+    schema::{
+        Field, IndexRecordOption, OwnedValue, Schema, TextFieldIndexing, TextOptions, Value,
+        INDEXED, STORED,
+    },
+    snippet::SnippetGenerator,
+    tokenizer::{
+        AsciiFoldingFilter, Language, LowerCaser, RawTokenizer, RemoveLongFilter, SimpleTokenizer,
+        Stemmer, TextAnalyzer,
+    },
+    Index, IndexReader, IndexWriter, ReloadPolicy, Searcher, TantivyDocument, Term,
+};
+use tokio::sync::{broadcast, mpsc, Notify, Semaphore, SemaphorePermit};
+use tokio_stream::wrappers::ReceiverStream;
+use tower_http::{
+    cors::{AllowHeaders, AllowOrigin, CorsLayer},
+    trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer},
+    LatencyUnit,
 };
+use tracing::{error, info, warn, Level};
+use tracing_subscriber::EnvFilter;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let config = Config::from_args()?;
+    init_tracing(&config.log_level)?;
 
     std::fs::create_dir_all(&config.data_dir)
         .with_context(|| format!("failed to create data dir {}", config.data_dir.display()))?;
 
     let db_path = config.data_dir.join("graphalfred.db");
     let index_dir = config.data_dir.join("search-index");
-    let store = Store::open(&db_path, &index_dir)?;
-
-    let state = Arc::new(Mutex::new(store));
+    let commit_policy = CommitPolicy {
+        max_pending: config.index_commit_ops,
+        max_delay: config.index_commit_interval,
+    };
+    let store = Store::open(
+        &db_path,
+        &index_dir,
+        commit_policy,
+        config.note_limits,
+        config.spawn,
+        config.search_language,
+        config.index_buffer_bytes,
+    )?;
+    let reads = ReadPool::open(&db_path, config.read_connections, config.read_timeout)?;
+
+    let state = Arc::new(AppState {
+        search: store.search.reader.clone(),
+        events: store.events.clone(),
+        store: Mutex::new(store),
+        reads,
+        search_limits: config.search_limits,
+        rendered: RenderCache::default(),
+    });
+
+    // Nothing is written in read-only mode, so neither task has work to do
+    // and the tombstone cleanup must not touch the database.
+    if !config.read_only {
+        tokio::spawn(commit_search_periodically(
+            state.clone(),
+            commit_policy.max_delay,
+        ));
+        tokio::spawn(prune_deletions_periodically(
+            state.clone(),
+            config.deletion_retention_days,
+        ));
+    }
 
     let app = Router::new()
         .route("/health", get(health))
+        .route("/workspaces", get(list_workspaces).post(create_workspace))
+        .route("/workspaces/{id}", delete(delete_workspace))
         .route("/graph", get(get_graph))
-        .route("/notes", post(create_note))
+        .route("/notes", get(list_notes).post(create_note))
+        .route("/notes/bulk", post(create_notes_bulk))
+        .route("/notes/bulk-delete", post(delete_notes_bulk))
+        .route("/notes/merge", post(merge_notes))
+        .route("/notes/viewport", get(notes_in_viewport))
         .route(
             "/notes/{id}",
-            get(get_note).put(update_note).delete(delete_note_handler),
+            get(get_note)
+                .put(update_note)
+                .patch(patch_note)
+                .delete(delete_note_handler),
         )
         .route("/notes/{id}/position", put(update_note_position))
+        .route("/notes/{id}/neighbors", get(note_neighbors))
+        .route("/notes/{id}/links", get(note_links))
+        .route("/notes/{id}/search", get(search_in_note))
+        .route("/notes/{id}/subgraph", get(note_subgraph))
+        .route("/notes/{id}/nearby", get(nearby_notes))
+        .route("/notes/{id}/suggestions", get(note_suggestions))
+        .route("/notes/{id}/similar", get(similar_notes))
+        .route("/notes/{id}/restore", post(restore_note))
+        .route("/notes/{id}/pin", post(pin_note).delete(unpin_note))
+        .route("/notes/{id}/raise", put(raise_note))
+        .route("/notes/{id}/lower", put(lower_note))
+        .route("/notes/{id}/duplicate", post(duplicate_note))
+        .route("/notes/{id}/children", post(create_child_note))
+        .route("/trash", get(list_trash))
         .route("/links", post(create_link).delete(delete_link_handler))
+        .route("/links/bulk", post(create_links_bulk))
         .route("/search", get(search_notes))
+        .route("/suggest", get(suggest_titles))
+        .route("/path", get(shortest_path))
+        .route("/components", get(connected_components))
+        .route("/communities", get(communities))
+        .route("/cycles", get(directed_cycles_handler))
+        .route("/undo", post(undo))
+        .route("/changes", get(list_changes))
+        .route("/sync", get(sync))
+        .route("/toposort", get(toposort))
+        .route("/orphans", get(list_orphans))
+        .route("/duplicates", get(list_duplicates))
+        .route("/stats", get(graph_stats))
+        .route("/centrality", get(centrality))
         .route("/layout/auto", post(auto_layout))
-        .with_state(state);
+        .route("/ws", get(watch_changes))
+        .route("/export", get(export_graph))
+        .route("/export/ndjson", get(export_ndjson))
+        .route("/export/graphml", get(export_graphml))
+        .route("/export/markdown", get(export_markdown))
+        .route("/import", post(import_graph))
+        .route("/admin/reindex", post(reindex))
+        .route("/admin/validate", post(validate))
+        .route("/admin/compact", post(compact));
+
+    let app = if config.read_only {
+        app.layer(middleware::from_fn(reject_writes))
+    } else {
+        app
+    };
+
+    let app = match &config.api_key {
+        Some(key) => app.layer(middleware::from_fn_with_state(
+            Arc::<str>::from(key.as_str()),
+            require_api_key,
+        )),
+        None => app,
+    };
+
+    let app = match config.rate_limit {
+        Some(per_second) => app.layer(middleware::from_fn_with_state(
+            Arc::new(RateLimiter::new(per_second)),
+            rate_limit,
+        )),
+        None => app,
+    };
+
+    let app = app
+        .layer(cors_layer(&config.cors_origins)?)
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
+                .on_response(
+                    DefaultOnResponse::new()
+                        .level(Level::INFO)
+                        .latency_unit(LatencyUnit::Millis),
+                ),
+        )
+        .with_state(state.clone());
+
+    let signalled = Arc::new(Notify::new());
+    match &config.unix_socket {
+        Some(path) => {
+            remove_stale_socket(path)?;
+            let listener = tokio::net::UnixListener::bind(path)
+                .with_context(|| format!("failed to bind unix socket {}", path.display()))?;
+
+            info!("GraphAlfred backend listening on unix:{}", path.display());
+
+            let served = drain_with_timeout(
+                axum::serve(listener, app.into_make_service())
+                    .with_graceful_shutdown(shutdown_signal(signalled.clone())),
+                &signalled,
+            )
+            .await;
+            if let Err(err) = std::fs::remove_file(path) {
+                warn!("failed to remove unix socket {}: {err}", path.display());
+            }
+            served.context("backend server error")?;
+        }
+        None => {
+            let addr: SocketAddr = format!("{}:{}", config.host, config.port)
+                .parse()
+                .context("failed to parse bind address")?;
 
-    let addr: SocketAddr = format!("{}:{}", config.host, config.port)
-        .parse()
-        .context("failed to parse bind address")?;
+            info!("GraphAlfred backend listening on http://{addr}");
 
-    println!("GraphAlfred backend listening on http://{addr}");
+            let listener = tokio::net::TcpListener::bind(addr)
+                .await
+                .context("failed to bind backend listener")?;
+
+            drain_with_timeout(
+                axum::serve(
+                    listener,
+                    app.into_make_service_with_connect_info::<SocketAddr>(),
+                )
+                .with_graceful_shutdown(shutdown_signal(signalled.clone())),
+                &signalled,
+            )
+            .await
+            .context("backend server error")?;
+        }
+    }
+
+    // A request wedged while holding the store lock must not keep the
+    // process alive either.
+    let close = tokio::task::spawn_blocking(move || lock_recovering(&state.store, "store").close());
+    match tokio::time::timeout(SHUTDOWN_TIMEOUT, close).await {
+        Ok(closed) => closed.context("store shutdown panicked")??,
+        Err(_) => warn!(
+            "store still busy after {}s, exiting without a final flush",
+            SHUTDOWN_TIMEOUT.as_secs()
+        ),
+    }
+
+    Ok(())
+}
 
-    let listener = tokio::net::TcpListener::bind(addr)
-        .await
-        .context("failed to bind backend listener")?;
+/// Commits buffered search index changes so searches lag writes by at most
+/// one interval.
+async fn commit_search_periodically(state: Arc<AppState>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let mut store = lock_recovering(&state.store, "store");
+        if let Err(err) = store.search.flush() {
+            error!("search index commit failed: {err:#}");
+        }
+    }
+}
 
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await
-        .context("backend server error")?;
+const DELETION_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Runs once at startup and then hourly; tombstones expire by the day, so
+/// checking more often would only cost write-lock time.
+async fn prune_deletions_periodically(state: Arc<AppState>, retention_days: u64) {
+    let mut ticker = tokio::time::interval(DELETION_PRUNE_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let mut store = lock_recovering(&state.store, "store");
+        match store.prune_deletions(retention_days) {
+            Ok(0) => {}
+            Ok(pruned) => info!("pruned {pruned} expired deletion tombstones"),
+            Err(err) => error!("pruning deletion tombstones failed: {err:#}"),
+        }
+    }
+}
 
+/// Logs to stderr. `level` is a plain level (`info`, `debug`, ...) or a full
+/// `tracing` filter such as `graphalfred_backend=debug,tower_http=info`. A
+/// plain level keeps Tantivy's chatty commit logging at `warn`.
+fn init_tracing(level: &str) -> anyhow::Result<()> {
+    let directives = if level.contains(['=', ',']) {
+        level.to_string()
+    } else {
+        format!("{level},tantivy=warn")
+    };
+    let filter =
+        EnvFilter::try_new(&directives).with_context(|| format!("invalid --log-level: {level}"))?;
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal())
+        .init();
     Ok(())
 }
 
-async fn shutdown_signal() {
-    if let Err(err) = tokio::signal::ctrl_c().await {
-        eprintln!("ctrl-c listener error: {err}");
+/// With no `--cors-origin` flags any origin is allowed, which suits local
+/// development; otherwise only the listed origins are.
+/// Request headers are mirrored rather than allowed with `*`, which browsers
+/// do not apply to `Authorization`.
+fn cors_layer(origins: &[String]) -> anyhow::Result<CorsLayer> {
+    if origins.is_empty() {
+        return Ok(CorsLayer::permissive().allow_headers(AllowHeaders::mirror_request()));
+    }
+
+    let origins = origins
+        .iter()
+        .map(|origin| {
+            HeaderValue::from_str(origin).with_context(|| format!("invalid CORS origin: {origin}"))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(CorsLayer::new()
+        .allow_origin(AllowOrigin::list(origins))
+        .allow_methods([
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::PATCH,
+            Method::DELETE,
+        ])
+        .allow_headers(AllowHeaders::mirror_request())
+        .expose_headers([header::ETAG]))
+}
+
+/// Per-client token buckets. Each IP may burst up to one second's worth of
+/// requests and then refills at `per_second`.
+struct RateLimiter {
+    per_second: f64,
+    buckets: Mutex<HashMap<IpAddr, TokenBucket>>,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+/// Past this many tracked clients, buckets that have refilled completely are
+/// forgotten so the map cannot grow without bound.
+const RATE_LIMIT_MAX_CLIENTS: usize = 1024;
+
+impl RateLimiter {
+    fn new(per_second: f64) -> Self {
+        Self {
+            per_second,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn burst(&self) -> f64 {
+        self.per_second.max(1.0)
+    }
+
+    /// Takes one token for `client`, or returns false when none is left.
+    fn try_acquire(&self, client: IpAddr) -> bool {
+        let now = Instant::now();
+        let burst = self.burst();
+        let mut buckets = lock_recovering(&self.buckets, "rate limiter");
+
+        if buckets.len() >= RATE_LIMIT_MAX_CLIENTS {
+            buckets.retain(|_, bucket| {
+                let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+                bucket.tokens + elapsed * self.per_second < burst
+            });
+        }
+
+        let bucket = buckets.entry(client).or_insert(TokenBucket {
+            tokens: burst,
+            refilled_at: now,
+        });
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(burst);
+        bucket.refilled_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Rejects requests beyond `--rate-limit` with `429`. `/health` is exempt so
+/// monitoring keeps working while a client is being throttled.
+/// Clients on the Unix socket carry no address and share the loopback bucket.
+async fn rate_limit(
+    State(limiter): State<Arc<RateLimiter>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let client = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map_or(IpAddr::V4(Ipv4Addr::LOCALHOST), |ConnectInfo(addr)| {
+            addr.ip()
+        });
+    if request.uri().path() == "/health" || limiter.try_acquire(client) {
+        next.run(request).await
+    } else {
+        ApiError::TooManyRequests("rate limit exceeded".to_string()).into_response()
+    }
+}
+
+/// Rejects requests without `Authorization: Bearer <--api-key>` with `401`.
+/// `/health` stays open for monitoring.
+async fn require_api_key(State(key): State<Arc<str>>, request: Request, next: Next) -> Response {
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| constant_time_eq(token.trim().as_bytes(), key.as_bytes()));
+    if authorized || request.uri().path() == "/health" {
+        next.run(request).await
+    } else {
+        ApiError::Unauthorized("missing or invalid API key".to_string()).into_response()
+    }
+}
+
+/// Answers `403` to every method that could change data when running with
+/// `--read-only`.
+async fn reject_writes(request: Request, next: Next) -> Response {
+    if matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    ) {
+        next.run(request).await
+    } else {
+        ApiError::Forbidden("the backend is running in read-only mode".to_string()).into_response()
+    }
+}
+
+/// Compares every byte rather than stopping at the first mismatch, so the
+/// response time does not tell a caller how much of a guessed key was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Removes a socket file left behind by a backend that did not shut down
+/// cleanly, which would otherwise make the bind fail. Anything that is not a
+/// socket is left alone.
+fn remove_stale_socket(path: &FsPath) -> anyhow::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)
+            .with_context(|| format!("failed to remove stale socket {}", path.display())),
+        Ok(_) => bail!("{} exists and is not a socket", path.display()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err).with_context(|| format!("failed to inspect {}", path.display())),
+    }
+}
+
+/// How long shutdown waits for open connections to finish, and then for the
+/// final flush, before giving up on them.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Resolves on Ctrl-C or, on Unix, SIGTERM (what `docker stop` and systemd
+/// send).
+async fn shutdown_signal(signalled: Arc<Notify>) {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            warn!("ctrl-c listener error: {err}");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(err) => {
+                warn!("SIGTERM listener error: {err}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c => {}
+        () = terminate => {}
+    }
+    info!("Shutting down backend...");
+    signalled.notify_one();
+}
+
+/// Runs the server until it has drained after the shutdown signal, but gives
+/// connections that never finish on their own (`/ws` watchers, stalled
+/// exports) only `SHUTDOWN_TIMEOUT` to go away.
+async fn drain_with_timeout(
+    server: impl IntoFuture<Output = io::Result<()>>,
+    signalled: &Notify,
+) -> io::Result<()> {
+    tokio::select! {
+        served = server.into_future() => served,
+        () = async {
+            signalled.notified().await;
+            tokio::time::sleep(SHUTDOWN_TIMEOUT).await;
+        } => {
+            warn!(
+                "connections still open after {}s, shutting down anyway",
+                SHUTDOWN_TIMEOUT.as_secs()
+            );
+            Ok(())
+        }
     }
-    println!("Shutting down backend...");
 }
 
 #[derive(Debug)]
 struct Config {
     host: String,
     port: u16,
+    /// Listen on this Unix domain socket instead of `host:port`.
+    unix_socket: Option<PathBuf>,
     data_dir: PathBuf,
+    cors_origins: Vec<String>,
+    index_commit_ops: usize,
+    index_commit_interval: Duration,
+    /// Memory the search index writer may buffer before flushing a segment.
+    index_buffer_bytes: usize,
+    read_connections: usize,
+    /// How long a read waits for a connection when the whole pool is busy.
+    read_timeout: Duration,
+    log_level: String,
+    rate_limit: Option<f64>,
+    /// Bearer token every request but `/health` must carry; `None` leaves the
+    /// API open.
+    api_key: Option<String>,
+    /// Refuse every write so a graph can be shared without risk.
+    read_only: bool,
+    /// How long `/sync` tombstones of deleted notes and links are kept.
+    deletion_retention_days: u64,
+    note_limits: NoteLimits,
+    search_limits: SearchLimits,
+    spawn: SpawnLayout,
+    /// Stemming language of the search analyzer; `None` disables stemming.
+    search_language: Option<Language>,
 }
 
-impl Config {
-    fn from_args() -> anyhow::Result<Self> {
-        let mut host = String::from("127.0.0.1");
-        let mut port = 8787;
-        let mut data_dir = default_data_dir()?;
+/// File name looked up in the data dir when `--config` is not given.
+const CONFIG_FILE_NAME: &str = "config.toml";
+
+/// Smallest writer budget tantivy accepts: 15 MB for its single indexing
+/// thread (tantivy's own constant is private).
+const MIN_INDEX_BUFFER_BYTES: usize = 15_000_000;
+
+/// Settings from one source: CLI flags or the TOML config file. Keys in the
+/// file are the flag names without the leading dashes (`cors-origins` takes a
+/// list). Unset fields fall through to the next source and then to the
+/// built-in defaults.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct ConfigLayer {
+    host: Option<String>,
+    port: Option<u16>,
+    unix_socket: Option<PathBuf>,
+    data_dir: Option<PathBuf>,
+    cors_origins: Option<Vec<String>>,
+    index_commit_ops: Option<usize>,
+    index_commit_ms: Option<u64>,
+    index_buffer_bytes: Option<usize>,
+    read_connections: Option<usize>,
+    read_timeout_ms: Option<u64>,
+    log_level: Option<String>,
+    rate_limit: Option<f64>,
+    api_key: Option<String>,
+    read_only: Option<bool>,
+    deletion_retention_days: Option<u64>,
+    max_title_chars: Option<usize>,
+    max_subtitle_chars: Option<usize>,
+    max_content_bytes: Option<usize>,
+    search_default_limit: Option<usize>,
+    search_max_limit: Option<usize>,
+    spawn_ring_slots: Option<usize>,
+    spawn_ring_step: Option<f64>,
+    search_language: Option<String>,
+}
+
+impl ConfigLayer {
+    /// Parses the command line, returning the flags and the `--config` path.
+    fn from_args(
+        mut args: impl Iterator<Item = String>,
+    ) -> anyhow::Result<(Self, Option<PathBuf>)> {
+        let mut layer = Self::default();
+        let mut config_path = None;
 
-        let mut args = env::args().skip(1);
         while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| anyhow!("missing value for {arg}"))
+            };
             match arg.as_str() {
-                "--host" => {
-                    host = args
-                        .next()
-                        .ok_or_else(|| anyhow!("missing value for --host"))?;
-                }
+                "--config" => config_path = Some(PathBuf::from(value()?)),
+                "--host" => layer.host = Some(value()?),
                 "--port" => {
-                    let raw = args
-                        .next()
-                        .ok_or_else(|| anyhow!("missing value for --port"))?;
-                    port = raw
-                        .parse::<u16>()
-                        .with_context(|| format!("invalid port: {raw}"))?;
+                    let raw = value()?;
+                    layer.port = Some(
+                        raw.parse::<u16>()
+                            .with_context(|| format!("invalid port: {raw}"))?,
+                    );
+                }
+                "--unix-socket" => layer.unix_socket = Some(PathBuf::from(value()?)),
+                "--data-dir" => layer.data_dir = Some(PathBuf::from(value()?)),
+                "--cors-origin" => {
+                    let origin = value()?;
+                    layer.cors_origins.get_or_insert_with(Vec::new).push(origin);
+                }
+                "--index-commit-ops" => {
+                    let raw = value()?;
+                    layer.index_commit_ops = Some(
+                        raw.parse::<usize>()
+                            .with_context(|| format!("invalid --index-commit-ops: {raw}"))?,
+                    );
+                }
+                "--index-commit-ms" => {
+                    let raw = value()?;
+                    layer.index_commit_ms = Some(
+                        raw.parse::<u64>()
+                            .with_context(|| format!("invalid --index-commit-ms: {raw}"))?,
+                    );
+                }
+                "--deletion-retention-days" => {
+                    let raw = value()?;
+                    layer.deletion_retention_days =
+                        Some(raw.parse::<u64>().with_context(|| {
+                            format!("invalid --deletion-retention-days: {raw}")
+                        })?);
+                }
+                "--index-buffer-bytes" => {
+                    let raw = value()?;
+                    layer.index_buffer_bytes = Some(
+                        raw.parse::<usize>()
+                            .with_context(|| format!("invalid --index-buffer-bytes: {raw}"))?,
+                    );
+                }
+                "--read-connections" => {
+                    let raw = value()?;
+                    layer.read_connections = Some(
+                        raw.parse::<usize>()
+                            .with_context(|| format!("invalid --read-connections: {raw}"))?,
+                    );
+                }
+                "--read-timeout-ms" => {
+                    let raw = value()?;
+                    layer.read_timeout_ms = Some(
+                        raw.parse::<u64>()
+                            .with_context(|| format!("invalid --read-timeout-ms: {raw}"))?,
+                    );
+                }
+                "--log-level" => layer.log_level = Some(value()?),
+                "--rate-limit" => {
+                    let raw = value()?;
+                    layer.rate_limit = Some(
+                        raw.parse::<f64>()
+                            .with_context(|| format!("invalid --rate-limit: {raw}"))?,
+                    );
+                }
+                "--api-key" => layer.api_key = Some(value()?),
+                "--read-only" => layer.read_only = Some(true),
+                "--max-title-chars" => {
+                    let raw = value()?;
+                    layer.max_title_chars = Some(
+                        raw.parse::<usize>()
+                            .with_context(|| format!("invalid --max-title-chars: {raw}"))?,
+                    );
+                }
+                "--max-subtitle-chars" => {
+                    let raw = value()?;
+                    layer.max_subtitle_chars = Some(
+                        raw.parse::<usize>()
+                            .with_context(|| format!("invalid --max-subtitle-chars: {raw}"))?,
+                    );
+                }
+                "--max-content-bytes" => {
+                    let raw = value()?;
+                    layer.max_content_bytes = Some(
+                        raw.parse::<usize>()
+                            .with_context(|| format!("invalid --max-content-bytes: {raw}"))?,
+                    );
+                }
+                "--search-language" => layer.search_language = Some(value()?),
+                "--search-default-limit" => {
+                    let raw = value()?;
+                    layer.search_default_limit = Some(
+                        raw.parse::<usize>()
+                            .with_context(|| format!("invalid --search-default-limit: {raw}"))?,
+                    );
+                }
+                "--search-max-limit" => {
+                    let raw = value()?;
+                    layer.search_max_limit = Some(
+                        raw.parse::<usize>()
+                            .with_context(|| format!("invalid --search-max-limit: {raw}"))?,
+                    );
                 }
-                "--data-dir" => {
-                    let raw = args
-                        .next()
-                        .ok_or_else(|| anyhow!("missing value for --data-dir"))?;
-                    data_dir = PathBuf::from(raw);
+                "--spawn-ring-slots" => {
+                    let raw = value()?;
+                    layer.spawn_ring_slots = Some(
+                        raw.parse::<usize>()
+                            .with_context(|| format!("invalid --spawn-ring-slots: {raw}"))?,
+                    );
+                }
+                "--spawn-ring-step" => {
+                    let raw = value()?;
+                    layer.spawn_ring_step = Some(
+                        raw.parse::<f64>()
+                            .with_context(|| format!("invalid --spawn-ring-step: {raw}"))?,
+                    );
                 }
                 _ => {}
             }
         }
 
+        Ok((layer, config_path))
+    }
+
+    fn from_file(path: &FsPath) -> anyhow::Result<Self> {
+        let raw = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        toml::from_str(&raw).with_context(|| format!("malformed config file {}", path.display()))
+    }
+
+    /// Keeps every field set here and takes the rest from `fallback`.
+    fn or(self, fallback: Self) -> Self {
+        Self {
+            host: self.host.or(fallback.host),
+            port: self.port.or(fallback.port),
+            unix_socket: self.unix_socket.or(fallback.unix_socket),
+            data_dir: self.data_dir.or(fallback.data_dir),
+            cors_origins: self.cors_origins.or(fallback.cors_origins),
+            index_commit_ops: self.index_commit_ops.or(fallback.index_commit_ops),
+            index_commit_ms: self.index_commit_ms.or(fallback.index_commit_ms),
+            index_buffer_bytes: self.index_buffer_bytes.or(fallback.index_buffer_bytes),
+            read_connections: self.read_connections.or(fallback.read_connections),
+            read_timeout_ms: self.read_timeout_ms.or(fallback.read_timeout_ms),
+            log_level: self.log_level.or(fallback.log_level),
+            rate_limit: self.rate_limit.or(fallback.rate_limit),
+            api_key: self.api_key.or(fallback.api_key),
+            read_only: self.read_only.or(fallback.read_only),
+            deletion_retention_days: self
+                .deletion_retention_days
+                .or(fallback.deletion_retention_days),
+            max_title_chars: self.max_title_chars.or(fallback.max_title_chars),
+            max_subtitle_chars: self.max_subtitle_chars.or(fallback.max_subtitle_chars),
+            max_content_bytes: self.max_content_bytes.or(fallback.max_content_bytes),
+            search_default_limit: self.search_default_limit.or(fallback.search_default_limit),
+            search_max_limit: self.search_max_limit.or(fallback.search_max_limit),
+            spawn_ring_slots: self.spawn_ring_slots.or(fallback.spawn_ring_slots),
+            spawn_ring_step: self.spawn_ring_step.or(fallback.spawn_ring_step),
+            search_language: self.search_language.or(fallback.search_language),
+        }
+    }
+}
+
+impl Config {
+    /// CLI flags win over the config file, which wins over the defaults.
+    /// Without `--config`, `config.toml` in the data dir is used if present.
+    fn from_args() -> anyhow::Result<Self> {
+        let (cli, config_path) = ConfigLayer::from_args(env::args().skip(1))?;
+
+        let file_path = match config_path {
+            Some(path) => Some(path),
+            None => {
+                let data_dir = match &cli.data_dir {
+                    Some(data_dir) => data_dir.clone(),
+                    None => default_data_dir()?,
+                };
+                Some(data_dir.join(CONFIG_FILE_NAME)).filter(|path| path.exists())
+            }
+        };
+        let file = match &file_path {
+            Some(path) => ConfigLayer::from_file(path)?,
+            None => ConfigLayer::default(),
+        };
+
+        // Checked here rather than in `resolve` so the error can say which
+        // source the value came from.
+        let buffer_source = match (cli.index_buffer_bytes, file.index_buffer_bytes, &file_path) {
+            (Some(bytes), _, _) => Some((bytes, String::from("--index-buffer-bytes"))),
+            (None, Some(bytes), Some(path)) => {
+                Some((bytes, format!("index-buffer-bytes in {}", path.display())))
+            }
+            _ => None,
+        };
+        if let Some((bytes, source)) = buffer_source {
+            if bytes < MIN_INDEX_BUFFER_BYTES {
+                bail!(
+                    "invalid {source}: {bytes} is below tantivy's minimum of \
+                     {MIN_INDEX_BUFFER_BYTES} bytes"
+                );
+            }
+        }
+
+        Self::resolve(cli.or(file))
+    }
+
+    fn resolve(layer: ConfigLayer) -> anyhow::Result<Self> {
+        let defaults = NoteLimits::default();
+        let rate_limit = layer.rate_limit.unwrap_or(0.0);
+        if !rate_limit.is_finite() || rate_limit < 0.0 {
+            bail!("invalid rate-limit: {rate_limit}");
+        }
+        if layer
+            .api_key
+            .as_deref()
+            .is_some_and(|key| key.trim().is_empty())
+        {
+            bail!("invalid api-key: must not be empty");
+        }
+        let search_defaults = SearchLimits::default();
+        let search_limits = SearchLimits {
+            default: layer
+                .search_default_limit
+                .unwrap_or(search_defaults.default),
+            max: layer.search_max_limit.unwrap_or(search_defaults.max),
+        };
+        if search_limits.default == 0 || search_limits.default > search_limits.max {
+            bail!(
+                "invalid search limits: default {} must be between 1 and the max {}",
+                search_limits.default,
+                search_limits.max
+            );
+        }
+        let spawn_defaults = SpawnLayout::default();
+        let ring_step = layer.spawn_ring_step.unwrap_or(spawn_defaults.ring_step);
+        if !ring_step.is_finite() || ring_step <= 0.0 {
+            bail!("invalid spawn-ring-step: {ring_step}");
+        }
+
         Ok(Self {
-            host,
-            port,
-            data_dir,
+            host: layer.host.unwrap_or_else(|| String::from("127.0.0.1")),
+            port: layer.port.unwrap_or(8787),
+            unix_socket: layer.unix_socket,
+            data_dir: match layer.data_dir {
+                Some(data_dir) => data_dir,
+                None => default_data_dir()?,
+            },
+            cors_origins: layer.cors_origins.unwrap_or_default(),
+            index_commit_ops: layer.index_commit_ops.unwrap_or(64).max(1),
+            index_commit_interval: Duration::from_millis(
+                layer.index_commit_ms.unwrap_or(1000).max(1),
+            ),
+            index_buffer_bytes: layer.index_buffer_bytes.unwrap_or(25_000_000),
+            read_connections: layer.read_connections.unwrap_or(4).max(1),
+            read_timeout: Duration::from_millis(layer.read_timeout_ms.unwrap_or(5000).max(1)),
+            log_level: layer.log_level.unwrap_or_else(|| String::from("info")),
+            // 0 turns limiting off, same as leaving it unset.
+            rate_limit: (rate_limit > 0.0).then_some(rate_limit),
+            api_key: layer.api_key.map(|key| key.trim().to_string()),
+            read_only: layer.read_only.unwrap_or(false),
+            deletion_retention_days: layer.deletion_retention_days.unwrap_or(30).max(1),
+            note_limits: NoteLimits {
+                max_title_chars: layer
+                    .max_title_chars
+                    .unwrap_or(defaults.max_title_chars)
+                    .max(1),
+                max_subtitle_chars: layer
+                    .max_subtitle_chars
+                    .unwrap_or(defaults.max_subtitle_chars),
+                max_content_bytes: layer
+                    .max_content_bytes
+                    .unwrap_or(defaults.max_content_bytes),
+            },
+            search_limits,
+            spawn: SpawnLayout {
+                ring_slots: layer
+                    .spawn_ring_slots
+                    .unwrap_or(spawn_defaults.ring_slots)
+                    .max(1),
+                ring_step,
+            },
+            search_language: parse_search_language(
+                layer.search_language.as_deref().unwrap_or("english"),
+            )?,
         })
     }
 }
 
+/// Accepts a stemmer language name in any case, or `none` to turn stemming
+/// off.
+fn parse_search_language(raw: &str) -> anyhow::Result<Option<Language>> {
+    let name = raw.trim().to_ascii_lowercase();
+    if name == "none" {
+        return Ok(None);
+    }
+    let mut chars = name.chars();
+    let capitalized = chars
+        .next()
+        .map(|first| first.to_ascii_uppercase().to_string() + chars.as_str())
+        .unwrap_or_default();
+    Language::deserialize(
+        serde::de::value::StrDeserializer::<serde::de::value::Error>::new(&capitalized),
+    )
+    .map(Some)
+    .map_err(|_| anyhow!("invalid search-language: {raw}"))
+}
+
 fn default_data_dir() -> anyhow::Result<PathBuf> {
     let home = env::var("HOME").context("HOME not set")?;
     Ok(PathBuf::from(home)
@@ -135,6 +927,11 @@ fn default_data_dir() -> anyhow::Result<PathBuf> {
 enum ApiError {
     BadRequest(String),
     NotFound(String),
+    Conflict(String),
+    Unauthorized(String),
+    Forbidden(String),
+    TooManyRequests(String),
+    ServiceUnavailable(String),
     Internal(anyhow::Error),
 }
 
@@ -144,6 +941,74 @@ impl From<anyhow::Error> for ApiError {
     }
 }
 
+impl From<StoreError> for ApiError {
+    fn from(value: StoreError) -> Self {
+        match value {
+            StoreError::NotFound(message) => Self::NotFound(message),
+            StoreError::Validation(message) => Self::BadRequest(message),
+            StoreError::Conflict(message) => Self::Conflict(message),
+            StoreError::Internal(err) => Self::Internal(err),
+        }
+    }
+}
+
+/// Failure of a `Store` or `Queries` method. The variant alone decides the
+/// HTTP status; database and index errors end up as `Internal`.
+#[derive(Debug)]
+enum StoreError {
+    NotFound(String),
+    Validation(String),
+    Conflict(String),
+    Internal(anyhow::Error),
+}
+
+type StoreResult<T> = Result<T, StoreError>;
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::NotFound(message)
+            | StoreError::Validation(message)
+            | StoreError::Conflict(message) => f.write_str(message),
+            StoreError::Internal(err) => write!(f, "{err:#}"),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl StoreError {
+    /// Prefixes the message with `context`, keeping the variant.
+    fn context(self, context: String) -> Self {
+        match self {
+            StoreError::NotFound(message) => StoreError::NotFound(format!("{context}: {message}")),
+            StoreError::Validation(message) => {
+                StoreError::Validation(format!("{context}: {message}"))
+            }
+            StoreError::Conflict(message) => StoreError::Conflict(format!("{context}: {message}")),
+            StoreError::Internal(err) => StoreError::Internal(err.context(context)),
+        }
+    }
+}
+
+impl From<anyhow::Error> for StoreError {
+    fn from(value: anyhow::Error) -> Self {
+        Self::Internal(value)
+    }
+}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(value: rusqlite::Error) -> Self {
+        Self::Internal(value.into())
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(value: serde_json::Error) -> Self {
+        Self::Internal(value.into())
+    }
+}
+
 impl IntoResponse for ApiError {
     fn into_response(self) -> Response {
         #[derive(Serialize)]
@@ -158,8 +1023,32 @@ impl IntoResponse for ApiError {
             ApiError::NotFound(message) => {
                 (StatusCode::NOT_FOUND, Json(ErrorBody { error: message })).into_response()
             }
+            ApiError::Conflict(message) => {
+                (StatusCode::CONFLICT, Json(ErrorBody { error: message })).into_response()
+            }
+            ApiError::Unauthorized(message) => (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, "Bearer")],
+                Json(ErrorBody { error: message }),
+            )
+                .into_response(),
+            ApiError::Forbidden(message) => {
+                (StatusCode::FORBIDDEN, Json(ErrorBody { error: message })).into_response()
+            }
+            ApiError::TooManyRequests(message) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, "1")],
+                Json(ErrorBody { error: message }),
+            )
+                .into_response(),
+            ApiError::ServiceUnavailable(message) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(header::RETRY_AFTER, "1")],
+                Json(ErrorBody { error: message }),
+            )
+                .into_response(),
             ApiError::Internal(err) => {
-                eprintln!("internal error: {err:#}");
+                error!("internal error: {err:#}");
                 (
                     StatusCode::INTERNAL_SERVER_ERROR,
                     Json(ErrorBody {
@@ -176,6 +1065,9 @@ impl IntoResponse for ApiError {
 #[serde(rename_all = "camelCase")]
 struct Note {
     id: i64,
+    /// Stable external id. Unlike `id` it survives export and import, so
+    /// other tools can link to the note by it.
+    uuid: String,
     title: String,
     subtitle: String,
     content: String,
@@ -183,22 +1075,57 @@ struct Note {
     y: f64,
     parent_id: Option<i64>,
     updated_at: String,
+    tags: Vec<String>,
+    workspace_id: i64,
+    created_at: String,
+    /// Bumped whenever the note is edited or moved; see
+    /// `UpdateNoteRequest::version`.
+    version: i64,
+    /// Hex color such as `#3366ff`; `None` renders the default style.
+    color: Option<String>,
+    icon: Option<String>,
+    pinned: bool,
+    /// Free-form attributes such as `{"status": "done"}`; always an object.
+    metadata: serde_json::Value,
+    /// Stacking order on the canvas; higher is drawn on top.
+    z: i64,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct Link {
     source_id: i64,
     target_id: i64,
+    directed: bool,
+    created_at: String,
+    /// Strength of the relationship: pulls harder in the force layout and
+    /// makes the link shorter for `/path`. Defaults to 1.
+    weight: f64,
 }
 
 #[derive(Debug, Clone, Serialize)]
 #[serde(rename_all = "camelCase")]
-struct GraphResponse {
-    notes: Vec<Note>,
+struct GraphResponse<N = Note> {
+    notes: Vec<N>,
     links: Vec<Link>,
 }
 
+/// The graph as `?format=adjacency`: every note appears in `adjacency`, with
+/// the sorted ids of the notes it shares a link with in either direction.
+#[derive(Debug, Serialize)]
+struct AdjacencyResponse {
+    notes: Vec<GraphNote>,
+    adjacency: BTreeMap<i64, Vec<i64>>,
+}
+
+/// A note of the full graph with the number of live links touching it.
+#[derive(Debug, Clone, Serialize)]
+struct GraphNote {
+    #[serde(flatten)]
+    note: Note,
+    degree: usize,
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct CreateNoteRequest {
@@ -209,829 +1136,7889 @@ struct CreateNoteRequest {
     y: Option<f64>,
     parent_id: Option<i64>,
     related_ids: Option<Vec<i64>>,
+    tags: Option<Vec<String>>,
+    color: Option<String>,
+    icon: Option<String>,
+    metadata: Option<serde_json::Value>,
+    /// Places the note next to this one instead of on the global spiral.
+    /// Ignored when both `x` and `y` are given.
+    spawn_near: Option<i64>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize)]
+struct BulkCreateResponse {
+    notes: Vec<SavedNote>,
+}
+
+#[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
-struct UpdateNoteRequest {
-    title: String,
-    subtitle: String,
-    content: String,
-    x: f64,
-    y: f64,
-    parent_id: Option<Option<i64>>,
-    related_ids: Option<Vec<i64>>,
+struct BulkDeleteResponse {
+    deleted: usize,
+    missing_ids: Vec<i64>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize)]
+struct BulkLinkResponse {
+    created: usize,
+    skipped: Vec<SkippedRow>,
+}
+
+/// A created or updated note, plus any `relatedIds` that were not linked
+/// because the note does not exist or sits in another focus layer, and the
+/// links that syncing `relatedIds` added or removed.
+#[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
-struct UpdatePositionRequest {
-    x: f64,
-    y: f64,
+struct SavedNote {
+    #[serde(flatten)]
+    note: Note,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    skipped_related_ids: Vec<i64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    added_links: Vec<Link>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    removed_links: Vec<Link>,
 }
 
+/// Outcome of `sync_related_links`.
+#[derive(Debug, Default)]
+struct RelatedLinksSync {
+    skipped: Vec<i64>,
+    added: Vec<Link>,
+    removed: Vec<Link>,
+}
+
+#[derive(Debug, Serialize)]
+struct ChildNoteResponse {
+    note: SavedNote,
+    link: Link,
+}
+
+/// A note as it appears in `/export` output. `updatedAt` is accepted but
+/// ignored; imported notes get a fresh timestamp.
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
-struct LinkRequest {
-    source_id: i64,
-    target_id: i64,
+struct ImportNote {
+    id: i64,
+    /// Kept unless another note already has it.
+    uuid: Option<String>,
+    title: String,
+    subtitle: Option<String>,
+    content: Option<String>,
+    x: Option<f64>,
+    y: Option<f64>,
+    parent_id: Option<i64>,
+    tags: Option<Vec<String>>,
+    color: Option<String>,
+    icon: Option<String>,
+    metadata: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Deserialize)]
-struct SearchQuery {
-    q: String,
-    limit: Option<usize>,
+struct ImportDocument {
+    notes: Vec<ImportNote>,
+    #[serde(default)]
+    links: Vec<LinkRequest>,
 }
 
+/// One line of `/export/ndjson` output.
 #[derive(Debug, Serialize)]
-struct SearchResponse {
-    results: Vec<Note>,
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum ExportRecord<'a> {
+    Note(&'a Note),
+    Link(&'a Link),
 }
 
-struct Store {
-    conn: Connection,
-    search: SearchIndex,
+/// One line of an NDJSON `/import` body.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum ImportRecord {
+    Note(Box<ImportNote>),
+    Link(LinkRequest),
 }
 
-impl Store {
-    fn open(db_path: &FsPath, index_dir: &FsPath) -> anyhow::Result<Self> {
-        let conn = Connection::open(db_path)
-            .with_context(|| format!("failed to open sqlite db {}", db_path.display()))?;
-        conn.pragma_update(None, "foreign_keys", "ON")?;
+/// `Json` for request bodies, except that a body which is not valid JSON or
+/// does not fit the expected shape is a `400` naming the offending field
+/// rather than axum's plain-text `422`. Other rejections (no JSON content
+/// type, unreadable body) keep axum's response.
+struct JsonBody<T>(T);
 
-        let mut store = Self {
-            conn,
-            search: SearchIndex::open(index_dir)?,
-        };
+impl<T: DeserializeOwned, S: Send + Sync> FromRequest<S> for JsonBody<T> {
+    type Rejection = Response;
 
-        store.init_schema()?;
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        <Json<T> as FromRequest<S>>::from_request(req, state)
+            .await
+            .map(|Json(value)| Self(value))
+            .map_err(json_rejection)
+    }
+}
 
-        let notes = store.list_notes()?;
-        store.search.rebuild(&notes)?;
+/// A missing body (no `Content-Type`) is `None`; anything sent must parse.
+impl<T: DeserializeOwned, S: Send + Sync> OptionalFromRequest<S> for JsonBody<T> {
+    type Rejection = Response;
 
-        Ok(store)
+    async fn from_request(req: Request, state: &S) -> Result<Option<Self>, Self::Rejection> {
+        <Json<T> as OptionalFromRequest<S>>::from_request(req, state)
+            .await
+            .map(|body| body.map(|Json(value)| Self(value)))
+            .map_err(json_rejection)
     }
+}
 
-    fn init_schema(&self) -> anyhow::Result<()> {
-        self.conn.execute_batch(
-            r#"
-            CREATE TABLE IF NOT EXISTS notes (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                title TEXT NOT NULL,
-                subtitle TEXT NOT NULL DEFAULT '',
-                content TEXT NOT NULL DEFAULT '',
-                x REAL NOT NULL DEFAULT 0,
-                y REAL NOT NULL DEFAULT 0,
-                parent_id INTEGER REFERENCES notes(id) ON DELETE SET NULL,
-                created_at TEXT NOT NULL DEFAULT (datetime('now')),
-                updated_at TEXT NOT NULL DEFAULT (datetime('now'))
-            );
+/// The serde error behind a body rejection, which names the field and
+/// position, without axum's generic prefix.
+fn json_rejection(rejection: JsonRejection) -> Response {
+    let detail = |err: &dyn std::error::Error| {
+        err.source()
+            .map_or_else(|| err.to_string(), ToString::to_string)
+    };
+    match rejection {
+        JsonRejection::JsonDataError(err) => {
+            ApiError::BadRequest(format!("invalid request body: {}", detail(&err))).into_response()
+        }
+        JsonRejection::JsonSyntaxError(err) => {
+            ApiError::BadRequest(format!("malformed JSON body: {}", detail(&err))).into_response()
+        }
+        rejection => rejection.into_response(),
+    }
+}
 
-            CREATE TABLE IF NOT EXISTS links (
-                source_id INTEGER NOT NULL REFERENCES notes(id) ON DELETE CASCADE,
-                target_id INTEGER NOT NULL REFERENCES notes(id) ON DELETE CASCADE,
-                PRIMARY KEY(source_id, target_id),
-                CHECK(source_id != target_id)
-            );
+/// The `/import` body: the JSON document `/export` writes, or the lines of
+/// `/export/ndjson` when sent as `application/x-ndjson`.
+struct ImportBody(ImportDocument);
+
+impl<S: Send + Sync> FromRequest<S> for ImportBody {
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let ndjson = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with(NDJSON_CONTENT_TYPE));
+        if !ndjson {
+            let JsonBody(document) =
+                <JsonBody<ImportDocument> as FromRequest<S>>::from_request(req, state).await?;
+            return Ok(Self(document));
+        }
 
-            CREATE TRIGGER IF NOT EXISTS notes_touch_updated_at
-            AFTER UPDATE OF title, subtitle, content, x, y, parent_id ON notes
-            BEGIN
-                UPDATE notes
-                SET updated_at = datetime('now')
+        let body = Bytes::from_request(req, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        parse_ndjson_import(&body)
+            .map(Self)
+            .map_err(|err| ApiError::from(err).into_response())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ImportMode {
+    /// Keep existing notes; imported notes whose id is taken get a new one.
+    #[default]
+    Merge,
+    /// Delete every note of the workspace (trashed ones included) before
+    /// importing.
+    Replace,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImportQuery {
+    mode: Option<ImportMode>,
+}
+
+#[derive(Debug, Serialize)]
+struct SkippedRow {
+    kind: &'static str,
+    index: usize,
+    reason: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ReindexResponse {
+    indexed_documents: usize,
+    elapsed_ms: u128,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CompactResponse {
+    /// Database file plus its write-ahead log.
+    database_bytes_before: u64,
+    database_bytes_after: u64,
+    index_bytes_before: u64,
+    index_bytes_after: u64,
+    elapsed_ms: u128,
+}
+
+#[derive(Debug, Deserialize)]
+struct ValidateQuery {
+    fix: Option<bool>,
+}
+
+/// A data-integrity problem found by `/admin/validate`, tagged with its
+/// `kind`.
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+enum IntegrityProblem {
+    /// A link to a note that does not exist.
+    #[serde(rename_all = "camelCase")]
+    DanglingLink {
+        source_id: i64,
+        target_id: i64,
+        missing_note_id: i64,
+    },
+    #[serde(rename_all = "camelCase")]
+    SelfLoop { note_id: i64 },
+    /// The reversed copy of a link between the same two notes, where at
+    /// least one of the pair is undirected.
+    #[serde(rename_all = "camelCase")]
+    DuplicateLink { source_id: i64, target_id: i64 },
+    /// A note whose `x` or `y` is missing, not a number or infinite.
+    #[serde(rename_all = "camelCase")]
+    InvalidPosition { note_id: i64 },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ValidationReport {
+    problems: Vec<IntegrityProblem>,
+    /// Rows deleted or repaired; always 0 without `fix=true`.
+    fixed: usize,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ImportResponse {
+    imported_notes: usize,
+    imported_links: usize,
+    /// Original id -> assigned id, for notes that could not keep their id.
+    remapped_ids: BTreeMap<i64, i64>,
+    skipped: Vec<SkippedRow>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UpdateNoteRequest {
+    title: String,
+    subtitle: String,
+    content: String,
+    x: f64,
+    y: f64,
+    parent_id: Option<Option<i64>>,
+    related_ids: Option<Vec<i64>>,
+    tags: Option<Vec<String>>,
+    /// Omitted keeps the current value, an empty string clears it.
+    color: Option<String>,
+    icon: Option<String>,
+    /// Replaces every attribute; omitted keeps them, `{}` clears them.
+    metadata: Option<serde_json::Value>,
+    /// Version the client last saw; falls back to the `If-Match` header.
+    version: Option<i64>,
+}
+
+/// Body of `PATCH /notes/{id}`: only the fields present are changed.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PatchNoteRequest {
+    title: Option<String>,
+    subtitle: Option<String>,
+    content: Option<String>,
+    x: Option<f64>,
+    y: Option<f64>,
+    /// `null` detaches the note from its parent.
+    #[serde(default, deserialize_with = "present")]
+    parent_id: Option<Option<i64>>,
+    related_ids: Option<Vec<i64>>,
+    tags: Option<Vec<String>>,
+    /// An empty string clears it.
+    color: Option<String>,
+    icon: Option<String>,
+    metadata: Option<serde_json::Value>,
+    /// Version the client last saw; falls back to the `If-Match` header.
+    version: Option<i64>,
+}
+
+/// Tells an explicit `null` (`Some(None)`) apart from a missing field
+/// (`None`, through `#[serde(default)]`).
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+#[derive(Debug, Serialize)]
+struct HealthResponse {
+    status: &'static str,
+    database: String,
+    search: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MergeNotesRequest {
+    keep_id: i64,
+    merge_id: i64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UndoResponse {
+    operation: String,
+    note_ids: Vec<i64>,
+}
+
+/// The state of a set of notes before a destructive operation, stored as
+/// JSON in the `operations` log so `POST /undo` can put it back.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NoteSnapshot {
+    notes: Vec<SnapshotNote>,
+    tags: Vec<(i64, String)>,
+    /// Every link touching one of the notes.
+    links: Vec<SnapshotLink>,
+    /// Parents of other notes nested under one of the notes, which a purge
+    /// or merge would otherwise leave detached.
+    children: Vec<(i64, i64)>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SnapshotNote {
+    id: i64,
+    title: String,
+    subtitle: String,
+    content: String,
+    x: f64,
+    y: f64,
+    parent_id: Option<i64>,
+    created_at: String,
+    deleted_at: Option<String>,
+    version: i64,
+    color: Option<String>,
+    icon: Option<String>,
+    pinned: bool,
+    #[serde(default)]
+    uuid: Option<String>,
+    /// Raw JSON text of the note's metadata.
+    #[serde(default)]
+    metadata: Option<String>,
+    #[serde(default)]
+    z: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SnapshotLink {
+    source_id: i64,
+    target_id: i64,
+    directed: bool,
+    wikilink: bool,
+    #[serde(default)]
+    created_at: Option<String>,
+    #[serde(default)]
+    weight: Option<f64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DuplicateNoteRequest {
+    copy_links: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UpdatePositionRequest {
+    x: f64,
+    y: f64,
+    version: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LinkRequest {
+    source_id: i64,
+    target_id: i64,
+    directed: Option<bool>,
+    /// Positive; omitted keeps an existing link's weight, or 1 for a new one.
+    weight: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListNotesQuery {
+    tag: Option<String>,
+    limit: Option<usize>,
+    offset: Option<usize>,
+    sort: Option<NoteSort>,
+    updated_after: Option<String>,
+    updated_before: Option<String>,
+}
+
+/// Inclusive `updated_at` bounds, already converted to SQLite's
+/// `YYYY-MM-DD HH:MM:SS` UTC text so plain string comparison orders them
+/// correctly.
+#[derive(Debug, Default)]
+struct UpdatedRange {
+    after: Option<String>,
+    before: Option<String>,
+}
+
+impl UpdatedRange {
+    /// The tighter of both bounds on each side.
+    fn intersect(&self, other: &Self) -> Self {
+        let tighter = |a: &Option<String>, b: &Option<String>, later: bool| match (a, b) {
+            (Some(a), Some(b)) => Some(if (a > b) == later { a } else { b }.clone()),
+            (a, b) => a.clone().or_else(|| b.clone()),
+        };
+        Self {
+            after: tighter(&self.after, &other.after, true),
+            before: tighter(&self.before, &other.before, false),
+        }
+    }
+
+    fn contains(&self, updated_at: &str) -> bool {
+        self.after
+            .as_deref()
+            .is_none_or(|after| updated_at >= after)
+            && self
+                .before
+                .as_deref()
+                .is_none_or(|before| updated_at <= before)
+    }
+}
+
+/// Sort keys accepted by `GET /notes`. Deserializing into this enum is what
+/// keeps arbitrary input out of the `ORDER BY` clause.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum NoteSort {
+    CreatedAt,
+    #[default]
+    UpdatedAt,
+    Title,
+    /// Bottom of the stack first, as the canvas draws them.
+    Z,
+}
+
+impl NoteSort {
+    fn order_by(self) -> &'static str {
+        match self {
+            NoteSort::CreatedAt => "created_at DESC, id DESC",
+            NoteSort::UpdatedAt => "updated_at DESC, id DESC",
+            NoteSort::Title => "title COLLATE NOCASE ASC, id ASC",
+            NoteSort::Z => "z ASC, id ASC",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct NoteListResponse {
+    notes: Vec<Note>,
+    total: usize,
+    limit: usize,
+    offset: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubgraphQuery {
+    depth: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NearbyQuery {
+    radius: Option<f64>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct NearbyNote {
+    #[serde(flatten)]
+    note: Note,
+    distance: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct NoteSearchQuery {
+    q: String,
+}
+
+/// Where a match sits in a note's content. `line` and `column` start at 1;
+/// `offset` (from 0) and `length` count characters, not bytes.
+#[derive(Debug, Serialize)]
+struct ContentMatch {
+    line: usize,
+    column: usize,
+    offset: usize,
+    length: usize,
+}
+
+/// `total` counts every match; `matches` holds the first
+/// `NOTE_SEARCH_MAX_MATCHES`.
+#[derive(Debug, Serialize)]
+struct NoteSearchResponse {
+    matches: Vec<ContentMatch>,
+    total: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct NearbyResponse {
+    notes: Vec<NearbyNote>,
+}
+
+/// A link seen from one of its notes: the note at the other end and its
+/// title.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NoteLink {
+    note_id: i64,
+    title: String,
+    wikilink: bool,
+    created_at: String,
+}
+
+/// Links of one note. Directed links are split by orientation; undirected
+/// ones have none.
+#[derive(Debug, Default, Serialize)]
+struct NoteLinksResponse {
+    outgoing: Vec<NoteLink>,
+    incoming: Vec<NoteLink>,
+    undirected: Vec<NoteLink>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChangesQuery {
+    since: String,
+}
+
+/// Notes and links that appeared or changed at or after `since`. A note
+/// created in that window is only listed under `created`.
+#[derive(Debug, Serialize)]
+struct ChangesResponse {
+    created: Vec<Note>,
+    updated: Vec<Note>,
+    links: Vec<Link>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SyncQuery {
+    /// `cursor` of the previous response; omitted for a full sync.
+    since: Option<String>,
+}
+
+/// Live notes and links written after the cursor, and the keys of those
+/// deleted, trashed or hidden since. Pass `cursor` as `since` next time.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SyncResponse {
+    notes: Vec<Note>,
+    links: Vec<Link>,
+    deleted_note_ids: Vec<i64>,
+    deleted_links: Vec<LinkKey>,
+    cursor: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LinkKey {
+    source_id: i64,
+    target_id: i64,
+}
+
+/// Canvas rectangle for `/notes/viewport`; bounds are inclusive.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ViewportQuery {
+    min_x: f64,
+    min_y: f64,
+    max_x: f64,
+    max_y: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct PathQuery {
+    from: i64,
+    to: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct PathResponse {
+    path: Vec<i64>,
+    length: usize,
+    /// Sum of `1 / weight` over the links of the path.
+    cost: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct ComponentsResponse {
+    components: Vec<Vec<i64>>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NoteCommunity {
+    note_id: i64,
+    community: usize,
+}
+
+/// `community` numbers run from 0, largest community first.
+#[derive(Debug, Serialize)]
+struct CommunitiesResponse {
+    count: usize,
+    communities: Vec<NoteCommunity>,
+}
+
+#[derive(Debug, Serialize)]
+struct CyclesResponse {
+    cycles: Vec<Vec<i64>>,
+}
+
+#[derive(Debug, Serialize)]
+struct ToposortResponse {
+    order: Vec<i64>,
+}
+
+/// `409` body of `/toposort` when the directed links are not acyclic.
+#[derive(Debug, Serialize)]
+struct CycleConflict {
+    error: String,
+    cycle: Vec<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateLinkQuery {
+    /// Refuse directed links that would close a cycle.
+    reject: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LimitQuery {
+    limit: Option<usize>,
+}
+
+/// `?workspace=<id>`, accepted by every endpoint.
+#[derive(Debug, Deserialize)]
+struct WorkspaceQuery {
+    workspace: Option<i64>,
+}
+
+impl WorkspaceQuery {
+    /// The requested workspace, or the default one; 404 if it does not exist.
+    fn resolve(&self, conn: &Connection) -> Result<i64, ApiError> {
+        let id = self.workspace.unwrap_or(DEFAULT_WORKSPACE_ID);
+        let exists = conn
+            .query_row(
+                "SELECT EXISTS(SELECT 1 FROM workspaces WHERE id = ?1)",
+                [id],
+                |row| row.get::<_, i64>(0),
+            )
+            .map_err(anyhow::Error::from)?
+            == 1;
+
+        if exists {
+            Ok(id)
+        } else {
+            Err(ApiError::NotFound(format!("workspace {id} not found")))
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Workspace {
+    id: i64,
+    name: String,
+    created_at: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateWorkspaceRequest {
+    name: String,
+}
+
+#[derive(Debug, Serialize)]
+struct WorkspacesResponse {
+    workspaces: Vec<Workspace>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeleteNoteQuery {
+    purge: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TrashedNote {
+    #[serde(flatten)]
+    note: Note,
+    deleted_at: String,
+}
+
+#[derive(Debug, Serialize)]
+struct TrashResponse {
+    notes: Vec<TrashedNote>,
+}
+
+#[derive(Debug, Serialize)]
+struct OrphansResponse {
+    notes: Vec<Note>,
+}
+
+/// Notes whose titles match once trimmed and lowercased; `title` is that
+/// normalized form.
+#[derive(Debug, Serialize)]
+struct DuplicateGroup {
+    title: String,
+    notes: Vec<Note>,
+}
+
+#[derive(Debug, Serialize)]
+struct DuplicatesResponse {
+    groups: Vec<DuplicateGroup>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GetNoteQuery {
+    render: Option<NoteRender>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum NoteRender {
+    Html,
+}
+
+/// A note with its Markdown content rendered, for `?render=html`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RenderedNote {
+    #[serde(flatten)]
+    note: Note,
+    content_html: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SimilarQuery {
+    limit: Option<usize>,
+    /// Leave out notes already linked to the note.
+    exclude_linked: Option<bool>,
+}
+
+/// A note whose text resembles the one asked about, with its relevance.
+#[derive(Debug, Serialize)]
+struct SimilarNote {
+    #[serde(flatten)]
+    note: Note,
+    score: f32,
+}
+
+#[derive(Debug, Serialize)]
+struct SimilarResponse {
+    notes: Vec<SimilarNote>,
+}
+
+/// A note not yet linked to the one asked about, with its Adamic-Adar score
+/// and how many neighbors the two share.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SuggestedNote {
+    #[serde(flatten)]
+    note: Note,
+    score: f64,
+    shared_neighbors: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct SuggestionsResponse {
+    notes: Vec<SuggestedNote>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum CentralityMetric {
+    #[default]
+    PageRank,
+    Degree,
+    Betweenness,
+}
+
+#[derive(Debug, Deserialize)]
+struct CentralityQuery {
+    metric: Option<CentralityMetric>,
+}
+
+#[derive(Debug, Serialize)]
+struct CentralityScore {
+    id: i64,
+    score: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct CentralityResponse {
+    metric: CentralityMetric,
+    scores: Vec<CentralityScore>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum LayoutAlgorithm {
+    #[default]
+    Ring,
+    Force,
+    Tree,
+}
+
+#[derive(Debug, Deserialize)]
+struct LayoutQuery {
+    algorithm: Option<LayoutAlgorithm>,
+    root: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LayoutResponse {
+    /// Number of notes that were given a new position.
+    laid_out: usize,
+    #[serde(flatten)]
+    graph: GraphResponse,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphStats {
+    note_count: usize,
+    link_count: usize,
+    average_degree: f64,
+    density: f64,
+    component_count: usize,
+    max_degree: usize,
+    min_degree: usize,
+    median_degree: f64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SearchQuery {
+    q: String,
+    limit: Option<usize>,
+    fuzziness: Option<u8>,
+    updated_after: Option<String>,
+    updated_before: Option<String>,
+}
+
+/// Narrows `/graph` to the notes matching a tag and/or a search query.
+#[derive(Debug, Deserialize)]
+struct GraphQuery {
+    tag: Option<String>,
+    q: Option<String>,
+    #[serde(default)]
+    format: GraphFormat,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum GraphFormat {
+    /// Parallel `notes` and `links` arrays.
+    #[default]
+    Links,
+    /// `notes` plus a map from each note id to the ids it is linked to.
+    Adjacency,
+}
+
+#[derive(Debug, Serialize)]
+struct SearchHit {
+    #[serde(flatten)]
+    note: Note,
+    snippet: String,
+    /// BM25 relevance; always 0.0 for hits served by the LIKE fallback.
+    score: f32,
+}
+
+/// Which path served a search: ranked Tantivy hits or the unranked SQL `LIKE` fallback.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum SearchSource {
+    Index,
+    Like,
+}
+
+#[derive(Debug, Serialize)]
+struct SearchResponse {
+    results: Vec<SearchHit>,
+    /// Number of notes matching the query, of which `results` is the first page.
+    total: usize,
+    source: SearchSource,
+}
+
+#[derive(Debug, Deserialize)]
+struct SuggestQuery {
+    q: String,
+    limit: Option<usize>,
+}
+
+/// Just enough of a note to render an autocomplete entry.
+#[derive(Debug, Serialize)]
+struct Suggestion {
+    id: i64,
+    title: String,
+}
+
+#[derive(Debug, Serialize)]
+struct SuggestResponse {
+    suggestions: Vec<Suggestion>,
+}
+
+/// Change pushed to `/ws` subscribers. Side-effect links of a note write
+/// (related ids, wikilinks, scope pruning) arrive as `linksChanged`, which
+/// replaces every link touching that note. `graphChanged` means the change was
+/// too broad to describe and the graph should be fetched again.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum ChangeEvent {
+    NoteCreated {
+        note: Note,
+    },
+    NoteUpdated {
+        note: Note,
+    },
+    #[serde(rename_all = "camelCase")]
+    NoteMoved {
+        id: i64,
+        x: f64,
+        y: f64,
+    },
+    NoteDeleted {
+        id: i64,
+    },
+    #[serde(rename_all = "camelCase")]
+    LinksChanged {
+        note_id: i64,
+        links: Vec<Link>,
+    },
+    LinkCreated {
+        link: Link,
+    },
+    LinkDeleted {
+        link: Link,
+    },
+    GraphChanged,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ChangeEnvelope {
+    workspace: i64,
+    #[serde(flatten)]
+    event: ChangeEvent,
+}
+
+/// Events a slow `/ws` client may fall behind by before it is told to refetch.
+const CHANGE_EVENT_BUFFER: usize = 256;
+
+/// Column list shared by every query that maps rows through `map_note_row`.
+/// Tags are folded into a JSON array so a note is always read in one row.
+const NOTE_COLUMNS: &str = r#"
+    id, title, subtitle, content, x, y, parent_id, updated_at,
+    (
+        SELECT json_group_array(tag)
+        FROM (SELECT tag FROM note_tags WHERE note_id = notes.id ORDER BY rowid)
+    ) AS tags,
+    workspace_id, created_at, version, color, icon, pinned, uuid, metadata, z
+"#;
+
+/// SQL expression for a random version 4 UUID, used as a note's `uuid`.
+const NEW_UUID_SQL: &str = r#"
+    lower(hex(randomblob(4))) || '-' || lower(hex(randomblob(2))) || '-4'
+    || substr(lower(hex(randomblob(2))), 2) || '-'
+    || substr('89ab', 1 + abs(random()) % 4, 1) || substr(lower(hex(randomblob(2))), 2)
+    || '-' || lower(hex(randomblob(6)))
+"#;
+
+/// Restricts a `links` query to edges whose endpoints are both outside the
+/// trash. Trashed notes keep their link rows so a restore brings them back.
+const LIVE_LINK_FILTER: &str = r#"
+    source_id IN (SELECT id FROM notes WHERE deleted_at IS NULL)
+    AND target_id IN (SELECT id FROM notes WHERE deleted_at IS NULL)
+"#;
+
+/// Order of every link list the API returns: by the unordered pair of
+/// endpoints, so flipping a link's direction (and with it the canonical
+/// storage order) does not move it, then by creation time and source.
+const LINK_ORDER: &str = r#"
+    min(source_id, target_id) ASC, max(source_id, target_id) ASC, created_at ASC, source_id ASC
+"#;
+
+/// Workspace used when a request does not name one. Created by the schema
+/// and cannot be deleted.
+const DEFAULT_WORKSPACE_ID: i64 = 1;
+
+/// How far a duplicated note is shifted from the original on both axes.
+const DUPLICATE_OFFSET: f64 = 40.0;
+
+/// Undoable operations kept per workspace; older entries are dropped.
+const UNDO_HISTORY: i64 = 50;
+
+/// How far from its anchor a note created with `spawnNear` is placed.
+const SPAWN_NEAR_DISTANCE: f64 = 100.0;
+
+/// Where notes created without a position go: rings of `ring_slots` notes
+/// around the origin, each `ring_step` further out than the last.
+#[derive(Debug, Clone, Copy)]
+struct SpawnLayout {
+    ring_slots: usize,
+    ring_step: f64,
+}
+
+impl Default for SpawnLayout {
+    fn default() -> Self {
+        Self {
+            ring_slots: 8,
+            ring_step: 140.0,
+        }
+    }
+}
+
+/// Caps on note text, checked on every write so one stray paste cannot bloat
+/// the database and the search index.
+#[derive(Debug, Clone, Copy)]
+struct NoteLimits {
+    max_title_chars: usize,
+    max_subtitle_chars: usize,
+    max_content_bytes: usize,
+}
+
+impl Default for NoteLimits {
+    fn default() -> Self {
+        Self {
+            max_title_chars: 512,
+            max_subtitle_chars: 512,
+            max_content_bytes: 256 * 1024,
+        }
+    }
+}
+
+/// Result count of `/search` when the request gives no `limit`, and the most
+/// it may ask for.
+#[derive(Debug, Clone, Copy)]
+struct SearchLimits {
+    default: usize,
+    max: usize,
+}
+
+impl Default for SearchLimits {
+    fn default() -> Self {
+        Self {
+            default: 20,
+            max: 100,
+        }
+    }
+}
+
+impl SearchLimits {
+    fn clamp(&self, limit: Option<usize>) -> usize {
+        limit.unwrap_or(self.default).clamp(1, self.max)
+    }
+}
+
+impl NoteLimits {
+    fn check(&self, title: &str, subtitle: &str, content: &str) -> StoreResult<()> {
+        if title.chars().count() > self.max_title_chars {
+            return Err(StoreError::Validation(format!(
+                "title must be at most {} characters",
+                self.max_title_chars
+            )));
+        }
+        if subtitle.chars().count() > self.max_subtitle_chars {
+            return Err(StoreError::Validation(format!(
+                "subtitle must be at most {} characters",
+                self.max_subtitle_chars
+            )));
+        }
+        if content.len() > self.max_content_bytes {
+            return Err(StoreError::Validation(format!(
+                "content must be at most {} bytes",
+                self.max_content_bytes
+            )));
+        }
+        Ok(())
+    }
+}
+
+struct Store {
+    conn: Connection,
+    search: SearchIndex,
+    events: broadcast::Sender<ChangeEnvelope>,
+    limits: NoteLimits,
+    spawn: SpawnLayout,
+    /// Workspace the current request operates on; set by `lock_store`.
+    workspace: i64,
+}
+
+impl Store {
+    fn open(
+        db_path: &FsPath,
+        index_dir: &FsPath,
+        commit_policy: CommitPolicy,
+        limits: NoteLimits,
+        spawn: SpawnLayout,
+        language: Option<Language>,
+        index_buffer_bytes: usize,
+    ) -> anyhow::Result<Self> {
+        let conn = Connection::open(db_path)
+            .with_context(|| format!("failed to open sqlite db {}", db_path.display()))?;
+        conn.pragma_update(None, "foreign_keys", "ON")?;
+        register_sql_functions(&conn)?;
+
+        // WAL lets the read pool keep reading while this connection writes.
+        // The mode is persistent, so the read-only connections pick it up too.
+        let journal_mode: String =
+            conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get(0))?;
+        if !journal_mode.eq_ignore_ascii_case("wal") {
+            bail!(
+                "sqlite db {} refused WAL mode (got {journal_mode})",
+                db_path.display()
+            );
+        }
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+
+        let mut store = Self {
+            conn,
+            search: SearchIndex::open(index_dir, commit_policy, language, index_buffer_bytes)?,
+            events: broadcast::channel(CHANGE_EVENT_BUFFER).0,
+            limits,
+            spawn,
+            workspace: DEFAULT_WORKSPACE_ID,
+        };
+
+        store.init_schema()?;
+        store.rebuild_search()?;
+
+        Ok(store)
+    }
+
+    /// Commits pending index changes and folds the WAL back into the
+    /// database file, so nothing is left to replay on the next start.
+    fn close(&mut self) -> anyhow::Result<()> {
+        self.search
+            .flush()
+            .context("failed to flush search index")?;
+        let busy: bool = self
+            .conn
+            .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |row| row.get(0))
+            .context("failed to checkpoint the database")?;
+        if busy {
+            warn!("WAL checkpoint incomplete: database still in use by a reader");
+        }
+        Ok(())
+    }
+
+    fn init_schema(&self) -> anyhow::Result<()> {
+        self.conn.execute_batch(
+            r#"
+            CREATE TABLE IF NOT EXISTS workspaces (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                name TEXT NOT NULL UNIQUE COLLATE NOCASE,
+                created_at TEXT NOT NULL DEFAULT (datetime('now'))
+            );
+
+            INSERT OR IGNORE INTO workspaces (id, name) VALUES (1, 'default');
+
+            CREATE TABLE IF NOT EXISTS notes (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                title TEXT NOT NULL,
+                subtitle TEXT NOT NULL DEFAULT '',
+                content TEXT NOT NULL DEFAULT '',
+                x REAL NOT NULL DEFAULT 0,
+                y REAL NOT NULL DEFAULT 0,
+                parent_id INTEGER REFERENCES notes(id) ON DELETE SET NULL,
+                created_at TEXT NOT NULL DEFAULT (datetime('now')),
+                updated_at TEXT NOT NULL DEFAULT (datetime('now')),
+                deleted_at TEXT,
+                workspace_id INTEGER NOT NULL DEFAULT 1 REFERENCES workspaces(id) ON DELETE CASCADE,
+                version INTEGER NOT NULL DEFAULT 1,
+                color TEXT,
+                icon TEXT,
+                pinned INTEGER NOT NULL DEFAULT 0,
+                uuid TEXT,
+                metadata TEXT NOT NULL DEFAULT '{}',
+                z INTEGER NOT NULL DEFAULT 0
+            );
+
+            CREATE TABLE IF NOT EXISTS links (
+                source_id INTEGER NOT NULL REFERENCES notes(id) ON DELETE CASCADE,
+                target_id INTEGER NOT NULL REFERENCES notes(id) ON DELETE CASCADE,
+                directed INTEGER NOT NULL DEFAULT 0,
+                wikilink INTEGER NOT NULL DEFAULT 0,
+                workspace_id INTEGER REFERENCES workspaces(id) ON DELETE CASCADE,
+                created_at TEXT NOT NULL DEFAULT (datetime('now')),
+                weight REAL NOT NULL DEFAULT 1.0,
+                PRIMARY KEY(source_id, target_id),
+                CHECK(source_id != target_id)
+            );
+
+            CREATE TABLE IF NOT EXISTS note_tags (
+                note_id INTEGER NOT NULL REFERENCES notes(id) ON DELETE CASCADE,
+                tag TEXT NOT NULL COLLATE NOCASE,
+                PRIMARY KEY(note_id, tag)
+            );
+
+            CREATE INDEX IF NOT EXISTS idx_note_tags_tag ON note_tags(tag);
+
+            CREATE TRIGGER IF NOT EXISTS notes_touch_updated_at
+            AFTER UPDATE OF title, subtitle, content, x, y, parent_id ON notes
+            BEGIN
+                UPDATE notes
+                SET updated_at = datetime('now')
+                WHERE id = NEW.id;
+            END;
+            "#,
+        )?;
+
+        if !self.column_exists("notes", "parent_id")? {
+            self.conn.execute(
+                "ALTER TABLE notes ADD COLUMN parent_id INTEGER REFERENCES notes(id) ON DELETE SET NULL",
+                [],
+            )?;
+        }
+
+        if !self.column_exists("notes", "deleted_at")? {
+            self.conn
+                .execute("ALTER TABLE notes ADD COLUMN deleted_at TEXT", [])?;
+        }
+
+        if !self.column_exists("notes", "version")? {
+            self.conn.execute(
+                "ALTER TABLE notes ADD COLUMN version INTEGER NOT NULL DEFAULT 1",
+                [],
+            )?;
+        }
+
+        for column in ["color", "icon"] {
+            if !self.column_exists("notes", column)? {
+                self.conn
+                    .execute(&format!("ALTER TABLE notes ADD COLUMN {column} TEXT"), [])?;
+            }
+        }
+
+        if !self.column_exists("notes", "pinned")? {
+            self.conn.execute(
+                "ALTER TABLE notes ADD COLUMN pinned INTEGER NOT NULL DEFAULT 0",
+                [],
+            )?;
+        }
+
+        if !self.column_exists("notes", "metadata")? {
+            self.conn.execute(
+                "ALTER TABLE notes ADD COLUMN metadata TEXT NOT NULL DEFAULT '{}'",
+                [],
+            )?;
+        }
+
+        if !self.column_exists("notes", "z")? {
+            self.conn.execute(
+                "ALTER TABLE notes ADD COLUMN z INTEGER NOT NULL DEFAULT 0",
+                [],
+            )?;
+        }
+
+        if !self.column_exists("links", "directed")? {
+            self.conn.execute(
+                "ALTER TABLE links ADD COLUMN directed INTEGER NOT NULL DEFAULT 0",
+                [],
+            )?;
+        }
+
+        if !self.column_exists("links", "weight")? {
+            self.conn.execute(
+                "ALTER TABLE links ADD COLUMN weight REAL NOT NULL DEFAULT 1.0",
+                [],
+            )?;
+        }
+
+        if !self.column_exists("links", "wikilink")? {
+            self.conn.execute(
+                "ALTER TABLE links ADD COLUMN wikilink INTEGER NOT NULL DEFAULT 0",
+                [],
+            )?;
+        }
+
+        // SQLite only accepts a REFERENCES column through ALTER TABLE when it
+        // defaults to NULL, so existing rows are backfilled afterwards.
+        if !self.column_exists("notes", "workspace_id")? {
+            self.conn.execute(
+                "ALTER TABLE notes ADD COLUMN workspace_id INTEGER REFERENCES workspaces(id) ON DELETE CASCADE",
+                [],
+            )?;
+            self.conn.execute(
+                &format!("UPDATE notes SET workspace_id = {DEFAULT_WORKSPACE_ID}"),
+                [],
+            )?;
+        }
+
+        if !self.column_exists("links", "workspace_id")? {
+            self.conn.execute(
+                "ALTER TABLE links ADD COLUMN workspace_id INTEGER REFERENCES workspaces(id) ON DELETE CASCADE",
+                [],
+            )?;
+            self.conn.execute(
+                "UPDATE links SET workspace_id = (SELECT workspace_id FROM notes WHERE id = links.source_id)",
+                [],
+            )?;
+        }
+
+        // Links can only join notes of one workspace, so a link simply takes
+        // the workspace of its source note.
+        self.conn.execute_batch(
+            r#"
+            CREATE TRIGGER IF NOT EXISTS links_set_workspace
+            AFTER INSERT ON links
+            BEGIN
+                UPDATE links
+                SET workspace_id = (SELECT workspace_id FROM notes WHERE id = NEW.source_id)
+                WHERE source_id = NEW.source_id AND target_id = NEW.target_id;
+            END;
+            "#,
+        )?;
+
+        // ALTER TABLE cannot add a column with a non-constant default, so on
+        // upgraded databases the column stays nullable: existing links get
+        // the later of their notes' creation times and the trigger stamps new
+        // ones.
+        if !self.column_exists("links", "created_at")? {
+            self.conn
+                .execute("ALTER TABLE links ADD COLUMN created_at TEXT", [])?;
+            self.conn.execute(
+                r#"
+                UPDATE links
+                SET created_at = (
+                    SELECT MAX(created_at) FROM notes WHERE id IN (links.source_id, links.target_id)
+                )
+                "#,
+                [],
+            )?;
+        }
+        self.conn.execute_batch(
+            r#"
+            CREATE TRIGGER IF NOT EXISTS links_set_created_at
+            AFTER INSERT ON links
+            WHEN NEW.created_at IS NULL
+            BEGIN
+                UPDATE links
+                SET created_at = datetime('now')
+                WHERE source_id = NEW.source_id AND target_id = NEW.target_id;
+            END;
+            "#,
+        )?;
+
+        // SQLite cannot add a column with a computed default, so new notes get
+        // their uuid from a trigger on fresh and upgraded databases alike.
+        if !self.column_exists("notes", "uuid")? {
+            self.conn
+                .execute("ALTER TABLE notes ADD COLUMN uuid TEXT", [])?;
+        }
+        self.conn.execute(
+            &format!("UPDATE notes SET uuid = {NEW_UUID_SQL} WHERE uuid IS NULL"),
+            [],
+        )?;
+        self.conn.execute_batch(&format!(
+            r#"
+            CREATE UNIQUE INDEX IF NOT EXISTS idx_notes_uuid ON notes(uuid);
+
+            CREATE TRIGGER IF NOT EXISTS notes_set_uuid
+            AFTER INSERT ON notes
+            WHEN NEW.uuid IS NULL
+            BEGIN
+                UPDATE notes
+                SET uuid = {NEW_UUID_SQL}
                 WHERE id = NEW.id;
             END;
+            "#
+        ))?;
+
+        self.conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_notes_parent_id ON notes(parent_id)",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_notes_workspace_id ON notes(workspace_id)",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_links_workspace_id ON links(workspace_id)",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_notes_deleted_at ON notes(deleted_at)",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_notes_position ON notes(workspace_id, x, y)",
+            [],
+        )?;
+        self.conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_links_created_at ON links(workspace_id, created_at)",
+            [],
+        )?;
+
+        self.conn.execute_batch(
+            r#"
+            CREATE TABLE IF NOT EXISTS operations (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                workspace_id INTEGER NOT NULL REFERENCES workspaces(id) ON DELETE CASCADE,
+                kind TEXT NOT NULL,
+                snapshot TEXT NOT NULL,
+                created_at TEXT NOT NULL DEFAULT (datetime('now'))
+            );
+
+            CREATE INDEX IF NOT EXISTS idx_operations_workspace_id ON operations(workspace_id, id);
+            "#,
+        )?;
+
+        // Every write to a note or link logs its key here, replacing the key's
+        // earlier entry, so `/sync` can tell which rows changed or disappeared
+        // after a cursor while the log keeps one row per note or link. Moving
+        // a note in or out of the trash also logs its links, which appear and
+        // vanish with it. Deletes also leave a tombstone in `deletions`,
+        // written by the same statement. There is no foreign key: rows must
+        // outlive what they name. Triggers are recreated on every start so
+        // existing databases pick up changes to them.
+        self.conn.execute_batch(
+            r#"
+            CREATE TABLE IF NOT EXISTS sync_log (
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                workspace_id INTEGER,
+                note_id INTEGER,
+                source_id INTEGER,
+                target_id INTEGER
+            );
+
+            CREATE INDEX IF NOT EXISTS idx_sync_log_workspace_id ON sync_log(workspace_id, seq);
+
+            -- Tombstones of removed notes and links, keyed by the sync log
+            -- entry of the delete. Links keep their source in `entity_id`.
+            CREATE TABLE IF NOT EXISTS deletions (
+                seq INTEGER PRIMARY KEY,
+                workspace_id INTEGER,
+                entity_type TEXT NOT NULL,
+                entity_id INTEGER NOT NULL,
+                target_id INTEGER,
+                deleted_at TEXT NOT NULL DEFAULT (datetime('now'))
+            );
+
+            CREATE INDEX IF NOT EXISTS idx_deletions_workspace_id ON deletions(workspace_id, seq);
+
+            -- Cursors at or below this were issued before tombstones they
+            -- depend on were pruned.
+            CREATE TABLE IF NOT EXISTS sync_horizon (seq INTEGER NOT NULL);
+            INSERT INTO sync_horizon (seq) SELECT 0 WHERE NOT EXISTS (SELECT 1 FROM sync_horizon);
+
+            CREATE INDEX IF NOT EXISTS idx_sync_log_note_id ON sync_log(note_id);
+            CREATE INDEX IF NOT EXISTS idx_sync_log_link ON sync_log(source_id, target_id);
+
+            DROP TRIGGER IF EXISTS sync_log_note_insert;
+            CREATE TRIGGER sync_log_note_insert
+            AFTER INSERT ON notes
+            BEGIN
+                DELETE FROM sync_log WHERE note_id = NEW.id;
+                INSERT INTO sync_log (workspace_id, note_id) VALUES (NEW.workspace_id, NEW.id);
+            END;
+
+            -- `updated_at` is left out so the touch from
+            -- `notes_touch_updated_at` does not log the note a second time.
+            DROP TRIGGER IF EXISTS sync_log_note_update;
+            CREATE TRIGGER sync_log_note_update
+            AFTER UPDATE OF title, subtitle, content, x, y, parent_id, deleted_at, workspace_id,
+                            version, color, icon, pinned, uuid, metadata, z ON notes
+            WHEN OLD.title IS NOT NEW.title
+               OR OLD.subtitle IS NOT NEW.subtitle
+               OR OLD.content IS NOT NEW.content
+               OR OLD.x IS NOT NEW.x
+               OR OLD.y IS NOT NEW.y
+               OR OLD.parent_id IS NOT NEW.parent_id
+               OR OLD.deleted_at IS NOT NEW.deleted_at
+               OR OLD.workspace_id IS NOT NEW.workspace_id
+               OR OLD.version IS NOT NEW.version
+               OR OLD.color IS NOT NEW.color
+               OR OLD.icon IS NOT NEW.icon
+               OR OLD.pinned IS NOT NEW.pinned
+               OR OLD.uuid IS NOT NEW.uuid
+               OR OLD.metadata IS NOT NEW.metadata
+               OR OLD.z IS NOT NEW.z
+            BEGIN
+                DELETE FROM sync_log WHERE note_id = NEW.id;
+                INSERT INTO sync_log (workspace_id, note_id) VALUES (NEW.workspace_id, NEW.id);
+            END;
+
+            DROP TRIGGER IF EXISTS sync_log_note_trash;
+            CREATE TRIGGER sync_log_note_trash
+            AFTER UPDATE OF deleted_at ON notes
+            WHEN OLD.deleted_at IS NOT NEW.deleted_at
+            BEGIN
+                DELETE FROM sync_log
+                WHERE (source_id, target_id) IN (
+                    SELECT source_id, target_id FROM links WHERE NEW.id IN (source_id, target_id)
+                );
+                INSERT INTO sync_log (workspace_id, source_id, target_id)
+                SELECT NEW.workspace_id, source_id, target_id
+                FROM links
+                WHERE NEW.id IN (source_id, target_id);
+            END;
+
+            DROP TRIGGER IF EXISTS sync_log_note_delete;
+            CREATE TRIGGER sync_log_note_delete
+            AFTER DELETE ON notes
+            BEGIN
+                DELETE FROM sync_log WHERE note_id = OLD.id;
+                INSERT INTO sync_log (workspace_id, note_id) VALUES (OLD.workspace_id, OLD.id);
+                INSERT INTO deletions (seq, workspace_id, entity_type, entity_id)
+                VALUES (last_insert_rowid(), OLD.workspace_id, 'note', OLD.id);
+            END;
+
+            DROP TRIGGER IF EXISTS sync_log_link_insert;
+            CREATE TRIGGER sync_log_link_insert
+            AFTER INSERT ON links
+            BEGIN
+                DELETE FROM sync_log WHERE source_id = NEW.source_id AND target_id = NEW.target_id;
+                INSERT INTO sync_log (workspace_id, source_id, target_id)
+                VALUES (
+                    COALESCE(NEW.workspace_id, (SELECT workspace_id FROM notes WHERE id = NEW.source_id)),
+                    NEW.source_id,
+                    NEW.target_id
+                );
+            END;
+
+            DROP TRIGGER IF EXISTS sync_log_link_update;
+            CREATE TRIGGER sync_log_link_update
+            AFTER UPDATE ON links
+            BEGIN
+                DELETE FROM sync_log
+                WHERE (source_id, target_id) IN (
+                    VALUES (OLD.source_id, OLD.target_id), (NEW.source_id, NEW.target_id)
+                );
+                INSERT INTO sync_log (workspace_id, source_id, target_id)
+                VALUES (NEW.workspace_id, OLD.source_id, OLD.target_id),
+                       (NEW.workspace_id, NEW.source_id, NEW.target_id);
+            END;
+
+            DROP TRIGGER IF EXISTS sync_log_link_delete;
+            CREATE TRIGGER sync_log_link_delete
+            AFTER DELETE ON links
+            BEGIN
+                DELETE FROM sync_log WHERE source_id = OLD.source_id AND target_id = OLD.target_id;
+                INSERT INTO sync_log (workspace_id, source_id, target_id)
+                VALUES (
+                    COALESCE(OLD.workspace_id, (SELECT workspace_id FROM notes WHERE id = OLD.source_id)),
+                    OLD.source_id,
+                    OLD.target_id
+                );
+                INSERT INTO deletions (seq, workspace_id, entity_type, entity_id, target_id)
+                SELECT seq, workspace_id, 'link', source_id, target_id
+                FROM sync_log
+                WHERE seq = last_insert_rowid();
+            END;
+            "#,
+        )?;
+
+        Ok(())
+    }
+
+    fn create_workspace(&mut self, payload: CreateWorkspaceRequest) -> StoreResult<Workspace> {
+        let name = payload.name.trim();
+        if name.is_empty() {
+            return Err(StoreError::Validation(
+                "workspace name cannot be empty".to_string(),
+            ));
+        }
+
+        let inserted = self.conn.execute(
+            "INSERT OR IGNORE INTO workspaces (name) VALUES (?1)",
+            [name],
+        )?;
+        if inserted == 0 {
+            return Err(StoreError::Validation(
+                "workspace name must be unique".to_string(),
+            ));
+        }
+
+        let id = self.conn.last_insert_rowid();
+        self.conn
+            .query_row(
+                "SELECT id, name, created_at FROM workspaces WHERE id = ?1",
+                [id],
+                map_workspace_row,
+            )
+            .map_err(Into::into)
+    }
+
+    /// Deletes a workspace together with all of its notes and links.
+    fn delete_workspace(&mut self, id: i64) -> StoreResult<bool> {
+        if id == DEFAULT_WORKSPACE_ID {
+            return Err(StoreError::Validation(
+                "the default workspace cannot be deleted".to_string(),
+            ));
+        }
+
+        let tx = self.conn.unchecked_transaction()?;
+        let mut stmt = self
+            .conn
+            .prepare("SELECT id FROM notes WHERE workspace_id = ?1")?;
+        let note_ids = stmt
+            .query_map([id], |row| row.get::<_, i64>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        drop(stmt);
+
+        self.conn
+            .execute("DELETE FROM notes WHERE workspace_id = ?1", [id])?;
+        let deleted = self
+            .conn
+            .execute("DELETE FROM workspaces WHERE id = ?1", [id])?;
+        tx.commit()?;
+
+        for note_id in note_ids {
+            self.search.delete_note(note_id)?;
+        }
+
+        if deleted > 0 {
+            let _ = self.events.send(ChangeEnvelope {
+                workspace: id,
+                event: ChangeEvent::GraphChanged,
+            });
+        }
+
+        Ok(deleted > 0)
+    }
+
+    /// Broadcasts a change in the current workspace. Called once the change
+    /// is committed; a send only fails when nobody is listening.
+    fn publish(&self, event: ChangeEvent) {
+        let _ = self.events.send(ChangeEnvelope {
+            workspace: self.workspace,
+            event,
+        });
+    }
+
+    /// Publishes a created or updated note followed by its current links.
+    fn publish_note(&self, note: &Note, created: bool) -> StoreResult<()> {
+        if self.events.receiver_count() == 0 {
+            return Ok(());
+        }
+
+        let note_id = note.id;
+        let note = note.clone();
+        self.publish(if created {
+            ChangeEvent::NoteCreated { note }
+        } else {
+            ChangeEvent::NoteUpdated { note }
+        });
+        self.publish(ChangeEvent::LinksChanged {
+            note_id,
+            links: self.links_of(note_id)?,
+        });
+        Ok(())
+    }
+
+    /// Reindexes every live note across all workspaces and returns how many
+    /// documents were written.
+    fn rebuild_search(&mut self) -> StoreResult<usize> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {NOTE_COLUMNS} FROM notes WHERE deleted_at IS NULL"
+        ))?;
+        let notes = stmt
+            .query_map([], map_note_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        drop(stmt);
+
+        self.search.rebuild(&notes)?;
+        Ok(notes.len())
+    }
+
+    /// Checks every workspace for rows the schema should have prevented but
+    /// manual edits can still produce. With `fix`, bad links are deleted and
+    /// notes with a broken position are moved to the origin.
+    fn validate(&mut self, fix: bool) -> StoreResult<ValidationReport> {
+        const DANGLING: &str = r#"
+            source_id NOT IN (SELECT id FROM notes) OR target_id NOT IN (SELECT id FROM notes)
+        "#;
+        const DUPLICATE: &str = r#"
+            source_id > target_id AND EXISTS (
+                SELECT 1 FROM links AS reverse
+                WHERE reverse.source_id = links.target_id
+                  AND reverse.target_id = links.source_id
+                  AND (reverse.directed = 0 OR links.directed = 0)
+            )
+        "#;
+        const BAD_POSITION: &str = r#"
+            NOT (
+                typeof(x) IN ('integer', 'real') AND abs(x) <= 1.7976931348623157e308
+                AND typeof(y) IN ('integer', 'real') AND abs(y) <= 1.7976931348623157e308
+            )
+        "#;
+
+        let mut problems = Vec::new();
+
+        let mut stmt = self.conn.prepare(&format!(
+            r#"
+            SELECT source_id, target_id,
+                   CASE WHEN source_id IN (SELECT id FROM notes) THEN target_id ELSE source_id END
+            FROM links
+            WHERE {DANGLING}
+            ORDER BY source_id ASC, target_id ASC
+            "#
+        ))?;
+        let rows = stmt.query_map([], |row| {
+            Ok(IntegrityProblem::DanglingLink {
+                source_id: row.get(0)?,
+                target_id: row.get(1)?,
+                missing_note_id: row.get(2)?,
+            })
+        })?;
+        problems.extend(rows.collect::<Result<Vec<_>, _>>()?);
+
+        let mut stmt = self.conn.prepare(
+            "SELECT source_id FROM links WHERE source_id = target_id ORDER BY source_id ASC",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok(IntegrityProblem::SelfLoop {
+                note_id: row.get(0)?,
+            })
+        })?;
+        problems.extend(rows.collect::<Result<Vec<_>, _>>()?);
+
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT source_id, target_id FROM links WHERE {DUPLICATE} ORDER BY source_id ASC, target_id ASC"
+        ))?;
+        let rows = stmt.query_map([], |row| {
+            Ok(IntegrityProblem::DuplicateLink {
+                source_id: row.get(0)?,
+                target_id: row.get(1)?,
+            })
+        })?;
+        problems.extend(rows.collect::<Result<Vec<_>, _>>()?);
+
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT id FROM notes WHERE {BAD_POSITION} ORDER BY id ASC"
+        ))?;
+        let rows = stmt.query_map([], |row| {
+            Ok(IntegrityProblem::InvalidPosition {
+                note_id: row.get(0)?,
+            })
+        })?;
+        problems.extend(rows.collect::<Result<Vec<_>, _>>()?);
+        drop(stmt);
+
+        let mut fixed = 0;
+        if fix && !problems.is_empty() {
+            let tx = self.conn.unchecked_transaction()?;
+            fixed += self.conn.execute(
+                &format!(
+                    "DELETE FROM links WHERE ({DANGLING}) OR source_id = target_id OR ({DUPLICATE})"
+                ),
+                [],
+            )?;
+            fixed += self.conn.execute(
+                &format!(
+                    "UPDATE notes SET x = 0, y = 0, version = version + 1 WHERE {BAD_POSITION}"
+                ),
+                [],
+            )?;
+            tx.commit()?;
+
+            let workspaces = self.list_workspaces()?;
+            for workspace in workspaces {
+                let _ = self.events.send(ChangeEnvelope {
+                    workspace: workspace.id,
+                    event: ChangeEvent::GraphChanged,
+                });
+            }
+        }
+
+        Ok(ValidationReport { problems, fixed })
+    }
+
+    /// Rewrites the database without its free pages and merges the search
+    /// index into a single segment. Both block writers until done. Sync log
+    /// entries superseded by a later one for the same note or link, which
+    /// databases from before the log collapsed them may still hold, are
+    /// dropped first; `/sync` only ever looks at the latest.
+    fn compact(&mut self) -> StoreResult<CompactResponse> {
+        let started = Instant::now();
+        let db_path = PathBuf::from(self.conn.path().unwrap_or_default());
+        let database_size = |path: &FsPath| -> io::Result<u64> {
+            let mut wal = path.as_os_str().to_owned();
+            wal.push("-wal");
+            Ok(file_size(path)? + file_size(FsPath::new(&wal))?)
+        };
+        let database_bytes_before = database_size(&db_path).context("failed to stat database")?;
+        let index_bytes_before = dir_size(&self.search.dir).context("failed to stat index")?;
+
+        self.conn.execute(
+            r#"
+            DELETE FROM sync_log
+            WHERE workspace_id IS NULL
+               OR seq NOT IN (
+                SELECT MAX(seq) FROM sync_log GROUP BY note_id, source_id, target_id
+               )
+            "#,
+            [],
+        )?;
+        self.conn.execute_batch("VACUUM")?;
+        let busy: bool = self
+            .conn
+            .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |row| row.get(0))?;
+        if busy {
+            warn!("WAL checkpoint incomplete after VACUUM: database still in use by a reader");
+        }
+        self.search.compact()?;
+
+        Ok(CompactResponse {
+            database_bytes_before,
+            database_bytes_after: database_size(&db_path).context("failed to stat database")?,
+            index_bytes_before,
+            index_bytes_after: dir_size(&self.search.dir).context("failed to stat index")?,
+            elapsed_ms: started.elapsed().as_millis(),
+        })
+    }
+
+    /// Drops tombstones older than `retention_days`, along with the sync log
+    /// entries of the rows they stood for, and moves the sync horizon past
+    /// them. Returns how many tombstones went.
+    fn prune_deletions(&mut self, retention_days: u64) -> StoreResult<usize> {
+        let tx = self.conn.unchecked_transaction()?;
+        let horizon = self.conn.query_row(
+            "SELECT MAX(seq) FROM deletions WHERE deleted_at < datetime('now', ?1)",
+            [format!("-{retention_days} days")],
+            |row| row.get::<_, Option<i64>>(0),
+        )?;
+        let Some(horizon) = horizon else {
+            return Ok(0);
+        };
+
+        let pruned = self
+            .conn
+            .execute("DELETE FROM deletions WHERE seq <= ?1", [horizon])?;
+        self.conn.execute(
+            r#"
+            DELETE FROM sync_log
+            WHERE seq <= ?1
+              AND (
+                (note_id IS NOT NULL AND note_id NOT IN (SELECT id FROM notes))
+                OR (
+                    source_id IS NOT NULL
+                    AND (source_id, target_id) NOT IN (SELECT source_id, target_id FROM links)
+                )
+              )
+            "#,
+            [horizon],
+        )?;
+        self.conn
+            .execute("UPDATE sync_horizon SET seq = max(seq, ?1)", [horizon])?;
+        tx.commit()?;
+        Ok(pruned)
+    }
+
+    fn column_exists(&self, table: &str, column: &str) -> anyhow::Result<bool> {
+        let pragma = format!("PRAGMA table_info({table})");
+        let mut stmt = self.conn.prepare(&pragma)?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let name: String = row.get(1)?;
+            if name == column {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// The note, its links and tags are written in one transaction; the search
+    /// index only learns about the note once that has committed.
+    fn create_note(&mut self, payload: CreateNoteRequest) -> StoreResult<SavedNote> {
+        let tx = self.conn.unchecked_transaction()?;
+        let saved = self.insert_note(payload)?;
+        tx.commit()?;
+
+        self.search.upsert_note(&saved.note)?;
+        self.publish_note(&saved.note, true)?;
+        Ok(saved)
+    }
+
+    /// Creates a note linked to `id` in one transaction. The new note joins
+    /// `id`'s focus layer and, unless the payload gives `x` and `y`, is placed
+    /// next to it.
+    fn create_child_note(
+        &mut self,
+        id: i64,
+        mut payload: CreateNoteRequest,
+    ) -> StoreResult<ChildNoteResponse> {
+        let anchor = self
+            .get_note(id)?
+            .ok_or_else(|| StoreError::NotFound(format!("note {id} not found")))?;
+        if payload.parent_id.is_some() && payload.parent_id != anchor.parent_id {
+            return Err(StoreError::Validation(format!(
+                "a linked note must share the focus layer of note {id}"
+            )));
+        }
+        payload.parent_id = anchor.parent_id;
+        payload.spawn_near = Some(id);
+
+        let tx = self.conn.unchecked_transaction()?;
+        let saved = self.insert_note(payload)?;
+        let (link, _) = self.upsert_link_raw(id, saved.note.id, false, None)?;
+        tx.commit()?;
+
+        self.search.upsert_note(&saved.note)?;
+        self.publish_note(&saved.note, true)?;
+        Ok(ChildNoteResponse { note: saved, link })
+    }
+
+    /// Copies a note under a fresh id, titled "<title> (copy)" and nudged off
+    /// the original. With `copy_links` its manual links are recreated on the
+    /// copy with the same orientation; wikilinks follow from the copied
+    /// content either way.
+    fn duplicate_note(&mut self, id: i64, copy_links: bool) -> StoreResult<Note> {
+        let original = self
+            .get_note(id)?
+            .ok_or_else(|| StoreError::NotFound(format!("note {id} not found")))?;
+
+        let tx = self.conn.unchecked_transaction()?;
+        let SavedNote { note: copy, .. } = self.insert_note(CreateNoteRequest {
+            title: format!("{} (copy)", original.title),
+            subtitle: Some(original.subtitle),
+            content: Some(original.content),
+            x: Some(original.x + DUPLICATE_OFFSET),
+            y: Some(original.y + DUPLICATE_OFFSET),
+            parent_id: original.parent_id,
+            related_ids: None,
+            tags: Some(original.tags),
+            color: original.color,
+            icon: original.icon,
+            metadata: Some(original.metadata),
+            spawn_near: None,
+        })?;
+
+        if copy_links {
+            let mut stmt = self.conn.prepare(&format!(
+                r#"
+                SELECT source_id, target_id, directed, created_at, weight
+                FROM links
+                WHERE (source_id = ?1 OR target_id = ?1) AND wikilink = 0 AND {LIVE_LINK_FILTER}
+                "#
+            ))?;
+            let links = stmt
+                .query_map([id], map_link_row)?
+                .collect::<Result<Vec<_>, _>>()?;
+            drop(stmt);
+
+            for link in links {
+                if link.source_id == id {
+                    self.upsert_link_raw(
+                        copy.id,
+                        link.target_id,
+                        link.directed,
+                        Some(link.weight),
+                    )?;
+                } else {
+                    self.upsert_link_raw(
+                        link.source_id,
+                        copy.id,
+                        link.directed,
+                        Some(link.weight),
+                    )?;
+                }
+            }
+        }
+        tx.commit()?;
+
+        self.search.upsert_note(&copy)?;
+        self.publish_note(&copy, true)?;
+        Ok(copy)
+    }
+
+    /// Creates all notes in one SQLite transaction and one index commit.
+    /// Any invalid entry rolls back the whole batch.
+    fn create_notes_bulk(
+        &mut self,
+        payloads: Vec<CreateNoteRequest>,
+    ) -> StoreResult<Vec<SavedNote>> {
+        let tx = self.conn.unchecked_transaction()?;
+        let mut saved = Vec::with_capacity(payloads.len());
+        for (index, payload) in payloads.into_iter().enumerate() {
+            let note = self
+                .insert_note(payload)
+                .map_err(|err| err.context(format!("bulk note {index}")))?;
+            saved.push(note);
+        }
+        tx.commit()?;
+
+        let notes = saved
+            .iter()
+            .map(|saved| saved.note.clone())
+            .collect::<Vec<_>>();
+        self.search.upsert_notes(&notes)?;
+        for note in &notes {
+            self.publish_note(note, true)?;
+        }
+
+        Ok(saved)
+    }
+
+    /// Loads an exported graph in one transaction and rebuilds the search
+    /// index once at the end. Notes keep their ids unless the id is already
+    /// taken, in which case every reference to them is remapped. Rows that
+    /// cannot be imported are skipped and reported rather than failing the
+    /// whole import.
+    fn import_graph(
+        &mut self,
+        document: ImportDocument,
+        mode: ImportMode,
+    ) -> StoreResult<ImportResponse> {
+        let tx = self.conn.unchecked_transaction()?;
+
+        if let ImportMode::Replace = mode {
+            self.conn.execute(
+                "DELETE FROM notes WHERE workspace_id = ?1",
+                [self.workspace],
+            )?;
+        }
+
+        let mut skipped = Vec::new();
+        let mut id_map = HashMap::new();
+        let mut remapped_ids = BTreeMap::new();
+        let mut parents = Vec::new();
+
+        for (index, note) in document.notes.into_iter().enumerate() {
+            let title = note.title.trim();
+            if title.is_empty() {
+                skipped.push(SkippedRow {
+                    kind: "note",
+                    index,
+                    reason: "title cannot be empty".to_string(),
+                });
+                continue;
+            }
+            if id_map.contains_key(&note.id) {
+                skipped.push(SkippedRow {
+                    kind: "note",
+                    index,
+                    reason: format!("duplicate note id {}", note.id),
+                });
+                continue;
+            }
+
+            let color = match normalize_color(note.color) {
+                Ok(color) => color,
+                Err(err) => {
+                    skipped.push(SkippedRow {
+                        kind: "note",
+                        index,
+                        reason: err.to_string(),
+                    });
+                    continue;
+                }
+            };
+            let icon = normalize_icon(note.icon);
+            let metadata = match note.metadata.map(normalize_metadata).transpose() {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    skipped.push(SkippedRow {
+                        kind: "note",
+                        index,
+                        reason: err.to_string(),
+                    });
+                    continue;
+                }
+            };
+            let subtitle = note.subtitle.unwrap_or_default();
+            let content = note.content.unwrap_or_default();
+            if let Err(err) = self.limits.check(title, &subtitle, &content) {
+                skipped.push(SkippedRow {
+                    kind: "note",
+                    index,
+                    reason: err.to_string(),
+                });
+                continue;
+            }
+            let (x, y) = match (note.x, note.y) {
+                (Some(x), Some(y)) => (x, y),
+                _ => self.default_spawn_position()?,
+            };
+            if let Err(err) = check_position(x, y) {
+                skipped.push(SkippedRow {
+                    kind: "note",
+                    index,
+                    reason: err.to_string(),
+                });
+                continue;
+            }
+
+            let id_taken = self.conn.query_row(
+                "SELECT EXISTS(SELECT 1 FROM notes WHERE id = ?1)",
+                [note.id],
+                |row| row.get::<_, i64>(0),
+            )? == 1;
+
+            let uuid = match note.uuid.as_deref().map(str::trim) {
+                Some(uuid) if !uuid.is_empty() && uuid.parse::<i64>().is_err() => {
+                    let uuid_taken = self.conn.query_row(
+                        "SELECT EXISTS(SELECT 1 FROM notes WHERE uuid = ?1)",
+                        [uuid],
+                        |row| row.get::<_, i64>(0),
+                    )? == 1;
+                    (!uuid_taken).then_some(uuid)
+                }
+                _ => None,
+            };
+
+            let id = if id_taken {
+                self.conn.execute(
+                    r#"
+                    INSERT INTO notes (
+                        title, subtitle, content, x, y, workspace_id, color, icon, uuid, metadata
+                    )
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, COALESCE(?10, '{}'))
+                    "#,
+                    params![
+                        title,
+                        subtitle,
+                        content,
+                        x,
+                        y,
+                        self.workspace,
+                        color,
+                        icon,
+                        uuid,
+                        metadata
+                    ],
+                )?;
+                let id = self.conn.last_insert_rowid();
+                remapped_ids.insert(note.id, id);
+                id
+            } else {
+                self.conn.execute(
+                    r#"
+                    INSERT INTO notes (
+                        id, title, subtitle, content, x, y, workspace_id, color, icon, uuid,
+                        metadata
+                    )
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, COALESCE(?11, '{}'))
+                    "#,
+                    params![
+                        note.id,
+                        title,
+                        subtitle,
+                        content,
+                        x,
+                        y,
+                        self.workspace,
+                        color,
+                        icon,
+                        uuid,
+                        metadata
+                    ],
+                )?;
+                note.id
+            };
+
+            if let Some(tags) = note.tags {
+                self.set_note_tags(id, &tags)?;
+            }
+            if let Some(parent_id) = note.parent_id {
+                parents.push((id, parent_id));
+            }
+            id_map.insert(note.id, id);
+        }
+
+        // Parents are resolved after every note exists so their order in the
+        // document does not matter. Parents outside the import are dropped.
+        for (id, parent_id) in parents {
+            if let Some(&parent_id) = id_map.get(&parent_id) {
+                if parent_id != id {
+                    self.conn.execute(
+                        "UPDATE notes SET parent_id = ?1 WHERE id = ?2",
+                        params![parent_id, id],
+                    )?;
+                }
+            }
+        }
+
+        let mut imported_links = 0;
+        for (index, link) in document.links.into_iter().enumerate() {
+            let (Some(&source_id), Some(&target_id)) =
+                (id_map.get(&link.source_id), id_map.get(&link.target_id))
+            else {
+                skipped.push(SkippedRow {
+                    kind: "link",
+                    index,
+                    reason: "link references a note that is not part of the import".to_string(),
+                });
+                continue;
+            };
+
+            let reason = if source_id == target_id {
+                Some("a note cannot link to itself")
+            } else if !self.notes_share_scope(source_id, target_id)? {
+                Some("links can only connect notes inside the same focus layer")
+            } else if link
+                .weight
+                .is_some_and(|weight| check_weight(weight).is_err())
+            {
+                Some(LINK_WEIGHT_RANGE)
+            } else {
+                None
+            };
+            if let Some(reason) = reason {
+                skipped.push(SkippedRow {
+                    kind: "link",
+                    index,
+                    reason: reason.to_string(),
+                });
+                continue;
+            }
+
+            self.upsert_link_raw(
+                source_id,
+                target_id,
+                link.directed.unwrap_or(false),
+                link.weight,
+            )?;
+            imported_links += 1;
+        }
+
+        tx.commit()?;
+
+        self.rebuild_search()?;
+        self.publish(ChangeEvent::GraphChanged);
+
+        Ok(ImportResponse {
+            imported_notes: id_map.len(),
+            imported_links,
+            remapped_ids,
+            skipped,
+        })
+    }
+
+    /// Writes a note and its tags and links to SQLite without touching the
+    /// search index.
+    fn insert_note(&self, payload: CreateNoteRequest) -> StoreResult<SavedNote> {
+        let title = payload.title.trim();
+        if title.is_empty() {
+            return Err(StoreError::Validation("title cannot be empty".to_string()));
+        }
+
+        let subtitle = payload.subtitle.unwrap_or_default();
+        let content = payload.content.unwrap_or_default();
+        self.limits.check(title, &subtitle, &content)?;
+        let color = normalize_color(payload.color)?;
+        let icon = normalize_icon(payload.icon);
+        let metadata = payload.metadata.map(normalize_metadata).transpose()?;
+
+        let (x, y) = match (payload.x, payload.y, payload.spawn_near) {
+            (Some(x), Some(y), _) => {
+                check_position(x, y)?;
+                (x, y)
+            }
+            (_, _, Some(anchor)) => self.spawn_position_near(anchor)?,
+            _ => self.default_spawn_position()?,
+        };
+
+        if let Some(parent_id) = payload.parent_id {
+            if !self.note_exists(parent_id)? {
+                return Err(StoreError::NotFound(format!(
+                    "parent note {parent_id} not found"
+                )));
+            }
+        }
+
+        self.conn.execute(
+            r#"
+            INSERT INTO notes (
+                title, subtitle, content, x, y, parent_id, workspace_id, color, icon, metadata
+            )
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, COALESCE(?10, '{}'))
+            "#,
+            params![
+                title,
+                subtitle,
+                content,
+                x,
+                y,
+                payload.parent_id,
+                self.workspace,
+                color,
+                icon,
+                metadata
+            ],
+        )?;
+
+        let id = self.conn.last_insert_rowid();
+
+        if let Some(tags) = payload.tags {
+            self.set_note_tags(id, &tags)?;
+        }
+
+        let note = self
+            .get_note(id)?
+            .ok_or_else(|| anyhow!("inserted note could not be read"))?;
+
+        let mut skipped_related_ids = Vec::new();
+        let mut added_links = Vec::new();
+        for related_id in payload.related_ids.unwrap_or_default() {
+            if related_id == id {
+                continue;
+            }
+            if self.note_exists(related_id)? && self.notes_share_scope(id, related_id)? {
+                let (link, created) = self.upsert_link_raw(id, related_id, false, None)?;
+                if created {
+                    added_links.push(link);
+                }
+            } else if !skipped_related_ids.contains(&related_id) {
+                skipped_related_ids.push(related_id);
+            }
+        }
+
+        self.sync_wikilinks(id, &content)?;
+
+        Ok(SavedNote {
+            note,
+            skipped_related_ids,
+            added_links,
+            removed_links: Vec::new(),
+        })
+    }
+
+    /// Replaces the tag set of a note. Tags are trimmed, empty ones dropped and
+    /// case-insensitive duplicates collapsed onto the first spelling given.
+    fn set_note_tags(&self, note_id: i64, tags: &[String]) -> StoreResult<()> {
+        self.conn
+            .execute("DELETE FROM note_tags WHERE note_id = ?1", [note_id])?;
+
+        for tag in tags {
+            let tag = tag.trim();
+            if tag.is_empty() {
+                continue;
+            }
+            self.conn.execute(
+                "INSERT OR IGNORE INTO note_tags (note_id, tag) VALUES (?1, ?2)",
+                params![note_id, tag],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn default_spawn_position(&self) -> StoreResult<(f64, f64)> {
+        let count = self.conn.query_row(
+            "SELECT COUNT(*) FROM notes WHERE workspace_id = ?1 AND deleted_at IS NULL",
+            [self.workspace],
+            |row| row.get::<_, i64>(0),
+        )? as usize;
+
+        let slots = self.spawn.ring_slots;
+        let ring = (count / slots) + 1;
+        let slot = count % slots;
+        let angle = (slot as f64 / slots as f64) * std::f64::consts::TAU;
+        let radius = (ring as f64) * self.spawn.ring_step;
+
+        Ok((radius * angle.cos(), radius * angle.sin()))
+    }
+
+    /// A spot `SPAWN_NEAR_DISTANCE` away from `anchor`. Successive notes
+    /// spawned near the same anchor fan out around it, one slot per link it
+    /// already has.
+    fn spawn_position_near(&self, anchor: i64) -> StoreResult<(f64, f64)> {
+        let note = self
+            .get_note(anchor)?
+            .ok_or_else(|| StoreError::NotFound(format!("spawn-near note {anchor} not found")))?;
+        let degree = self.links_of(anchor)?.len();
+
+        let slots = self.spawn.ring_slots;
+        let angle = ((degree % slots) as f64 / slots as f64) * std::f64::consts::TAU;
+        Ok((
+            note.x + SPAWN_NEAR_DISTANCE * angle.cos(),
+            note.y + SPAWN_NEAR_DISTANCE * angle.sin(),
+        ))
+    }
+
+    fn update_note(&mut self, id: i64, payload: UpdateNoteRequest) -> StoreResult<SavedNote> {
+        if payload.title.trim().is_empty() {
+            return Err(StoreError::Validation("title cannot be empty".to_string()));
+        }
+        self.limits
+            .check(payload.title.trim(), &payload.subtitle, &payload.content)?;
+        check_position(payload.x, payload.y)?;
+
+        let color = payload
+            .color
+            .map(|color| normalize_color(Some(color)))
+            .transpose()?;
+        let metadata = payload.metadata.map(normalize_metadata).transpose()?;
+
+        if let Some(Some(parent_id)) = payload.parent_id {
+            if parent_id == id {
+                return Err(StoreError::Validation(
+                    "a note cannot be its own parent".to_string(),
+                ));
+            }
+            if !self.note_exists(parent_id)? {
+                return Err(StoreError::NotFound(format!(
+                    "parent note {parent_id} not found"
+                )));
+            }
+        }
+
+        let tx = self.conn.unchecked_transaction()?;
+        let updated = if let Some(parent_id) = payload.parent_id {
+            self.conn.execute(
+                r#"
+                UPDATE notes
+                SET title = ?1,
+                    subtitle = ?2,
+                    content = ?3,
+                    x = ?4,
+                    y = ?5,
+                    parent_id = ?6,
+                    version = version + 1
+                WHERE id = ?7
+                  AND workspace_id = ?8
+                  AND deleted_at IS NULL
+                  AND (?9 IS NULL OR version = ?9)
+                "#,
+                params![
+                    payload.title.trim(),
+                    payload.subtitle,
+                    payload.content,
+                    payload.x,
+                    payload.y,
+                    parent_id,
+                    id,
+                    self.workspace,
+                    payload.version
+                ],
+            )?
+        } else {
+            self.conn.execute(
+                r#"
+                UPDATE notes
+                SET title = ?1,
+                    subtitle = ?2,
+                    content = ?3,
+                    x = ?4,
+                    y = ?5,
+                    version = version + 1
+                WHERE id = ?6
+                  AND workspace_id = ?7
+                  AND deleted_at IS NULL
+                  AND (?8 IS NULL OR version = ?8)
+                "#,
+                params![
+                    payload.title.trim(),
+                    payload.subtitle,
+                    payload.content,
+                    payload.x,
+                    payload.y,
+                    id,
+                    self.workspace,
+                    payload.version
+                ],
+            )?
+        };
+
+        if updated == 0 {
+            return Err(self.update_miss(id, payload.version)?);
+        }
+
+        if let Some(color) = color {
+            self.conn.execute(
+                "UPDATE notes SET color = ?1 WHERE id = ?2",
+                params![color, id],
+            )?;
+        }
+        if let Some(icon) = payload.icon {
+            self.conn.execute(
+                "UPDATE notes SET icon = ?1 WHERE id = ?2",
+                params![normalize_icon(Some(icon)), id],
+            )?;
+        }
+        if let Some(metadata) = metadata {
+            self.conn.execute(
+                "UPDATE notes SET metadata = ?1 WHERE id = ?2",
+                params![metadata, id],
+            )?;
+        }
+
+        self.prune_links_outside_scope(id)?;
+
+        let related = match payload.related_ids {
+            Some(related_ids) => self.sync_related_links(id, &related_ids)?,
+            None => RelatedLinksSync::default(),
+        };
+
+        self.sync_wikilinks(id, &payload.content)?;
+
+        if let Some(tags) = payload.tags {
+            self.set_note_tags(id, &tags)?;
+        }
+        tx.commit()?;
+
+        let note = self
+            .get_note(id)?
+            .ok_or_else(|| StoreError::NotFound(format!("updated note {id} not found")))?;
+
+        self.search.upsert_note(&note)?;
+        self.publish_note(&note, false)?;
+
+        Ok(SavedNote {
+            note,
+            skipped_related_ids: related.skipped,
+            added_links: related.added,
+            removed_links: related.removed,
+        })
+    }
+
+    /// Updates only the columns present in `payload`, in one `UPDATE`, so
+    /// fields the client did not send cannot clobber concurrent edits.
+    fn patch_note(&mut self, id: i64, payload: PatchNoteRequest) -> StoreResult<SavedNote> {
+        let title = payload.title.as_deref().map(str::trim);
+        if title.is_some_and(str::is_empty) {
+            return Err(StoreError::Validation("title cannot be empty".to_string()));
+        }
+        self.limits.check(
+            title.unwrap_or_default(),
+            payload.subtitle.as_deref().unwrap_or_default(),
+            payload.content.as_deref().unwrap_or_default(),
+        )?;
+        check_position(payload.x.unwrap_or_default(), payload.y.unwrap_or_default())?;
+
+        if let Some(Some(parent_id)) = payload.parent_id {
+            if parent_id == id {
+                return Err(StoreError::Validation(
+                    "a note cannot be its own parent".to_string(),
+                ));
+            }
+            if !self.note_exists(parent_id)? {
+                return Err(StoreError::NotFound(format!(
+                    "parent note {parent_id} not found"
+                )));
+            }
+        }
+
+        let mut assignments = vec!["version = version + 1"];
+        let mut values = Vec::new();
+        let text = |value: &str| SqlValue::Text(value.to_string());
+        if let Some(title) = title {
+            assignments.push("title = ?");
+            values.push(text(title));
+        }
+        if let Some(subtitle) = &payload.subtitle {
+            assignments.push("subtitle = ?");
+            values.push(text(subtitle));
+        }
+        if let Some(content) = &payload.content {
+            assignments.push("content = ?");
+            values.push(text(content));
+        }
+        if let Some(x) = payload.x {
+            assignments.push("x = ?");
+            values.push(SqlValue::Real(x));
+        }
+        if let Some(y) = payload.y {
+            assignments.push("y = ?");
+            values.push(SqlValue::Real(y));
+        }
+        if let Some(parent_id) = payload.parent_id {
+            assignments.push("parent_id = ?");
+            values.push(parent_id.map_or(SqlValue::Null, SqlValue::Integer));
+        }
+        if let Some(color) = payload.color {
+            assignments.push("color = ?");
+            values.push(normalize_color(Some(color))?.map_or(SqlValue::Null, SqlValue::Text));
+        }
+        if let Some(icon) = payload.icon {
+            assignments.push("icon = ?");
+            values.push(normalize_icon(Some(icon)).map_or(SqlValue::Null, SqlValue::Text));
+        }
+        if let Some(metadata) = payload.metadata {
+            assignments.push("metadata = ?");
+            values.push(SqlValue::Text(normalize_metadata(metadata)?));
+        }
+        let version = payload.version.map_or(SqlValue::Null, SqlValue::Integer);
+        values.extend([
+            SqlValue::Integer(id),
+            SqlValue::Integer(self.workspace),
+            version.clone(),
+            version,
+        ]);
+
+        let tx = self.conn.unchecked_transaction()?;
+        let updated = self.conn.execute(
+            &format!(
+                r#"
+                UPDATE notes
+                SET {}
+                WHERE id = ?
+                  AND workspace_id = ?
+                  AND deleted_at IS NULL
+                  AND (? IS NULL OR version = ?)
+                "#,
+                assignments.join(", ")
+            ),
+            params_from_iter(values.iter()),
+        )?;
+        if updated == 0 {
+            return Err(self.update_miss(id, payload.version)?);
+        }
+
+        if payload.parent_id.is_some() {
+            self.prune_links_outside_scope(id)?;
+        }
+        let related = match payload.related_ids {
+            Some(related_ids) => self.sync_related_links(id, &related_ids)?,
+            None => RelatedLinksSync::default(),
+        };
+        if let Some(content) = &payload.content {
+            self.sync_wikilinks(id, content)?;
+        }
+        if let Some(tags) = payload.tags {
+            self.set_note_tags(id, &tags)?;
+        }
+        tx.commit()?;
+
+        let note = self
+            .get_note(id)?
+            .ok_or_else(|| StoreError::NotFound(format!("updated note {id} not found")))?;
+
+        self.search.upsert_note(&note)?;
+        self.publish_note(&note, false)?;
+
+        Ok(SavedNote {
+            note,
+            skipped_related_ids: related.skipped,
+            added_links: related.added,
+            removed_links: related.removed,
+        })
+    }
+
+    fn update_note_position(
+        &mut self,
+        id: i64,
+        payload: UpdatePositionRequest,
+    ) -> StoreResult<Note> {
+        check_position(payload.x, payload.y)?;
+        let updated = self.conn.execute(
+            r#"
+            UPDATE notes
+            SET x = ?1,
+                y = ?2,
+                version = version + 1
+            WHERE id = ?3
+              AND workspace_id = ?4
+              AND deleted_at IS NULL
+              AND (?5 IS NULL OR version = ?5)
+            "#,
+            params![payload.x, payload.y, id, self.workspace, payload.version],
+        )?;
+
+        if updated == 0 {
+            return Err(self.update_miss(id, payload.version)?);
+        }
+
+        let note = self
+            .get_note(id)?
+            .ok_or_else(|| StoreError::NotFound(format!("updated note {id} not found")))?;
+        self.publish(ChangeEvent::NoteMoved {
+            id,
+            x: note.x,
+            y: note.y,
+        });
+        Ok(note)
+    }
+
+    /// Pins or unpins a note. `pinned` is outside the columns watched by the
+    /// `notes_touch_updated_at` trigger, so this leaves `updated_at` (and the
+    /// version) alone.
+    fn set_note_pinned(&mut self, id: i64, pinned: bool) -> StoreResult<Note> {
+        let updated = self.conn.execute(
+            "UPDATE notes SET pinned = ?1 WHERE id = ?2 AND workspace_id = ?3 AND deleted_at IS NULL",
+            params![pinned, id, self.workspace],
+        )?;
+        if updated == 0 {
+            return Err(StoreError::NotFound(format!("note {id} not found")));
+        }
+
+        let note = self
+            .get_note(id)?
+            .ok_or_else(|| StoreError::NotFound(format!("updated note {id} not found")))?;
+        self.publish(ChangeEvent::NoteUpdated { note: note.clone() });
+        Ok(note)
+    }
+
+    /// Stacks a note above (`raise`) or below every other live note of the
+    /// workspace. Like `pinned`, `z` is not watched by the
+    /// `notes_touch_updated_at` trigger, so `updated_at` and the version stay.
+    /// A note already on top (or at the bottom) keeps its `z`.
+    fn restack_note(&mut self, id: i64, raise: bool) -> StoreResult<Note> {
+        let (keep, bound) = if raise {
+            ("max", "MAX(z) + 1")
+        } else {
+            ("min", "MIN(z) - 1")
+        };
+        let updated = self.conn.execute(
+            &format!(
+                r#"
+                UPDATE notes
+                SET z = {keep}(z, COALESCE((
+                    SELECT {bound}
+                    FROM notes
+                    WHERE workspace_id = ?2 AND deleted_at IS NULL AND id != ?1
+                ), z))
+                WHERE id = ?1 AND workspace_id = ?2 AND deleted_at IS NULL
+                "#
+            ),
+            [id, self.workspace],
+        )?;
+        if updated == 0 {
+            return Err(StoreError::NotFound(format!("note {id} not found")));
+        }
+
+        let note = self
+            .get_note(id)?
+            .ok_or_else(|| StoreError::NotFound(format!("updated note {id} not found")))?;
+        self.publish(ChangeEvent::NoteUpdated { note: note.clone() });
+        Ok(note)
+    }
+
+    /// Explains why a versioned UPDATE of a note touched no row.
+    fn update_miss(&self, id: i64, expected: Option<i64>) -> StoreResult<StoreError> {
+        let current = self
+            .conn
+            .query_row(
+                "SELECT version FROM notes WHERE id = ?1 AND workspace_id = ?2 AND deleted_at IS NULL",
+                [id, self.workspace],
+                |row| row.get::<_, i64>(0),
+            )
+            .optional()?;
+
+        Ok(match (current, expected) {
+            (Some(current), Some(expected)) => StoreError::Conflict(format!(
+                "version conflict on note {id}: expected {expected}, current {current}"
+            )),
+            _ => StoreError::NotFound(format!("note {id} not found")),
+        })
+    }
+
+    /// Moves a live note to the trash. Its links stay in place but are hidden
+    /// until the note is restored.
+    fn delete_note(&mut self, id: i64) -> StoreResult<bool> {
+        let tx = self.conn.unchecked_transaction()?;
+        let snapshot = self.snapshot_notes(&[id])?;
+        let affected = self.conn.execute(
+            r#"
+            UPDATE notes
+            SET deleted_at = datetime('now')
+            WHERE id = ?1 AND workspace_id = ?2 AND deleted_at IS NULL
+            "#,
+            [id, self.workspace],
+        )?;
+        if affected == 0 {
+            return Ok(false);
+        }
+        self.log_operation("delete", &snapshot)?;
+        tx.commit()?;
+
+        self.search.delete_note(id)?;
+        self.publish(ChangeEvent::NoteDeleted { id });
+        Ok(true)
+    }
+
+    /// Trashes every listed note, or with `purge` removes them for good, in
+    /// one transaction and one index update. The whole batch is a single undo
+    /// step. Ids that match no note are reported back rather than failing.
+    fn delete_notes(&mut self, ids: &[i64], purge: bool) -> StoreResult<BulkDeleteResponse> {
+        let mut ids = ids.to_vec();
+        ids.sort_unstable();
+        ids.dedup();
+
+        let tx = self.conn.unchecked_transaction()?;
+        let snapshot = self.snapshot_notes(&ids)?;
+        let mut deleted = Vec::with_capacity(ids.len());
+        let mut missing_ids = Vec::new();
+        {
+            let mut stmt = self.conn.prepare(if purge {
+                "DELETE FROM notes WHERE id = ?1 AND workspace_id = ?2"
+            } else {
+                r#"
+                UPDATE notes
+                SET deleted_at = datetime('now')
+                WHERE id = ?1 AND workspace_id = ?2 AND deleted_at IS NULL
+                "#
+            })?;
+            for id in ids {
+                if stmt.execute([id, self.workspace])? > 0 {
+                    deleted.push(id);
+                } else {
+                    missing_ids.push(id);
+                }
+            }
+        }
+        if deleted.is_empty() {
+            return Ok(BulkDeleteResponse {
+                deleted: 0,
+                missing_ids,
+            });
+        }
+        self.log_operation(if purge { "purge" } else { "delete" }, &snapshot)?;
+        tx.commit()?;
+
+        self.search.delete_notes(&deleted)?;
+        for &id in &deleted {
+            self.publish(ChangeEvent::NoteDeleted { id });
+        }
+        Ok(BulkDeleteResponse {
+            deleted: deleted.len(),
+            missing_ids,
+        })
+    }
+
+    /// Permanently removes a note, live or trashed, along with its links.
+    fn purge_note(&mut self, id: i64) -> StoreResult<bool> {
+        let tx = self.conn.unchecked_transaction()?;
+        let snapshot = self.snapshot_notes(&[id])?;
+        let affected = self.conn.execute(
+            "DELETE FROM notes WHERE id = ?1 AND workspace_id = ?2",
+            [id, self.workspace],
+        )?;
+        if affected == 0 {
+            return Ok(false);
+        }
+        self.log_operation("purge", &snapshot)?;
+        tx.commit()?;
+
+        self.search.delete_note(id)?;
+        self.publish(ChangeEvent::NoteDeleted { id });
+        Ok(true)
+    }
+
+    /// Folds `merge_id` into `keep_id`: its content is appended, its links
+    /// and children move over, and the merged note is deleted for good.
+    /// Links that would become self-loops, duplicate an existing link or cross
+    /// focus layers are dropped.
+    fn merge_notes(&mut self, keep_id: i64, merge_id: i64) -> StoreResult<Note> {
+        if keep_id == merge_id {
+            return Err(StoreError::Validation(
+                "cannot merge a note into itself".to_string(),
+            ));
+        }
+        let kept = self
+            .get_note(keep_id)?
+            .ok_or_else(|| StoreError::NotFound(format!("note {keep_id} not found")))?;
+        let merged = self
+            .get_note(merge_id)?
+            .ok_or_else(|| StoreError::NotFound(format!("note {merge_id} not found")))?;
+
+        let content = match (kept.content.is_empty(), merged.content.is_empty()) {
+            (_, true) => kept.content,
+            (true, false) => merged.content,
+            (false, false) => format!("{}\n\n{}", kept.content, merged.content),
+        };
+        self.limits.check(&kept.title, &kept.subtitle, &content)?;
+
+        let tx = self.conn.unchecked_transaction()?;
+        let snapshot = self.snapshot_notes(&[keep_id, merge_id])?;
+        self.log_operation("merge", &snapshot)?;
+
+        let mut stmt = self.conn.prepare(
+            r#"
+            SELECT source_id, target_id, directed, wikilink, created_at, weight
+            FROM links
+            WHERE source_id = ?1 OR target_id = ?1
+            "#,
+        )?;
+        let links = stmt
+            .query_map([merge_id], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, bool>(2)?,
+                    row.get::<_, bool>(3)?,
+                    row.get::<_, Option<String>>(4)?,
+                    row.get::<_, f64>(5)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        drop(stmt);
+
+        self.conn.execute(
+            "UPDATE notes SET parent_id = ?1 WHERE parent_id = ?2 AND id != ?1",
+            [keep_id, merge_id],
+        )?;
+        self.conn
+            .execute("DELETE FROM notes WHERE id = ?1", [merge_id])?;
+
+        for (source_id, target_id, directed, wikilink, created_at, weight) in links {
+            let repoint = |id: i64| if id == merge_id { keep_id } else { id };
+            let Ok((source_id, target_id)) =
+                edge_key(repoint(source_id), repoint(target_id), directed)
+            else {
+                continue;
+            };
+            if !self.notes_share_scope(source_id, target_id)? {
+                continue;
+            }
+            self.conn.execute(
+                r#"
+                INSERT INTO links (source_id, target_id, directed, wikilink, created_at, weight)
+                VALUES (?1, ?2, ?3, ?4, COALESCE(?5, datetime('now')), ?6)
+                ON CONFLICT(source_id, target_id) DO NOTHING
+                "#,
+                params![source_id, target_id, directed, wikilink, created_at, weight],
+            )?;
+        }
+
+        self.conn.execute(
+            "UPDATE notes SET content = ?1, version = version + 1 WHERE id = ?2",
+            params![content, keep_id],
+        )?;
+        self.sync_wikilinks(keep_id, &content)?;
+        tx.commit()?;
+
+        let note = self
+            .get_note(keep_id)?
+            .ok_or_else(|| StoreError::NotFound(format!("merged note {keep_id} not found")))?;
+        self.search.delete_note(merge_id)?;
+        self.search.upsert_note(&note)?;
+        self.publish(ChangeEvent::GraphChanged);
+
+        Ok(note)
+    }
+
+    /// Captures the notes (live or trashed) of the current workspace among
+    /// `ids` together with their tags, links and nested notes.
+    fn snapshot_notes(&self, ids: &[i64]) -> StoreResult<NoteSnapshot> {
+        let placeholders = vec!["?"; ids.len()].join(", ");
+        let mut values = ids.to_vec();
+        values.push(self.workspace);
+
+        let mut stmt = self.conn.prepare(&format!(
+            r#"
+            SELECT id, title, subtitle, content, x, y, parent_id, created_at,
+                   deleted_at, version, color, icon, pinned, uuid, metadata, z
+            FROM notes
+            WHERE id IN ({placeholders}) AND workspace_id = ?
+            "#
+        ))?;
+        let notes = stmt
+            .query_map(params_from_iter(values.iter()), |row| {
+                Ok(SnapshotNote {
+                    id: row.get(0)?,
+                    title: row.get(1)?,
+                    subtitle: row.get(2)?,
+                    content: row.get(3)?,
+                    x: row.get(4)?,
+                    y: row.get(5)?,
+                    parent_id: row.get(6)?,
+                    created_at: row.get(7)?,
+                    deleted_at: row.get(8)?,
+                    version: row.get(9)?,
+                    color: row.get(10)?,
+                    icon: row.get(11)?,
+                    pinned: row.get(12)?,
+                    uuid: row.get(13)?,
+                    metadata: row.get(14)?,
+                    z: row.get(15)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        drop(stmt);
+
+        let ids = notes.iter().map(|note| note.id).collect::<Vec<_>>();
+        if ids.is_empty() {
+            return Ok(NoteSnapshot::default());
+        }
+        let placeholders = vec!["?"; ids.len()].join(", ");
+
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT note_id, tag FROM note_tags WHERE note_id IN ({placeholders}) ORDER BY rowid"
+        ))?;
+        let tags = stmt
+            .query_map(params_from_iter(ids.iter()), |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        drop(stmt);
+
+        let mut stmt = self.conn.prepare(&format!(
+            r#"
+            SELECT source_id, target_id, directed, wikilink, created_at, weight
+            FROM links
+            WHERE source_id IN ({placeholders}) OR target_id IN ({placeholders})
+            "#
+        ))?;
+        let links = stmt
+            .query_map(params_from_iter(ids.iter().chain(ids.iter())), |row| {
+                Ok(SnapshotLink {
+                    source_id: row.get(0)?,
+                    target_id: row.get(1)?,
+                    directed: row.get(2)?,
+                    wikilink: row.get(3)?,
+                    created_at: row.get(4)?,
+                    weight: row.get(5)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        drop(stmt);
+
+        let mut stmt = self.conn.prepare(&format!(
+            r#"
+            SELECT id, parent_id
+            FROM notes
+            WHERE parent_id IN ({placeholders}) AND id NOT IN ({placeholders})
+            "#
+        ))?;
+        let children = stmt
+            .query_map(params_from_iter(ids.iter().chain(ids.iter())), |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        drop(stmt);
+
+        Ok(NoteSnapshot {
+            notes,
+            tags,
+            links,
+            children,
+        })
+    }
+
+    /// Appends an undoable operation to the workspace's log, keeping only the
+    /// most recent `UNDO_HISTORY` entries.
+    fn log_operation(&self, kind: &str, snapshot: &NoteSnapshot) -> StoreResult<()> {
+        self.conn.execute(
+            "INSERT INTO operations (workspace_id, kind, snapshot) VALUES (?1, ?2, ?3)",
+            params![self.workspace, kind, serde_json::to_string(snapshot)?],
+        )?;
+        self.conn.execute(
+            r#"
+            DELETE FROM operations
+            WHERE workspace_id = ?1 AND id NOT IN (
+                SELECT id FROM operations WHERE workspace_id = ?1 ORDER BY id DESC LIMIT ?2
+            )
+            "#,
+            [self.workspace, UNDO_HISTORY],
+        )?;
+        Ok(())
+    }
+
+    /// Reverses the most recent logged operation of the workspace by writing
+    /// its snapshot back. Later edits to the same notes are overwritten.
+    fn undo(&mut self) -> StoreResult<UndoResponse> {
+        let tx = self.conn.unchecked_transaction()?;
+        let (op_id, operation, snapshot) = self
+            .conn
+            .query_row(
+                r#"
+                SELECT id, kind, snapshot
+                FROM operations
+                WHERE workspace_id = ?1
+                ORDER BY id DESC
+                LIMIT 1
+                "#,
+                [self.workspace],
+                |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                    ))
+                },
+            )
+            .optional()?
+            .ok_or_else(|| StoreError::NotFound("operation to undo not found".to_string()))?;
+        let snapshot: NoteSnapshot = serde_json::from_str(&snapshot)
+            .with_context(|| format!("invalid snapshot for operation {op_id}"))?;
+
+        for note in &snapshot.notes {
+            self.conn.execute(
+                r#"
+                INSERT INTO notes (
+                    id, title, subtitle, content, x, y, parent_id, created_at,
+                    deleted_at, version, color, icon, pinned, workspace_id, uuid, metadata, z
+                )
+                VALUES (
+                    ?1, ?2, ?3, ?4, ?5, ?6, NULL, ?7, ?8, ?9 + 1, ?10, ?11, ?12, ?13, ?14,
+                    COALESCE(?15, '{}'), ?16
+                )
+                ON CONFLICT(id) DO UPDATE SET
+                    title = excluded.title,
+                    subtitle = excluded.subtitle,
+                    content = excluded.content,
+                    x = excluded.x,
+                    y = excluded.y,
+                    deleted_at = excluded.deleted_at,
+                    version = notes.version + 1,
+                    color = excluded.color,
+                    icon = excluded.icon,
+                    pinned = excluded.pinned,
+                    metadata = excluded.metadata,
+                    z = excluded.z
+                "#,
+                params![
+                    note.id,
+                    note.title,
+                    note.subtitle,
+                    note.content,
+                    note.x,
+                    note.y,
+                    note.created_at,
+                    note.deleted_at,
+                    note.version,
+                    note.color,
+                    note.icon,
+                    note.pinned,
+                    self.workspace,
+                    note.uuid,
+                    note.metadata,
+                    note.z,
+                ],
+            )?;
+            self.conn
+                .execute("DELETE FROM note_tags WHERE note_id = ?1", [note.id])?;
+            self.conn.execute(
+                "DELETE FROM links WHERE source_id = ?1 OR target_id = ?1",
+                [note.id],
+            )?;
+        }
+
+        // Parents are set once every note is back, as they may point at each
+        // other; a parent that has since been purged is left unset.
+        let parents = snapshot
+            .notes
+            .iter()
+            .map(|note| (note.id, note.parent_id))
+            .chain(
+                snapshot
+                    .children
+                    .iter()
+                    .map(|&(id, parent_id)| (id, Some(parent_id))),
+            );
+        for (id, parent_id) in parents {
+            self.conn.execute(
+                r#"
+                UPDATE notes
+                SET parent_id = (SELECT id FROM notes WHERE id = ?2)
+                WHERE id = ?1 AND parent_id IS NOT ?2
+                "#,
+                params![id, parent_id],
+            )?;
+        }
+
+        for (note_id, tag) in &snapshot.tags {
+            self.conn.execute(
+                "INSERT OR IGNORE INTO note_tags (note_id, tag) VALUES (?1, ?2)",
+                params![note_id, tag],
+            )?;
+        }
+
+        for link in &snapshot.links {
+            self.conn.execute(
+                r#"
+                INSERT INTO links (source_id, target_id, directed, wikilink, created_at, weight)
+                SELECT ?1, ?2, ?3, ?4, COALESCE(?5, datetime('now')), COALESCE(?6, 1.0)
+                WHERE EXISTS(SELECT 1 FROM notes WHERE id = ?1)
+                  AND EXISTS(SELECT 1 FROM notes WHERE id = ?2)
+                ON CONFLICT(source_id, target_id) DO NOTHING
+                "#,
+                params![
+                    link.source_id,
+                    link.target_id,
+                    link.directed,
+                    link.wikilink,
+                    link.created_at,
+                    link.weight
+                ],
+            )?;
+        }
+
+        self.conn
+            .execute("DELETE FROM operations WHERE id = ?1", [op_id])?;
+        tx.commit()?;
+
+        let note_ids = snapshot
+            .notes
+            .iter()
+            .map(|note| note.id)
+            .collect::<Vec<_>>();
+        for &id in &note_ids {
+            match self.get_note(id)? {
+                Some(note) => self.search.upsert_note(&note)?,
+                None => self.search.delete_note(id)?,
+            }
+        }
+        self.publish(ChangeEvent::GraphChanged);
+
+        Ok(UndoResponse {
+            operation,
+            note_ids,
+        })
+    }
+
+    fn restore_note(&mut self, id: i64) -> StoreResult<Note> {
+        let restored = self.conn.execute(
+            r#"
+            UPDATE notes
+            SET deleted_at = NULL
+            WHERE id = ?1 AND workspace_id = ?2 AND deleted_at IS NOT NULL
+            "#,
+            [id, self.workspace],
+        )?;
+        if restored == 0 {
+            return Err(StoreError::NotFound(format!(
+                "note {id} not found in trash"
+            )));
+        }
+
+        let note = self
+            .get_note(id)?
+            .ok_or_else(|| StoreError::NotFound(format!("restored note {id} not found")))?;
+        self.search.upsert_note(&note)?;
+        self.publish_note(&note, true)?;
+
+        Ok(note)
+    }
+
+    /// With `reject_cycles`, a directed link whose target can already reach
+    /// its source along directed links is refused. The flag is `true` when
+    /// the link did not exist before.
+    fn create_link(
+        &mut self,
+        payload: LinkRequest,
+        reject_cycles: bool,
+    ) -> StoreResult<(Link, bool)> {
+        let directed = payload.directed.unwrap_or(false);
+        if let Some(weight) = payload.weight {
+            check_weight(weight)?;
+        }
+        if directed && reject_cycles && payload.source_id != payload.target_id {
+            let adjacency = directed_adjacency(&self.list_links()?);
+            if let Some(path) = bfs_path(&adjacency, payload.target_id, payload.source_id) {
+                let path = path
+                    .iter()
+                    .map(i64::to_string)
+                    .collect::<Vec<_>>()
+                    .join(" -> ");
+                return Err(StoreError::Validation(format!(
+                    "cannot link {} -> {}: it would close the cycle {path} -> {}",
+                    payload.source_id, payload.target_id, payload.target_id
+                )));
+            }
+        }
+
+        let (link, created) = self.upsert_link_raw(
+            payload.source_id,
+            payload.target_id,
+            directed,
+            payload.weight,
+        )?;
+        self.publish(ChangeEvent::LinkCreated { link: link.clone() });
+        Ok((link, created))
+    }
+
+    /// Inserts every valid link in one transaction. Rows that would link a
+    /// note to itself, name a missing note, cross focus layers or repeat an
+    /// existing link in either orientation are skipped and reported by their
+    /// index; existing links keep their direction and weight.
+    fn create_links_bulk(&mut self, payload: Vec<LinkRequest>) -> StoreResult<BulkLinkResponse> {
+        let tx = self.conn.unchecked_transaction()?;
+        let mut created = 0;
+        let mut skipped = Vec::new();
+        {
+            let mut insert = self.conn.prepare(
+                r#"
+                INSERT OR IGNORE INTO links (source_id, target_id, directed, weight)
+                VALUES (?1, ?2, ?3, COALESCE(?4, 1.0))
+                "#,
+            )?;
+            for (index, link) in payload.into_iter().enumerate() {
+                let directed = link.directed.unwrap_or(false);
+                let mut missing = None;
+                for id in [link.source_id, link.target_id] {
+                    if !self.note_exists(id)? {
+                        missing = Some(id);
+                        break;
+                    }
+                }
+
+                let reason = if link.source_id == link.target_id {
+                    Some("a note cannot link to itself".to_string())
+                } else if link
+                    .weight
+                    .is_some_and(|weight| check_weight(weight).is_err())
+                {
+                    Some(LINK_WEIGHT_RANGE.to_string())
+                } else if let Some(id) = missing {
+                    Some(format!("note {id} not found"))
+                } else if !self.notes_share_scope(link.source_id, link.target_id)? {
+                    Some("links can only connect notes inside the same focus layer".to_string())
+                } else if self.notes_linked(link.source_id, link.target_id)? {
+                    Some("the notes are already linked".to_string())
+                } else {
+                    None
+                };
+                if let Some(reason) = reason {
+                    skipped.push(SkippedRow {
+                        kind: "link",
+                        index,
+                        reason,
+                    });
+                    continue;
+                }
+
+                let (source_id, target_id) = edge_key(link.source_id, link.target_id, directed)?;
+                created += insert.execute(params![source_id, target_id, directed, link.weight])?;
+            }
+        }
+        tx.commit()?;
+
+        if created > 0 {
+            self.publish(ChangeEvent::GraphChanged);
+        }
+        Ok(BulkLinkResponse { created, skipped })
+    }
+
+    /// Returns `false` when both notes exist but are not linked; a missing
+    /// note is reported as an error naming it. A link hidden because one end
+    /// is in the trash counts as missing too, so it survives until restore.
+    fn delete_link(&mut self, payload: LinkRequest) -> StoreResult<bool> {
+        let directed = payload.directed.unwrap_or(false);
+        let (source_id, target_id) = edge_key(payload.source_id, payload.target_id, directed)?;
+        let deleted = self
+            .conn
+            .query_row(
+                &format!(
+                    r#"
+                    DELETE FROM links
+                    WHERE source_id = ?1 AND target_id = ?2 AND directed = ?3 AND workspace_id = ?4
+                      AND {LIVE_LINK_FILTER}
+                    RETURNING created_at, weight
+                    "#
+                ),
+                params![source_id, target_id, directed, self.workspace],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, f64>(1)?)),
+            )
+            .optional()?;
+
+        let Some((created_at, weight)) = deleted else {
+            for id in [source_id, target_id] {
+                if !self.note_exists(id)? {
+                    return Err(StoreError::NotFound(format!("note {id} not found")));
+                }
+            }
+            return Ok(false);
+        };
+
+        self.publish(ChangeEvent::LinkDeleted {
+            link: Link {
+                source_id,
+                target_id,
+                directed,
+                created_at,
+                weight,
+            },
+        });
+        Ok(true)
+    }
+
+    /// Also returns whether the link is new; an existing one only has its
+    /// direction, and its weight when one is given, updated. When the notes
+    /// are already linked the other way round, that link is returned as is
+    /// rather than stored a second time reversed.
+    fn upsert_link_raw(
+        &self,
+        a: i64,
+        b: i64,
+        directed: bool,
+        weight: Option<f64>,
+    ) -> StoreResult<(Link, bool)> {
+        let (source_id, target_id) = edge_key(a, b, directed)?;
+
+        let mut missing = Vec::new();
+        for id in [source_id, target_id] {
+            if !self.note_exists(id)? {
+                missing.push(id);
+            }
+        }
+        match missing.as_slice() {
+            [] => {}
+            [id] => {
+                return Err(StoreError::NotFound(format!(
+                    "cannot link: note {id} not found"
+                )))
+            }
+            [a, b] => {
+                return Err(StoreError::NotFound(format!(
+                    "cannot link: notes {a} and {b} not found"
+                )))
+            }
+            _ => unreachable!(),
+        }
+
+        if !self.notes_share_scope(source_id, target_id)? {
+            return Err(StoreError::Validation(
+                "links can only connect notes inside the same focus layer".to_string(),
+            ));
+        }
+
+        let existed = self.conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM links WHERE source_id = ?1 AND target_id = ?2)",
+            [source_id, target_id],
+            |row| row.get::<_, bool>(0),
+        )?;
+        if !existed && self.notes_linked(source_id, target_id)? {
+            let link = self.conn.query_row(
+                r#"
+                SELECT source_id, target_id, directed, created_at, weight
+                FROM links
+                WHERE source_id = ?1 AND target_id = ?2
+                "#,
+                [target_id, source_id],
+                map_link_row,
+            )?;
+            return Ok((link, false));
+        }
+
+        let (created_at, weight) = self.conn.query_row(
+            r#"
+            INSERT INTO links (source_id, target_id, directed, weight)
+            VALUES (?1, ?2, ?3, COALESCE(?4, 1.0))
+            ON CONFLICT(source_id, target_id) DO UPDATE
+            SET directed = excluded.directed,
+                wikilink = 0,
+                weight = COALESCE(?4, weight)
+            RETURNING created_at, weight
+            "#,
+            params![source_id, target_id, directed, weight],
+            |row| Ok((row.get::<_, Option<String>>(0)?, row.get::<_, f64>(1)?)),
+        )?;
+
+        let link = Link {
+            source_id,
+            target_id,
+            directed,
+            weight,
+            created_at: match created_at {
+                Some(created_at) => created_at,
+                // Upgraded databases stamp new links in a trigger, which
+                // `RETURNING` does not see.
+                None => self.conn.query_row(
+                    "SELECT created_at FROM links WHERE source_id = ?1 AND target_id = ?2",
+                    [source_id, target_id],
+                    |row| row.get(0),
+                )?,
+            },
+        };
+        Ok((link, !existed))
+    }
+
+    /// Makes the undirected links of `note_id` match `related_ids`. Returns
+    /// the links it added and removed, and the ids that were left out because
+    /// they are missing or in another focus layer.
+    fn sync_related_links(
+        &self,
+        note_id: i64,
+        related_ids: &[i64],
+    ) -> StoreResult<RelatedLinksSync> {
+        let note_scope = self.note_parent_id(note_id)?;
+        let mut desired = HashSet::new();
+        let mut skipped = Vec::new();
+        for related_id in related_ids {
+            if *related_id == note_id {
+                continue;
+            }
+            if self.note_exists(*related_id)? && self.note_parent_id(*related_id)? == note_scope {
+                desired.insert(*related_id);
+            } else if !skipped.contains(related_id) {
+                skipped.push(*related_id);
+            }
+        }
+
+        // Links to trashed notes are left alone so they come back on restore.
+        let mut stmt = self.conn.prepare(&format!(
+            r#"
+            SELECT source_id, target_id, directed, created_at, weight
+            FROM links
+            WHERE (source_id = ?1 OR target_id = ?1) AND {LIVE_LINK_FILTER}
+            ORDER BY {LINK_ORDER}
+            "#
+        ))?;
+        let current_links = stmt
+            .query_map([note_id], map_link_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Edges are compared by their opposite endpoint so that an existing
+        // directed link to a related note is kept as-is instead of being
+        // replaced by an undirected one.
+        let mut current = HashSet::new();
+        let mut removed = Vec::new();
+        for link in current_links {
+            let other_id = if link.source_id == note_id {
+                link.target_id
+            } else {
+                link.source_id
+            };
+
+            if desired.contains(&other_id) {
+                current.insert(other_id);
+            } else {
+                self.conn.execute(
+                    "DELETE FROM links WHERE source_id = ?1 AND target_id = ?2",
+                    params![link.source_id, link.target_id],
+                )?;
+                removed.push(link);
+            }
+        }
+
+        let mut missing = desired.difference(&current).copied().collect::<Vec<_>>();
+        missing.sort_unstable();
+        let mut added = Vec::with_capacity(missing.len());
+        for other_id in missing {
+            let (link, created) = self.upsert_link_raw(note_id, other_id, false, None)?;
+            if created {
+                added.push(link);
+            }
+        }
+
+        Ok(RelatedLinksSync {
+            skipped,
+            added,
+            removed,
+        })
+    }
+
+    /// Links `note_id` to every note its content mentions as `[[Title]]`
+    /// (case-insensitive, same focus layer) and drops links it created for
+    /// mentions that are gone. Wikilink links point from the mentioning note
+    /// to the mentioned one; a pair that is already linked some other way is
+    /// left untouched. Unresolved mentions are ignored.
+    fn sync_wikilinks(&self, note_id: i64, content: &str) -> StoreResult<()> {
+        let scope = self.note_parent_id(note_id)?;
+
+        let mut desired = HashSet::new();
+        for title in wikilink_titles(content) {
+            let target_id = self
+                .conn
+                .query_row(
+                    r#"
+                    SELECT id
+                    FROM notes
+                    WHERE title = ?1 COLLATE NOCASE
+                      AND parent_id IS ?2
+                      AND workspace_id = ?3
+                      AND deleted_at IS NULL
+                    ORDER BY id ASC
+                    LIMIT 1
+                    "#,
+                    params![title, scope, self.workspace],
+                    |row| row.get::<_, i64>(0),
+                )
+                .optional()?;
+            if let Some(target_id) = target_id.filter(|target_id| *target_id != note_id) {
+                desired.insert(target_id);
+            }
+        }
+
+        // Links to trashed notes are kept so they come back on restore.
+        let mut stmt = self.conn.prepare(
+            r#"
+            SELECT target_id
+            FROM links
+            WHERE source_id = ?1
+              AND wikilink = 1
+              AND target_id IN (SELECT id FROM notes WHERE deleted_at IS NULL)
+            "#,
+        )?;
+        let current = stmt
+            .query_map([note_id], |row| row.get::<_, i64>(0))?
+            .collect::<Result<HashSet<_>, _>>()?;
+
+        for target_id in current.difference(&desired) {
+            self.conn.execute(
+                "DELETE FROM links WHERE source_id = ?1 AND target_id = ?2 AND wikilink = 1",
+                params![note_id, target_id],
+            )?;
+        }
+
+        for target_id in desired.difference(&current) {
+            self.conn.execute(
+                r#"
+                INSERT INTO links (source_id, target_id, directed, wikilink)
+                SELECT ?1, ?2, 1, 1
+                WHERE NOT EXISTS (
+                    SELECT 1 FROM links
+                    WHERE (source_id = ?1 AND target_id = ?2)
+                       OR (source_id = ?2 AND target_id = ?1 AND directed = 0)
+                )
+                "#,
+                params![note_id, target_id],
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn prune_links_outside_scope(&self, note_id: i64) -> StoreResult<()> {
+        let note_scope = self.note_parent_id(note_id)?;
+
+        let mut stmt = self.conn.prepare(
+            r#"
+            SELECT source_id, target_id
+            FROM links
+            WHERE source_id = ?1 OR target_id = ?1
+            "#,
+        )?;
+
+        let current_rows = stmt.query_map([note_id], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?))
+        })?;
+
+        for edge in current_rows {
+            let (source_id, target_id) = edge?;
+            let other_id = if source_id == note_id {
+                target_id
+            } else {
+                source_id
+            };
+
+            if self.note_parent_id(other_id)? != note_scope {
+                self.conn.execute(
+                    "DELETE FROM links WHERE source_id = ?1 AND target_id = ?2",
+                    params![source_id, target_id],
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes positions computed by `Queries::layout_positions`. Notes
+    /// trashed or moved to another workspace in the meantime are skipped.
+    fn apply_layout(&mut self, positions: &[(i64, f64, f64)]) -> StoreResult<LayoutResponse> {
+        let mut laid_out = 0;
+        if !positions.is_empty() {
+            let tx = self.conn.transaction()?;
+            {
+                let mut stmt = tx.prepare(
+                    r#"
+                    UPDATE notes
+                    SET x = ?1, y = ?2, version = version + 1
+                    WHERE id = ?3 AND workspace_id = ?4 AND deleted_at IS NULL
+                    "#,
+                )?;
+                for &(id, x, y) in positions {
+                    check_position(x, y)?;
+                    laid_out += stmt.execute(params![x, y, id, self.workspace])?;
+                }
+            }
+            tx.commit()?;
+            self.publish(ChangeEvent::GraphChanged);
+        }
+
+        Ok(LayoutResponse {
+            laid_out,
+            graph: self.graph()?,
+        })
+    }
+}
+
+/// Read-only queries. Implemented by `Store` for the write path and by
+/// `ReadSession` for handlers running on a pooled read connection.
+trait Queries {
+    fn conn(&self) -> &Connection;
+    fn search_reader(&self) -> &SearchReader;
+    /// Workspace every query is scoped to.
+    fn workspace(&self) -> i64;
+
+    /// Every workspace, regardless of the one this session is scoped to.
+    fn list_workspaces(&self) -> StoreResult<Vec<Workspace>> {
+        let mut stmt = self
+            .conn()
+            .prepare("SELECT id, name, created_at FROM workspaces ORDER BY id ASC")?;
+        let rows = stmt.query_map([], map_workspace_row)?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// New positions for every note. Computed on a read connection so the
+    /// store stays free for other writes while a large layout runs.
+    fn layout_positions(
+        &self,
+        algorithm: LayoutAlgorithm,
+        root: Option<i64>,
+    ) -> StoreResult<Vec<(i64, f64, f64)>> {
+        if let LayoutAlgorithm::Tree = algorithm {
+            let root = root.ok_or_else(|| {
+                StoreError::Validation("tree layout must specify a root note".to_string())
+            })?;
+            if !self.note_exists(root)? {
+                return Err(StoreError::NotFound(format!("root note {root} not found")));
+            }
+        }
+
+        let notes = self.list_notes()?;
+        if notes.is_empty() {
+            return Ok(Vec::new());
+        }
+        if let LayoutAlgorithm::Force = algorithm {
+            if notes.len() > FORCE_LAYOUT_MAX_NOTES {
+                return Err(StoreError::Validation(format!(
+                    "force layout is limited to {FORCE_LAYOUT_MAX_NOTES} notes, this workspace has {}; use the ring or tree layout",
+                    notes.len()
+                )));
+            }
+        }
+
+        let links = self.list_links()?;
+
+        Ok(match algorithm {
+            LayoutAlgorithm::Ring => ring_layout(&notes, &links),
+            LayoutAlgorithm::Force => force_layout(&notes, &links),
+            LayoutAlgorithm::Tree => tree_layout(&notes, &links, root.unwrap_or_default()),
+        })
+    }
+
+    fn list_notes(&self) -> StoreResult<Vec<Note>> {
+        let mut stmt = self.conn().prepare(&format!(
+            r#"
+            SELECT {NOTE_COLUMNS}
+            FROM notes
+            WHERE workspace_id = ?1 AND deleted_at IS NULL
+            ORDER BY updated_at DESC
+            "#
+        ))?;
+
+        let rows = stmt.query_map([self.workspace()], map_note_row)?;
+        let notes = rows.collect::<Result<Vec<_>, _>>()?;
+
+        Ok(notes)
+    }
+
+    /// Validates the `updatedAfter`/`updatedBefore` query parameters. SQLite's
+    /// own `datetime()` does the parsing, so offsets and fractional seconds
+    /// end up in exactly the format `updated_at` is stored in.
+    fn updated_range(
+        &self,
+        after: Option<&str>,
+        before: Option<&str>,
+    ) -> StoreResult<UpdatedRange> {
+        let normalize = |value: Option<&str>| -> StoreResult<Option<String>> {
+            let Some(value) = value.map(str::trim).filter(|value| !value.is_empty()) else {
+                return Ok(None);
+            };
+            // `2024` and `2024-01` mean the first day of that year or month.
+            let raw = value;
+            let padded;
+            let value = match value.len() {
+                4 => {
+                    padded = format!("{value}-01-01");
+                    padded.as_str()
+                }
+                7 => {
+                    padded = format!("{value}-01");
+                    padded.as_str()
+                }
+                _ => value,
+            };
+            let looks_like_date = value.len() >= 10
+                && value
+                    .bytes()
+                    .take(10)
+                    .enumerate()
+                    .all(|(index, byte)| match index {
+                        4 | 7 => byte == b'-',
+                        _ => byte.is_ascii_digit(),
+                    });
+            let parsed = if looks_like_date {
+                self.conn()
+                    .query_row("SELECT datetime(?1)", [value], |row| {
+                        row.get::<_, Option<String>>(0)
+                    })?
+            } else {
+                None
+            };
+            parsed.map(Some).ok_or_else(|| {
+                StoreError::Validation(format!("invalid timestamp {raw}: expected ISO-8601"))
+            })
+        };
+
+        Ok(UpdatedRange {
+            after: normalize(after)?,
+            before: normalize(before)?,
+        })
+    }
+
+    /// Returns one page of notes plus the total number of notes matching the
+    /// filter. Pinned notes always come first. The `id` tiebreak keeps pages
+    /// stable when sort keys collide.
+    fn list_notes_page(
+        &self,
+        tag: Option<&str>,
+        updated: &UpdatedRange,
+        metadata: &[(String, String)],
+        sort: NoteSort,
+        limit: usize,
+        offset: usize,
+    ) -> StoreResult<(Vec<Note>, usize)> {
+        let mut clauses = vec!["workspace_id = ?", "deleted_at IS NULL"];
+        let mut values = vec![SqlValue::Integer(self.workspace())];
+
+        if let Some(tag) = tag {
+            clauses.push("id IN (SELECT note_id FROM note_tags WHERE tag = ?)");
+            values.push(SqlValue::Text(tag.trim().to_string()));
+        }
+        if let Some(after) = &updated.after {
+            clauses.push("updated_at >= ?");
+            values.push(SqlValue::Text(after.clone()));
+        }
+        if let Some(before) = &updated.before {
+            clauses.push("updated_at <= ?");
+            values.push(SqlValue::Text(before.clone()));
+        }
+        // `->` yields the attribute as JSON text, so `done` matches the
+        // string "done" while `3` and `true` match numbers and booleans.
+        for (path, value) in metadata {
+            clauses.push("metadata -> ? IN (?, json_quote(?))");
+            values.push(SqlValue::Text(path.clone()));
+            values.push(SqlValue::Text(value.clone()));
+            values.push(SqlValue::Text(value.clone()));
+        }
+
+        let where_sql = format!("WHERE {}", clauses.join(" AND "));
+
+        let total = self.conn().query_row(
+            &format!("SELECT COUNT(*) FROM notes {where_sql}"),
+            params_from_iter(values.iter()),
+            |row| row.get::<_, i64>(0),
+        )? as usize;
+
+        values.push(SqlValue::Integer(limit as i64));
+        values.push(SqlValue::Integer(offset as i64));
+
+        let mut stmt = self.conn().prepare(&format!(
+            r#"
+            SELECT {NOTE_COLUMNS}
+            FROM notes
+            {where_sql}
+            ORDER BY pinned DESC, {order_by}
+            LIMIT ? OFFSET ?
+            "#,
+            order_by = sort.order_by()
+        ))?;
+
+        let rows = stmt.query_map(params_from_iter(values.iter()), map_note_row)?;
+        let notes = rows.collect::<Result<Vec<_>, _>>()?;
+
+        Ok((notes, total))
+    }
+
+    fn get_note(&self, id: i64) -> StoreResult<Option<Note>> {
+        let mut stmt = self.conn().prepare(&format!(
+            r#"
+            SELECT {NOTE_COLUMNS}
+            FROM notes
+            WHERE id = ?1 AND workspace_id = ?2 AND deleted_at IS NULL
+            "#
+        ))?;
+
+        stmt.query_row([id, self.workspace()], map_note_row)
+            .optional()
+            .map_err(Into::into)
+    }
+
+    fn get_note_by_uuid(&self, uuid: &str) -> StoreResult<Option<Note>> {
+        let mut stmt = self.conn().prepare(&format!(
+            r#"
+            SELECT {NOTE_COLUMNS}
+            FROM notes
+            WHERE uuid = ?1 AND workspace_id = ?2 AND deleted_at IS NULL
+            "#
+        ))?;
+
+        stmt.query_row(params![uuid, self.workspace()], map_note_row)
+            .optional()
+            .map_err(Into::into)
+    }
+
+    fn list_note_ids(&self) -> StoreResult<Vec<i64>> {
+        let mut stmt = self.conn().prepare(
+            "SELECT id FROM notes WHERE workspace_id = ?1 AND deleted_at IS NULL ORDER BY id ASC",
+        )?;
+        let rows = stmt.query_map([self.workspace()], |row| row.get(0))?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    fn note_exists(&self, id: i64) -> StoreResult<bool> {
+        let exists = self.conn().query_row(
+            r#"
+            SELECT EXISTS(
+                SELECT 1 FROM notes WHERE id = ?1 AND workspace_id = ?2 AND deleted_at IS NULL
+            )
+            "#,
+            [id, self.workspace()],
+            |row| row.get::<_, i64>(0),
+        )? == 1;
+
+        Ok(exists)
+    }
+
+    fn note_parent_id(&self, id: i64) -> StoreResult<Option<i64>> {
+        self.conn()
+            .query_row("SELECT parent_id FROM notes WHERE id = ?1", [id], |row| {
+                row.get(0)
+            })
+            .optional()
+            .map(|value| value.flatten())
+            .map_err(Into::into)
+    }
+
+    /// Whether a link joins `a` and `b`, stored in either orientation.
+    fn notes_linked(&self, a: i64, b: i64) -> StoreResult<bool> {
+        let linked = self.conn().query_row(
+            r#"
+            SELECT EXISTS(
+                SELECT 1 FROM links
+                WHERE (source_id = ?1 AND target_id = ?2) OR (source_id = ?2 AND target_id = ?1)
+            )
+            "#,
+            [a, b],
+            |row| row.get::<_, bool>(0),
+        )?;
+        Ok(linked)
+    }
+
+    fn notes_share_scope(&self, a: i64, b: i64) -> StoreResult<bool> {
+        Ok(self.note_parent_id(a)? == self.note_parent_id(b)?)
+    }
+
+    fn list_links(&self) -> StoreResult<Vec<Link>> {
+        let mut stmt = self.conn().prepare(&format!(
+            r#"
+            SELECT source_id, target_id, directed, created_at, weight
+            FROM links
+            WHERE workspace_id = ?1 AND {LIVE_LINK_FILTER}
+            ORDER BY {LINK_ORDER}
+            "#
+        ))?;
+
+        let rows = stmt.query_map([self.workspace()], map_link_row)?;
+
+        let links = rows.collect::<Result<Vec<_>, _>>()?;
+        Ok(links)
+    }
+
+    /// Live notes and links of the workspace touched since `since`, oldest
+    /// change first.
+    fn changes_since(&self, since: &str) -> StoreResult<ChangesResponse> {
+        let since = self
+            .updated_range(Some(since), None)?
+            .after
+            .ok_or_else(|| StoreError::Validation("since cannot be empty".to_string()))?;
+
+        let mut stmt = self.conn().prepare(&format!(
+            r#"
+            SELECT {NOTE_COLUMNS}
+            FROM notes
+            WHERE workspace_id = ?1 AND deleted_at IS NULL AND updated_at >= ?2
+            ORDER BY updated_at ASC, id ASC
+            "#
+        ))?;
+        let (created, updated) = stmt
+            .query_map(params![self.workspace(), since], map_note_row)?
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .partition(|note| note.created_at >= since);
+
+        let mut stmt = self.conn().prepare(&format!(
+            r#"
+            SELECT source_id, target_id, directed, created_at, weight
+            FROM links
+            WHERE workspace_id = ?1 AND created_at >= ?2 AND {LIVE_LINK_FILTER}
+            ORDER BY created_at ASC, source_id ASC, target_id ASC
+            "#
+        ))?;
+        let links = stmt
+            .query_map(params![self.workspace(), since], map_link_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(ChangesResponse {
+            created,
+            updated,
+            links,
+        })
+    }
+
+    /// Without `since`, every live note and link. Reads run in one
+    /// transaction so the returned cursor matches the rows exactly.
+    fn sync_since(&self, since: Option<&str>) -> StoreResult<SyncResponse> {
+        let _snapshot = self.conn().unchecked_transaction()?;
+        let (cursor, horizon) = self.conn().query_row(
+            r#"
+            SELECT COALESCE((SELECT seq FROM sqlite_sequence WHERE name = 'sync_log'), 0),
+                   (SELECT seq FROM sync_horizon)
+            "#,
+            [],
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)),
+        )?;
+
+        let Some(since) = since else {
+            return Ok(SyncResponse {
+                notes: self.list_notes()?,
+                links: self.list_links()?,
+                deleted_note_ids: Vec::new(),
+                deleted_links: Vec::new(),
+                cursor: cursor.to_string(),
+            });
+        };
+        let since = since
+            .trim()
+            .parse::<i64>()
+            .ok()
+            .filter(|since| (0..=cursor).contains(since))
+            .ok_or_else(|| {
+                StoreError::Validation(format!(
+                    "invalid sync cursor {since:?}; sync again without since"
+                ))
+            })?;
+        if since < horizon {
+            return Err(StoreError::Conflict(
+                "sync cursor predates the retained deletion log; sync again without since"
+                    .to_string(),
+            ));
+        }
+
+        let mut stmt = self.conn().prepare(&format!(
+            r#"
+            SELECT {NOTE_COLUMNS}
+            FROM notes
+            WHERE workspace_id = ?1 AND deleted_at IS NULL
+              AND id IN (SELECT note_id FROM sync_log WHERE workspace_id = ?1 AND seq > ?2)
+            ORDER BY id
+            "#
+        ))?;
+        let notes = stmt
+            .query_map([self.workspace(), since], map_note_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut stmt = self.conn().prepare(
+            r#"
+            SELECT note_id
+            FROM (
+                SELECT note_id FROM sync_log
+                WHERE workspace_id = ?1 AND seq > ?2 AND note_id IS NOT NULL
+                UNION
+                SELECT entity_id FROM deletions
+                WHERE workspace_id = ?1 AND seq > ?2 AND entity_type = 'note'
+            )
+            WHERE note_id NOT IN (
+                SELECT id FROM notes WHERE workspace_id = ?1 AND deleted_at IS NULL
+            )
+            ORDER BY note_id
+            "#,
+        )?;
+        let deleted_note_ids = stmt
+            .query_map([self.workspace(), since], |row| row.get(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut stmt = self.conn().prepare(&format!(
+            r#"
+            SELECT source_id, target_id, directed, created_at, weight
+            FROM links
+            WHERE workspace_id = ?1 AND {LIVE_LINK_FILTER}
+              AND (source_id, target_id) IN (
+                SELECT source_id, target_id FROM sync_log WHERE workspace_id = ?1 AND seq > ?2
+              )
+            ORDER BY {LINK_ORDER}
+            "#
+        ))?;
+        let links = stmt
+            .query_map([self.workspace(), since], map_link_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut stmt = self.conn().prepare(&format!(
+            r#"
+            SELECT source_id, target_id
+            FROM (
+                SELECT source_id, target_id FROM sync_log
+                WHERE workspace_id = ?1 AND seq > ?2 AND source_id IS NOT NULL
+                UNION
+                SELECT entity_id, target_id FROM deletions
+                WHERE workspace_id = ?1 AND seq > ?2 AND entity_type = 'link'
+            )
+            WHERE (source_id, target_id) NOT IN (
+                SELECT source_id, target_id FROM links
+                WHERE workspace_id = ?1 AND {LIVE_LINK_FILTER}
+            )
+            ORDER BY source_id, target_id
+            "#
+        ))?;
+        let deleted_links = stmt
+            .query_map([self.workspace(), since], |row| {
+                Ok(LinkKey {
+                    source_id: row.get(0)?,
+                    target_id: row.get(1)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(SyncResponse {
+            notes,
+            links,
+            deleted_note_ids,
+            deleted_links,
+            cursor: cursor.to_string(),
+        })
+    }
+
+    fn links_of(&self, note_id: i64) -> StoreResult<Vec<Link>> {
+        let mut stmt = self.conn().prepare(&format!(
+            r#"
+            SELECT source_id, target_id, directed, created_at, weight
+            FROM links
+            WHERE (source_id = ?1 OR target_id = ?1) AND {LIVE_LINK_FILTER}
+            ORDER BY {LINK_ORDER}
+            "#
+        ))?;
+
+        let rows = stmt.query_map([note_id], map_link_row)?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// The note itself, every note directly linked to it and the links
+    /// between them.
+    fn neighbors(&self, id: i64) -> StoreResult<GraphResponse> {
+        let center = self
+            .get_note(id)?
+            .ok_or_else(|| StoreError::NotFound(format!("note {id} not found")))?;
+
+        let links = self.links_of(id)?;
+        let mut notes = vec![center];
+        let mut seen = HashSet::from([id]);
+        for link in &links {
+            let other_id = if link.source_id == id {
+                link.target_id
+            } else {
+                link.source_id
+            };
+            if seen.insert(other_id) {
+                if let Some(note) = self.get_note(other_id)? {
+                    notes.push(note);
+                }
+            }
+        }
+
+        Ok(GraphResponse { notes, links })
+    }
+
+    /// The live links of `id`, each with the title of the note at the other
+    /// end, ordered by that title.
+    fn note_links(&self, id: i64) -> StoreResult<NoteLinksResponse> {
+        if !self.note_exists(id)? {
+            return Err(StoreError::NotFound(format!("note {id} not found")));
+        }
+
+        let mut stmt = self.conn().prepare(&format!(
+            r#"
+            SELECT links.source_id, links.directed, links.wikilink, links.created_at,
+                   other.id, other.title
+            FROM links
+            JOIN notes AS other
+              ON other.id = CASE WHEN links.source_id = ?1 THEN links.target_id ELSE links.source_id END
+            WHERE (links.source_id = ?1 OR links.target_id = ?1) AND {LIVE_LINK_FILTER}
+            ORDER BY other.title COLLATE NOCASE ASC, other.id ASC
+            "#
+        ))?;
+        let mut rows = stmt.query([id])?;
+
+        let mut response = NoteLinksResponse::default();
+        while let Some(row) = rows.next()? {
+            let source_id: i64 = row.get(0)?;
+            let directed: bool = row.get(1)?;
+            let link = NoteLink {
+                wikilink: row.get(2)?,
+                created_at: row.get(3)?,
+                note_id: row.get(4)?,
+                title: row.get(5)?,
+            };
+            match (directed, source_id == id) {
+                (false, _) => response.undirected.push(link),
+                (true, true) => response.outgoing.push(link),
+                (true, false) => response.incoming.push(link),
+            }
+        }
+        Ok(response)
+    }
+
+    fn search_in_note(&self, id: i64, query: &str) -> StoreResult<NoteSearchResponse> {
+        if query.is_empty() {
+            return Err(StoreError::Validation("q cannot be empty".to_string()));
+        }
+        let note = self
+            .get_note(id)?
+            .ok_or_else(|| StoreError::NotFound(format!("note {id} not found")))?;
+        Ok(content_matches(&note.content, query))
+    }
+
+    /// Every note within `depth` hops of `id` (ignoring link direction) and
+    /// all links among them, in BFS order from the starting note.
+    fn subgraph(&self, id: i64, depth: usize) -> StoreResult<GraphResponse> {
+        if !self.note_exists(id)? {
+            return Err(StoreError::NotFound(format!("note {id} not found")));
+        }
+
+        let all_links = self.list_links()?;
+        let adjacency = undirected_adjacency(&all_links);
+
+        let mut order = vec![id];
+        let mut visited = HashSet::from([id]);
+        let mut frontier = vec![id];
+        for _ in 0..depth {
+            let mut next_frontier = Vec::new();
+            for current in frontier {
+                for &next in adjacency.get(&current).into_iter().flatten() {
+                    if visited.insert(next) {
+                        order.push(next);
+                        next_frontier.push(next);
+                    }
+                }
+            }
+            if next_frontier.is_empty() {
+                break;
+            }
+            frontier = next_frontier;
+        }
+
+        let mut notes = Vec::with_capacity(order.len());
+        for note_id in order {
+            if let Some(note) = self.get_note(note_id)? {
+                notes.push(note);
+            }
+        }
+
+        let links = all_links
+            .into_iter()
+            .filter(|link| visited.contains(&link.source_id) && visited.contains(&link.target_id))
+            .collect();
+
+        Ok(GraphResponse { notes, links })
+    }
+
+    /// Notes whose text resembles note `id`'s, most similar first. With
+    /// `exclude_linked`, notes it already links to are left out.
+    fn similar(
+        &self,
+        id: i64,
+        limit: usize,
+        exclude_linked: bool,
+    ) -> StoreResult<Vec<SimilarNote>> {
+        let note = self
+            .get_note(id)?
+            .ok_or_else(|| StoreError::NotFound(format!("note {id} not found")))?;
+
+        let exclude = if exclude_linked {
+            self.links_of(id)?
+                .into_iter()
+                .map(|link| {
+                    if link.source_id == id {
+                        link.target_id
+                    } else {
+                        link.source_id
+                    }
+                })
+                .collect()
+        } else {
+            HashSet::new()
+        };
+
+        let mut similar = Vec::with_capacity(limit);
+        for (other_id, score) in self.search_reader().similar_ids(&note, limit, &exclude)? {
+            if let Some(note) = self.get_note(other_id)? {
+                similar.push(SimilarNote { note, score });
+            }
+        }
+        Ok(similar)
+    }
+
+    /// The `limit` best link suggestions for note `id`, from `link_suggestions`.
+    fn suggestions(&self, id: i64, limit: usize) -> StoreResult<Vec<SuggestedNote>> {
+        if !self.note_exists(id)? {
+            return Err(StoreError::NotFound(format!("note {id} not found")));
+        }
+
+        let mut suggestions = Vec::with_capacity(limit);
+        for (candidate, score, shared_neighbors) in link_suggestions(id, &self.list_links()?)
+            .into_iter()
+            .take(limit)
+        {
+            if let Some(note) = self.get_note(candidate)? {
+                suggestions.push(SuggestedNote {
+                    note,
+                    score,
+                    shared_neighbors,
+                });
+            }
+        }
+        Ok(suggestions)
+    }
+
+    /// Notes within `radius` of note `id` on the canvas, closest first. The
+    /// bounding box lets SQLite use the position index before the exact
+    /// distance check.
+    fn nearby(&self, id: i64, radius: f64, limit: usize) -> StoreResult<Vec<NearbyNote>> {
+        if !radius.is_finite() || radius < 0.0 {
+            return Err(StoreError::Validation(format!(
+                "invalid radius {radius}: must be a non-negative number"
+            )));
+        }
+        let center = self
+            .get_note(id)?
+            .ok_or_else(|| StoreError::NotFound(format!("note {id} not found")))?;
+
+        let mut stmt = self.conn().prepare(&format!(
+            r#"
+            SELECT {NOTE_COLUMNS}, (x - ?2) * (x - ?2) + (y - ?3) * (y - ?3) AS distance_sq
+            FROM notes
+            WHERE workspace_id = ?1
+              AND deleted_at IS NULL
+              AND id != ?5
+              AND x BETWEEN ?2 - ?4 AND ?2 + ?4
+              AND y BETWEEN ?3 - ?4 AND ?3 + ?4
+              AND distance_sq <= ?4 * ?4
+            ORDER BY distance_sq ASC, id ASC
+            LIMIT ?6
+            "#
+        ))?;
+        let rows = stmt.query_map(
+            params![
+                self.workspace(),
+                center.x,
+                center.y,
+                radius,
+                id,
+                limit as i64
+            ],
+            |row| {
+                Ok(NearbyNote {
+                    note: map_note_row(row)?,
+                    distance: row.get::<_, f64>(18)?.sqrt(),
+                })
+            },
+        )?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// Notes positioned inside the rectangle and the links between them.
+    fn viewport(&self, area: &ViewportQuery) -> StoreResult<GraphResponse> {
+        if area.min_x > area.max_x || area.min_y > area.max_y {
+            return Err(StoreError::Validation(
+                "invalid viewport: min must not exceed max".to_string(),
+            ));
+        }
+
+        let in_area = r#"
+            workspace_id = ?1
+              AND deleted_at IS NULL
+              AND x BETWEEN ?2 AND ?4
+              AND y BETWEEN ?3 AND ?5
+        "#;
+        let bounds = params![
+            self.workspace(),
+            area.min_x,
+            area.min_y,
+            area.max_x,
+            area.max_y
+        ];
+
+        let mut stmt = self.conn().prepare(&format!(
+            "SELECT {NOTE_COLUMNS} FROM notes WHERE {in_area} ORDER BY id ASC"
+        ))?;
+        let notes = stmt
+            .query_map(bounds, map_note_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut stmt = self.conn().prepare(&format!(
+            r#"
+            SELECT source_id, target_id, directed, created_at, weight
+            FROM links
+            WHERE workspace_id = ?1
+              AND source_id IN (SELECT id FROM notes WHERE {in_area})
+              AND target_id IN (SELECT id FROM notes WHERE {in_area})
+            ORDER BY {LINK_ORDER}
+            "#
+        ))?;
+        let links = stmt
+            .query_map(bounds, map_link_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(GraphResponse { notes, links })
+    }
+
+    fn graph(&self) -> StoreResult<GraphResponse> {
+        Ok(GraphResponse {
+            notes: self.list_notes()?,
+            links: self.list_links()?,
+        })
+    }
+
+    /// Like `graph`, but every note carries its degree, counted in the same
+    /// query so clients need not derive it from the link list.
+    fn graph_with_degrees(&self) -> StoreResult<GraphResponse<GraphNote>> {
+        let mut stmt = self.conn().prepare(&format!(
+            r#"
+            SELECT {NOTE_COLUMNS}, COALESCE(degrees.degree, 0)
+            FROM notes
+            LEFT JOIN (
+                SELECT note_id, COUNT(*) AS degree
+                FROM (
+                    SELECT source_id AS note_id FROM links
+                    WHERE workspace_id = ?1 AND {LIVE_LINK_FILTER}
+                    UNION ALL
+                    SELECT target_id AS note_id FROM links
+                    WHERE workspace_id = ?1 AND {LIVE_LINK_FILTER}
+                )
+                GROUP BY note_id
+            ) AS degrees ON degrees.note_id = notes.id
+            WHERE workspace_id = ?1 AND deleted_at IS NULL
+            ORDER BY updated_at DESC
+            "#
+        ))?;
+
+        let rows = stmt.query_map([self.workspace()], |row| {
+            Ok(GraphNote {
+                note: map_note_row(row)?,
+                degree: row.get::<_, i64>(18)? as usize,
+            })
+        })?;
+        Ok(GraphResponse {
+            notes: rows.collect::<Result<Vec<_>, _>>()?,
+            links: self.list_links()?,
+        })
+    }
+
+    /// The slice of the graph matching `tag` and `query`: the matching notes
+    /// and only the links whose endpoints both match. Degrees count the
+    /// links of the slice, not of the whole graph.
+    fn filtered_graph(
+        &self,
+        tag: Option<&str>,
+        query: Option<&str>,
+    ) -> StoreResult<GraphResponse<GraphNote>> {
+        let mut ids: Option<BTreeSet<i64>> = None;
+        if let Some(tag) = tag {
+            let mut stmt = self.conn().prepare(
+                r#"
+                SELECT notes.id
+                FROM notes
+                JOIN note_tags ON note_tags.note_id = notes.id
+                WHERE notes.workspace_id = ?1 AND notes.deleted_at IS NULL AND note_tags.tag = ?2
+                "#,
+            )?;
+            let rows = stmt.query_map(params![self.workspace(), tag], |row| row.get(0))?;
+            ids = Some(rows.collect::<Result<_, _>>()?);
+        }
+        if let Some(query) = query {
+            let live = self.conn().query_row(
+                "SELECT COUNT(*) FROM notes WHERE workspace_id = ?1 AND deleted_at IS NULL",
+                [self.workspace()],
+                |row| row.get::<_, i64>(0),
+            )? as usize;
+            let found = self
+                .search_notes(query, &UpdatedRange::default(), live.max(1), 1)?
+                .results
+                .into_iter()
+                .map(|hit| hit.note.id)
+                .collect::<BTreeSet<_>>();
+            ids = Some(match ids {
+                Some(tagged) => tagged.intersection(&found).copied().collect(),
+                None => found,
+            });
+        }
+        let ids = serde_json::to_string(&ids.unwrap_or_default())?;
+
+        let mut stmt = self.conn().prepare(&format!(
+            r#"
+            SELECT {NOTE_COLUMNS}
+            FROM notes
+            WHERE workspace_id = ?1 AND id IN (SELECT value FROM json_each(?2))
+            ORDER BY updated_at DESC
+            "#
+        ))?;
+        let notes = stmt
+            .query_map(params![self.workspace(), ids], map_note_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut stmt = self.conn().prepare(&format!(
+            r#"
+            SELECT source_id, target_id, directed, created_at, weight
+            FROM links
+            WHERE workspace_id = ?1
+              AND source_id IN (SELECT value FROM json_each(?2))
+              AND target_id IN (SELECT value FROM json_each(?2))
+            ORDER BY {LINK_ORDER}
+            "#
+        ))?;
+        let links = stmt
+            .query_map(params![self.workspace(), ids], map_link_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut degrees = HashMap::<i64, usize>::new();
+        for link in &links {
+            *degrees.entry(link.source_id).or_default() += 1;
+            *degrees.entry(link.target_id).or_default() += 1;
+        }
+        let notes = notes
+            .into_iter()
+            .map(|note| GraphNote {
+                degree: degrees.get(&note.id).copied().unwrap_or(0),
+                note,
+            })
+            .collect();
+        Ok(GraphResponse { notes, links })
+    }
+
+    /// Returns the best `limit` hits and the total number of matching notes.
+    /// `tag:` and `updatedAfter:`/`updatedBefore:` tokens in `query` narrow
+    /// the results; a query made only of such tokens lists every note that
+    /// passes them, most recently updated first.
+    fn search_notes(
+        &self,
+        query: &str,
+        updated: &UpdatedRange,
+        limit: usize,
+        fuzziness: u8,
+    ) -> StoreResult<SearchResponse> {
+        let filters = parse_search_filters(query);
+        let updated = updated.intersect(&self.updated_range(
+            filters.updated_after.as_deref(),
+            filters.updated_before.as_deref(),
+        )?);
+        let tags = filters.tags;
+        let text = filters.text.as_str();
+        let filtered = updated.after.is_some() || updated.before.is_some() || !tags.is_empty();
+
+        if text.is_empty() && !filtered {
+            return Ok(SearchResponse {
+                results: Vec::new(),
+                total: 0,
+                source: SearchSource::Index,
+            });
+        }
+
+        if !text.is_empty() {
+            let passes = |id: i64| -> anyhow::Result<bool> {
+                let updated_at = self
+                    .conn()
+                    .query_row(
+                        "SELECT updated_at FROM notes WHERE id = ?1 AND deleted_at IS NULL",
+                        [id],
+                        |row| row.get::<_, String>(0),
+                    )
+                    .optional()?;
+                if !updated_at.is_some_and(|updated_at| updated.contains(&updated_at)) {
+                    return Ok(false);
+                }
+                for tag in &tags {
+                    let tagged = self.conn().query_row(
+                        "SELECT EXISTS(SELECT 1 FROM note_tags WHERE note_id = ?1 AND tag = ?2)",
+                        params![id, tag],
+                        |row| row.get::<_, bool>(0),
+                    )?;
+                    if !tagged {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            };
+
+            let page = self.search_reader().search_ids(
+                text,
+                self.workspace(),
+                limit,
+                fuzziness,
+                filtered.then_some(&passes as &dyn Fn(i64) -> anyhow::Result<bool>),
+            )?;
+            if page.total > 0 {
+                let mut results = Vec::with_capacity(page.matches.len());
+                for found in page.matches {
+                    if let Some(note) = self.get_note(found.id)? {
+                        let snippet = found
+                            .snippet
+                            .unwrap_or_else(|| content_preview(&note.content));
+                        results.push(SearchHit {
+                            note,
+                            snippet,
+                            score: found.score,
+                        });
+                    }
+                }
+                return Ok(SearchResponse {
+                    results,
+                    total: page.total,
+                    source: SearchSource::Index,
+                });
+            }
+        }
+
+        let mut clauses = vec!["workspace_id = ?", "deleted_at IS NULL"];
+        let mut values = vec![SqlValue::Integer(self.workspace())];
+        if !text.is_empty() {
+            // Folded like the index so case and accents are ignored here too.
+            clauses.push("(fold(title) LIKE ? OR fold(subtitle) LIKE ? OR fold(content) LIKE ?)");
+            let term = SqlValue::Text(format!("%{}%", fold_text(text)));
+            values.extend([term.clone(), term.clone(), term]);
+        }
+        if let Some(after) = &updated.after {
+            clauses.push("updated_at >= ?");
+            values.push(SqlValue::Text(after.clone()));
+        }
+        if let Some(before) = &updated.before {
+            clauses.push("updated_at <= ?");
+            values.push(SqlValue::Text(before.clone()));
+        }
+        for tag in &tags {
+            clauses.push("id IN (SELECT note_id FROM note_tags WHERE tag = ?)");
+            values.push(SqlValue::Text(tag.clone()));
+        }
+        let predicate = clauses.join(" AND ");
+
+        let total = self.conn().query_row(
+            &format!("SELECT COUNT(*) FROM notes WHERE {predicate}"),
+            params_from_iter(values.iter()),
+            |row| row.get::<_, i64>(0),
+        )? as usize;
+
+        values.push(SqlValue::Integer(limit as i64));
+        let mut stmt = self.conn().prepare(&format!(
+            r#"
+            SELECT {NOTE_COLUMNS}
+            FROM notes
+            WHERE {predicate}
+            ORDER BY updated_at DESC
+            LIMIT ?
+            "#
+        ))?;
+        let rows = stmt.query_map(params_from_iter(values.iter()), map_note_row)?;
+        let notes = rows.collect::<Result<Vec<_>, _>>()?;
+
+        Ok(SearchResponse {
+            results: notes
+                .into_iter()
+                .map(|note| SearchHit {
+                    snippet: if text.is_empty() {
+                        content_preview(&note.content)
+                    } else {
+                        substring_snippet(&note.content, text)
+                    },
+                    note,
+                    score: 0.0,
+                })
+                .collect(),
+            total,
+            source: SearchSource::Like,
+        })
+    }
+
+    /// Titles containing `prefix` (case-insensitively), with titles that start
+    /// with it first and the most recently updated first within each group.
+    /// `instr` rather than `LIKE` so `%` and `_` in the input match literally.
+    fn suggest_titles(&self, prefix: &str, limit: usize) -> StoreResult<Vec<Suggestion>> {
+        let prefix = prefix.trim();
+        if prefix.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut stmt = self.conn().prepare(
+            r#"
+            SELECT id, title
+            FROM notes
+            WHERE workspace_id = ?2
+              AND deleted_at IS NULL
+              AND instr(lower(title), lower(?1)) > 0
+            ORDER BY instr(lower(title), lower(?1)) = 1 DESC, updated_at DESC, id DESC
+            LIMIT ?3
             "#,
         )?;
+        let rows = stmt.query_map(params![prefix, self.workspace(), limit as i64], |row| {
+            Ok(Suggestion {
+                id: row.get(0)?,
+                title: row.get(1)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// Cheapest path by link weight, with its cost; see `weighted_path`.
+    fn shortest_path(&self, from: i64, to: i64) -> StoreResult<Option<(Vec<i64>, f64)>> {
+        for id in [from, to] {
+            if !self.note_exists(id)? {
+                return Err(StoreError::NotFound(format!("note {id} not found")));
+            }
+        }
+
+        if from == to {
+            return Ok(Some((vec![from], 0.0)));
+        }
+
+        Ok(weighted_path(&self.list_links()?, from, to))
+    }
+
+    fn directed_cycles(&self) -> StoreResult<Vec<Vec<i64>>> {
+        Ok(directed_cycles(&self.list_links()?))
+    }
+
+    fn topological_order(&self) -> StoreResult<Result<Vec<i64>, Vec<i64>>> {
+        let ids = self.list_note_ids()?;
+        let links = self.list_links()?;
+        Ok(topological_order(&ids, &links))
+    }
+
+    fn connected_components(&self) -> StoreResult<Vec<Vec<i64>>> {
+        let ids = self.list_note_ids()?;
+        let links = self.list_links()?;
+        Ok(weak_components(&ids, &links))
+    }
+
+    fn communities(&self) -> StoreResult<Vec<Vec<i64>>> {
+        let ids = self.list_note_ids()?;
+        let links = self.list_links()?;
+        Ok(label_propagation(&ids, &links))
+    }
+
+    fn list_orphans(&self, limit: usize) -> StoreResult<Vec<Note>> {
+        let mut stmt = self.conn().prepare(&format!(
+            r#"
+            SELECT {NOTE_COLUMNS}
+            FROM notes
+            WHERE workspace_id = ?2
+              AND deleted_at IS NULL
+              AND id NOT IN (
+                SELECT source_id FROM links WHERE {LIVE_LINK_FILTER}
+                UNION
+                SELECT target_id FROM links WHERE {LIVE_LINK_FILTER}
+              )
+            ORDER BY updated_at DESC
+            LIMIT ?1
+            "#
+        ))?;
+
+        let rows = stmt.query_map([limit as i64, self.workspace()], map_note_row)?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// Live notes sharing a normalized title with at least one other note,
+    /// grouped by that title in title order, oldest note first in each group.
+    fn duplicate_titles(&self) -> StoreResult<Vec<DuplicateGroup>> {
+        let mut stmt = self.conn().prepare(&format!(
+            r#"
+            SELECT {NOTE_COLUMNS}, duplicates.title_key
+            FROM notes
+            JOIN (
+                SELECT lower(trim(title)) AS title_key
+                FROM notes
+                WHERE workspace_id = ?1 AND deleted_at IS NULL
+                GROUP BY lower(trim(title))
+                HAVING count(*) > 1
+            ) AS duplicates ON duplicates.title_key = lower(trim(notes.title))
+            WHERE workspace_id = ?1 AND deleted_at IS NULL
+            ORDER BY duplicates.title_key, notes.id
+            "#
+        ))?;
+
+        let mut groups: Vec<DuplicateGroup> = Vec::new();
+        let mut rows = stmt.query([self.workspace()])?;
+        while let Some(row) = rows.next()? {
+            let note = map_note_row(row)?;
+            let title: String = row.get(18)?;
+            match groups.last_mut() {
+                Some(group) if group.title == title => group.notes.push(note),
+                _ => groups.push(DuplicateGroup {
+                    title,
+                    notes: vec![note],
+                }),
+            }
+        }
+        Ok(groups)
+    }
+
+    /// Visits every live note in id order without collecting them.
+    fn for_each_note(&self, mut visit: impl FnMut(Note) -> anyhow::Result<()>) -> StoreResult<()> {
+        let mut stmt = self.conn().prepare(&format!(
+            r#"
+            SELECT {NOTE_COLUMNS}
+            FROM notes
+            WHERE workspace_id = ?1 AND deleted_at IS NULL
+            ORDER BY id ASC
+            "#
+        ))?;
+
+        let mut rows = stmt.query([self.workspace()])?;
+        while let Some(row) = rows.next()? {
+            visit(map_note_row(row)?)?;
+        }
+        Ok(())
+    }
+
+    /// Visits every live link without collecting them.
+    fn for_each_link(&self, mut visit: impl FnMut(Link) -> anyhow::Result<()>) -> StoreResult<()> {
+        let mut stmt = self.conn().prepare(&format!(
+            r#"
+            SELECT source_id, target_id, directed, created_at, weight
+            FROM links
+            WHERE workspace_id = ?1 AND {LIVE_LINK_FILTER}
+            ORDER BY {LINK_ORDER}
+            "#
+        ))?;
+
+        let mut rows = stmt.query([self.workspace()])?;
+        while let Some(row) = rows.next()? {
+            visit(map_link_row(row)?)?;
+        }
+        Ok(())
+    }
+
+    /// Trashed notes, most recently deleted first.
+    fn list_trash(&self) -> StoreResult<Vec<TrashedNote>> {
+        let mut stmt = self.conn().prepare(&format!(
+            r#"
+            SELECT {NOTE_COLUMNS}, deleted_at
+            FROM notes
+            WHERE workspace_id = ?1 AND deleted_at IS NOT NULL
+            ORDER BY deleted_at DESC, id DESC
+            "#
+        ))?;
+
+        let rows = stmt.query_map([self.workspace()], |row| {
+            Ok(TrashedNote {
+                note: map_note_row(row)?,
+                deleted_at: row.get(18)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// Scores for every note, highest first (ties by id).
+    fn centrality(&self, metric: CentralityMetric) -> StoreResult<Vec<CentralityScore>> {
+        let ids = self.list_note_ids()?;
+        let links = self.list_links()?;
+
+        let scores = match metric {
+            CentralityMetric::PageRank => pagerank(&ids, &links),
+            CentralityMetric::Degree => degree_centrality(&ids, &links),
+            CentralityMetric::Betweenness => {
+                if ids.len() > BETWEENNESS_MAX_NOTES {
+                    return Err(StoreError::Validation(format!(
+                        "betweenness centrality is limited to {BETWEENNESS_MAX_NOTES} notes, this workspace has {}",
+                        ids.len()
+                    )));
+                }
+                betweenness_centrality(&ids, &links)
+            }
+        };
+
+        let mut scores = scores
+            .into_iter()
+            .map(|(id, score)| CentralityScore { id, score })
+            .collect::<Vec<_>>();
+        scores.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.id.cmp(&b.id)));
+        Ok(scores)
+    }
+
+    /// Aggregate metrics computed from ids and links only, so note contents
+    /// never need to be loaded. Density treats every link as undirected.
+    fn stats(&self) -> StoreResult<GraphStats> {
+        let ids = self.list_note_ids()?;
+        let links = self.list_links()?;
+
+        let mut degrees = ids
+            .iter()
+            .map(|id| (*id, 0usize))
+            .collect::<HashMap<_, _>>();
+        for link in &links {
+            for endpoint in [link.source_id, link.target_id] {
+                if let Some(degree) = degrees.get_mut(&endpoint) {
+                    *degree += 1;
+                }
+            }
+        }
+
+        let mut sorted = degrees.into_values().collect::<Vec<_>>();
+        sorted.sort_unstable();
+
+        let note_count = ids.len();
+        let link_count = links.len();
+        let median_degree = match note_count {
+            0 => 0.0,
+            n if n % 2 == 1 => sorted[n / 2] as f64,
+            n => (sorted[n / 2 - 1] + sorted[n / 2]) as f64 / 2.0,
+        };
+        let density = if note_count > 1 {
+            2.0 * link_count as f64 / (note_count * (note_count - 1)) as f64
+        } else {
+            0.0
+        };
+
+        Ok(GraphStats {
+            note_count,
+            link_count,
+            average_degree: if note_count > 0 {
+                2.0 * link_count as f64 / note_count as f64
+            } else {
+                0.0
+            },
+            density,
+            component_count: weak_components(&ids, &links).len(),
+            max_degree: sorted.last().copied().unwrap_or(0),
+            min_degree: sorted.first().copied().unwrap_or(0),
+            median_degree,
+        })
+    }
+}
+
+impl Queries for Store {
+    fn conn(&self) -> &Connection {
+        &self.conn
+    }
+
+    fn search_reader(&self) -> &SearchReader {
+        &self.search.reader
+    }
+
+    fn workspace(&self) -> i64 {
+        self.workspace
+    }
+}
+
+fn map_note_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Note> {
+    Ok(Note {
+        id: row.get(0)?,
+        uuid: row.get(15)?,
+        title: row.get(1)?,
+        subtitle: row.get(2)?,
+        content: row.get(3)?,
+        x: row.get(4)?,
+        y: row.get(5)?,
+        parent_id: row.get(6)?,
+        updated_at: row.get(7)?,
+        tags: json_column(row, 8)?,
+        workspace_id: row.get(9)?,
+        created_at: row.get(10)?,
+        version: row.get(11)?,
+        color: row.get(12)?,
+        icon: row.get(13)?,
+        pinned: row.get(14)?,
+        metadata: json_column(row, 16)?,
+        z: row.get(17)?,
+    })
+}
+
+fn json_column<T: serde::de::DeserializeOwned>(
+    row: &rusqlite::Row<'_>,
+    idx: usize,
+) -> rusqlite::Result<T> {
+    let raw: String = row.get(idx)?;
+    serde_json::from_str(&raw).map_err(|err| {
+        rusqlite::Error::FromSqlConversionFailure(idx, rusqlite::types::Type::Text, Box::new(err))
+    })
+}
+
+fn map_workspace_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Workspace> {
+    Ok(Workspace {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        created_at: row.get(2)?,
+    })
+}
+
+fn map_link_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Link> {
+    Ok(Link {
+        source_id: row.get(0)?,
+        target_id: row.get(1)?,
+        directed: row.get(2)?,
+        created_at: row.get(3)?,
+        weight: row.get(4)?,
+    })
+}
+
+fn normalize_edge(a: i64, b: i64) -> StoreResult<(i64, i64)> {
+    if a == b {
+        return Err(StoreError::Validation(
+            "a note cannot link to itself".to_string(),
+        ));
+    }
+
+    if a < b {
+        Ok((a, b))
+    } else {
+        Ok((b, a))
+    }
+}
+
+/// Directed edges keep their orientation; undirected ones are stored in
+/// canonical `(min, max)` order.
+fn edge_key(a: i64, b: i64, directed: bool) -> StoreResult<(i64, i64)> {
+    if !directed {
+        return normalize_edge(a, b);
+    }
+
+    if a == b {
+        return Err(StoreError::Validation(
+            "a note cannot link to itself".to_string(),
+        ));
+    }
+
+    Ok((a, b))
+}
+
+/// Builds an adjacency map from links. Undirected links are traversable both
+/// ways, directed links only from source to target.
+fn adjacency(links: &[Link]) -> HashMap<i64, Vec<i64>> {
+    let mut adjacency: HashMap<i64, Vec<i64>> = HashMap::new();
+    for link in links {
+        adjacency
+            .entry(link.source_id)
+            .or_default()
+            .push(link.target_id);
+        if !link.directed {
+            adjacency
+                .entry(link.target_id)
+                .or_default()
+                .push(link.source_id);
+        }
+    }
+    adjacency
+}
+
+/// Directed links only, with each target list sorted so traversals are
+/// deterministic. Undirected links are ignored.
+fn directed_adjacency(links: &[Link]) -> HashMap<i64, Vec<i64>> {
+    let mut adjacency: HashMap<i64, Vec<i64>> = HashMap::new();
+    for link in links.iter().filter(|link| link.directed) {
+        adjacency
+            .entry(link.source_id)
+            .or_default()
+            .push(link.target_id);
+    }
+    for targets in adjacency.values_mut() {
+        targets.sort_unstable();
+    }
+    adjacency
+}
+
+fn undirected_adjacency(links: &[Link]) -> HashMap<i64, Vec<i64>> {
+    let mut adjacency: HashMap<i64, Vec<i64>> = HashMap::new();
+    for link in links {
+        adjacency
+            .entry(link.source_id)
+            .or_default()
+            .push(link.target_id);
+        adjacency
+            .entry(link.target_id)
+            .or_default()
+            .push(link.source_id);
+    }
+    adjacency
+}
+
+/// Groups `links` by endpoint, with an empty list for notes that have no
+/// links so every note is a key.
+fn adjacency_list(notes: &[GraphNote], links: &[Link]) -> BTreeMap<i64, Vec<i64>> {
+    let mut adjacency = notes
+        .iter()
+        .map(|note| (note.note.id, Vec::new()))
+        .collect::<BTreeMap<_, _>>();
+    for (id, mut neighbors) in undirected_adjacency(links) {
+        neighbors.sort_unstable();
+        neighbors.dedup();
+        adjacency.insert(id, neighbors);
+    }
+    adjacency
+}
+
+const PAGERANK_DAMPING: f64 = 0.85;
+const PAGERANK_ITERATIONS: usize = 50;
+
+/// PageRank over the link graph; undirected links count in both directions.
+/// Rank held by notes without outgoing links is spread evenly across all
+/// notes so the scores keep summing to 1.
+fn pagerank(ids: &[i64], links: &[Link]) -> Vec<(i64, f64)> {
+    let count = ids.len();
+    if count == 0 {
+        return Vec::new();
+    }
+
+    let index = ids
+        .iter()
+        .enumerate()
+        .map(|(position, id)| (*id, position))
+        .collect::<HashMap<_, _>>();
+    let adjacency = adjacency(links);
+    let outgoing = ids
+        .iter()
+        .map(|id| {
+            adjacency
+                .get(id)
+                .into_iter()
+                .flatten()
+                .filter_map(|target| index.get(target).copied())
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    let n = count as f64;
+    let mut ranks = vec![1.0 / n; count];
+    for _ in 0..PAGERANK_ITERATIONS {
+        let dangling = (0..count)
+            .filter(|node| outgoing[*node].is_empty())
+            .map(|node| ranks[node])
+            .sum::<f64>();
+        let base = (1.0 - PAGERANK_DAMPING) / n + PAGERANK_DAMPING * dangling / n;
+
+        let mut next = vec![base; count];
+        for (node, targets) in outgoing.iter().enumerate() {
+            if targets.is_empty() {
+                continue;
+            }
+            let share = PAGERANK_DAMPING * ranks[node] / targets.len() as f64;
+            for target in targets {
+                next[*target] += share;
+            }
+        }
+        ranks = next;
+    }
+
+    ids.iter().copied().zip(ranks).collect()
+}
+
+/// Notes two hops from `id` that are not linked to it, scored by Adamic-Adar:
+/// each shared neighbor adds `1 / ln(degree)`, so hubs count for less than
+/// notes with few links. Returns `(id, score, shared neighbors)`, best first.
+fn link_suggestions(id: i64, links: &[Link]) -> Vec<(i64, f64, usize)> {
+    let mut neighbors: HashMap<i64, HashSet<i64>> = HashMap::new();
+    for (note_id, others) in undirected_adjacency(links) {
+        neighbors.insert(note_id, others.into_iter().collect());
+    }
+    let Some(direct) = neighbors.get(&id) else {
+        return Vec::new();
+    };
+
+    let mut candidates: HashMap<i64, (f64, usize)> = HashMap::new();
+    for shared in direct {
+        let around = &neighbors[shared];
+        // `shared` links to `id` and the candidate, so its degree is at
+        // least 2 and the logarithm is positive.
+        let weight = 1.0 / (around.len() as f64).ln();
+        for &candidate in around {
+            if candidate == id || direct.contains(&candidate) {
+                continue;
+            }
+            let entry = candidates.entry(candidate).or_default();
+            entry.0 += weight;
+            entry.1 += 1;
+        }
+    }
+
+    let mut scored = candidates
+        .into_iter()
+        .map(|(candidate, (score, shared))| (candidate, score, shared))
+        .collect::<Vec<_>>();
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    scored
+}
+
+/// Links per note divided by the number of other notes, ignoring direction.
+fn degree_centrality(ids: &[i64], links: &[Link]) -> Vec<(i64, f64)> {
+    let adjacency = undirected_adjacency(links);
+    let others = ids.len().saturating_sub(1).max(1) as f64;
+    ids.iter()
+        .map(|id| {
+            let degree = adjacency.get(id).map_or(0, Vec::len);
+            (*id, degree as f64 / others)
+        })
+        .collect()
+}
+
+/// Brandes' algorithm costs O(notes × links); past this size
+/// `metric=betweenness` is refused rather than tying up a read connection.
+const BETWEENNESS_MAX_NOTES: usize = 5000;
+
+/// Betweenness centrality by Brandes' algorithm, ignoring direction: the
+/// share of shortest paths between other notes that pass through each note,
+/// normalized to 0..1.
+fn betweenness_centrality(ids: &[i64], links: &[Link]) -> Vec<(i64, f64)> {
+    let index: HashMap<i64, usize> = ids.iter().enumerate().map(|(i, &id)| (id, i)).collect();
+    let adjacency = undirected_adjacency(links);
+    let neighbors: Vec<Vec<usize>> = ids
+        .iter()
+        .map(|id| {
+            adjacency
+                .get(id)
+                .into_iter()
+                .flatten()
+                .filter_map(|next| index.get(next).copied())
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .collect()
+        })
+        .collect();
+
+    let n = ids.len();
+    let mut centrality = vec![0.0; n];
+    for source in 0..n {
+        let mut stack = Vec::with_capacity(n);
+        let mut predecessors: Vec<Vec<usize>> = vec![Vec::new(); n];
+        let mut paths = vec![0.0; n];
+        let mut distance: Vec<Option<usize>> = vec![None; n];
+        paths[source] = 1.0;
+        distance[source] = Some(0);
+
+        let mut queue = VecDeque::from([source]);
+        while let Some(current) = queue.pop_front() {
+            stack.push(current);
+            let next_distance = distance[current].map(|d| d + 1);
+            for &next in &neighbors[current] {
+                if distance[next].is_none() {
+                    distance[next] = next_distance;
+                    queue.push_back(next);
+                }
+                if distance[next] == next_distance {
+                    paths[next] += paths[current];
+                    predecessors[next].push(current);
+                }
+            }
+        }
+
+        let mut dependency = vec![0.0; n];
+        while let Some(current) = stack.pop() {
+            for &previous in &predecessors[current] {
+                dependency[previous] +=
+                    paths[previous] / paths[current] * (1.0 + dependency[current]);
+            }
+            if current != source {
+                centrality[current] += dependency[current];
+            }
+        }
+    }
+
+    // Each unordered pair was counted from both ends.
+    let pairs = (n.saturating_sub(1) * n.saturating_sub(2)) as f64;
+    ids.iter()
+        .zip(centrality)
+        .map(|(&id, score)| (id, if pairs > 0.0 { score / pairs } else { 0.0 }))
+        .collect()
+}
+
+/// Groups notes into weakly connected components, largest first. Notes
+/// without links form singleton components.
+fn weak_components(ids: &[i64], links: &[Link]) -> Vec<Vec<i64>> {
+    let adjacency = undirected_adjacency(links);
+    let mut visited = HashSet::new();
+    let mut components = Vec::new();
+
+    for &start in ids {
+        if !visited.insert(start) {
+            continue;
+        }
+
+        let mut component = vec![start];
+        let mut queue = VecDeque::from([start]);
+        while let Some(current) = queue.pop_front() {
+            for &next in adjacency.get(&current).into_iter().flatten() {
+                if visited.insert(next) {
+                    component.push(next);
+                    queue.push_back(next);
+                }
+            }
+        }
+
+        component.sort_unstable();
+        components.push(component);
+    }
+
+    components.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a[0].cmp(&b[0])));
+    components
+}
+
+const LABEL_PROPAGATION_MAX_ITERATIONS: usize = 100;
+
+/// Clusters notes by label propagation over the undirected link graph,
+/// grouped like `weak_components`. Every note starts with its own id as
+/// label and, visiting notes in id order, repeatedly adopts the label with
+/// the most weight among its neighbours until nothing changes. A link weighs
+/// one plus the number of neighbours its notes share, so labels spread
+/// through tightly knit groups before they cross a lone bridge. Ties keep
+/// the current label if it is among them and otherwise take the smallest,
+/// so the result is deterministic.
+fn label_propagation(ids: &[i64], links: &[Link]) -> Vec<Vec<i64>> {
+    let neighbors: HashMap<i64, BTreeSet<i64>> = undirected_adjacency(links)
+        .into_iter()
+        .map(|(id, next)| (id, next.into_iter().collect()))
+        .collect();
+    let mut order = ids.to_vec();
+    order.sort_unstable();
+    let mut labels: HashMap<i64, i64> = order.iter().map(|&id| (id, id)).collect();
+
+    for _ in 0..LABEL_PROPAGATION_MAX_ITERATIONS {
+        let mut changed = false;
+        for &id in &order {
+            let Some(own) = neighbors.get(&id) else {
+                continue;
+            };
+            let mut counts: BTreeMap<i64, usize> = BTreeMap::new();
+            for next in own {
+                if let Some(&label) = labels.get(next) {
+                    let shared = neighbors
+                        .get(next)
+                        .map_or(0, |theirs| own.intersection(theirs).count());
+                    *counts.entry(label).or_default() += 1 + shared;
+                }
+            }
+            let Some(&best) = counts.values().max() else {
+                continue;
+            };
+            let current = labels[&id];
+            if counts.get(&current) == Some(&best) {
+                continue;
+            }
+            // BTreeMap iterates labels in ascending order.
+            let label = counts
+                .iter()
+                .find(|(_, &count)| count == best)
+                .map(|(&label, _)| label)
+                .unwrap_or(current);
+            labels.insert(id, label);
+            changed = true;
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    let mut groups: BTreeMap<i64, Vec<i64>> = BTreeMap::new();
+    for &id in &order {
+        groups.entry(labels[&id]).or_default().push(id);
+    }
+    let mut communities = groups.into_values().collect::<Vec<_>>();
+    communities.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a[0].cmp(&b[0])));
+    communities
+}
+
+/// Cycles among directed links, found by depth-first search: every edge back
+/// into the current path yields the cycle it closes. Each cycle is rotated to
+/// start at its smallest id, and duplicates are dropped. This reports at
+/// least one cycle through every cyclic part of the graph, not every
+/// elementary cycle, which could be exponentially many.
+fn directed_cycles(links: &[Link]) -> Vec<Vec<i64>> {
+    let adjacency = directed_adjacency(links);
+    let mut starts = adjacency.keys().copied().collect::<Vec<_>>();
+    starts.sort_unstable();
+
+    let mut finished = HashSet::new();
+    let mut cycles = BTreeSet::new();
+
+    for start in starts {
+        if finished.contains(&start) {
+            continue;
+        }
+
+        // `path` is the current DFS branch, `positions` indexes it and
+        // `cursors` remembers the next child to visit for every entry.
+        let mut path = vec![start];
+        let mut positions = HashMap::from([(start, 0)]);
+        let mut cursors = vec![0];
+
+        while let (Some(&current), Some(cursor)) = (path.last(), cursors.last_mut()) {
+            let children = adjacency.get(&current).map(Vec::as_slice).unwrap_or(&[]);
+            let Some(&child) = children.get(*cursor) else {
+                finished.insert(current);
+                positions.remove(&current);
+                path.pop();
+                cursors.pop();
+                continue;
+            };
+            *cursor += 1;
+
+            if let Some(&position) = positions.get(&child) {
+                let mut cycle = path[position..].to_vec();
+                if let Some(smallest) = cycle
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, id)| **id)
+                    .map(|(index, _)| index)
+                {
+                    cycle.rotate_left(smallest);
+                }
+                cycles.insert(cycle);
+            } else if !finished.contains(&child) {
+                positions.insert(child, path.len());
+                path.push(child);
+                cursors.push(0);
+            }
+        }
+    }
+
+    cycles.into_iter().collect()
+}
+
+/// Kahn's algorithm over the directed links, so every note comes after the
+/// notes linking to it. Ready notes are taken smallest id first, which keeps
+/// the order stable. Undirected links impose no order. When the links are not
+/// acyclic, one of the cycles that blocked the sort is returned instead.
+fn topological_order(ids: &[i64], links: &[Link]) -> Result<Vec<i64>, Vec<i64>> {
+    let adjacency = directed_adjacency(links);
+    let mut in_degree = ids
+        .iter()
+        .map(|id| (*id, 0usize))
+        .collect::<HashMap<_, _>>();
+    for targets in adjacency.values() {
+        for target in targets {
+            if let Some(degree) = in_degree.get_mut(target) {
+                *degree += 1;
+            }
+        }
+    }
+
+    let mut ready = in_degree
+        .iter()
+        .filter(|(_, degree)| **degree == 0)
+        .map(|(id, _)| Reverse(*id))
+        .collect::<BinaryHeap<_>>();
+    let mut order = Vec::with_capacity(ids.len());
+
+    while let Some(Reverse(id)) = ready.pop() {
+        order.push(id);
+        for target in adjacency.get(&id).into_iter().flatten() {
+            if let Some(degree) = in_degree.get_mut(target) {
+                *degree -= 1;
+                if *degree == 0 {
+                    ready.push(Reverse(*target));
+                }
+            }
+        }
+    }
+
+    if order.len() == ids.len() {
+        return Ok(order);
+    }
+
+    let sorted = order.into_iter().collect::<HashSet<_>>();
+    let blocked = links
+        .iter()
+        .filter(|link| !sorted.contains(&link.source_id) && !sorted.contains(&link.target_id))
+        .cloned()
+        .collect::<Vec<_>>();
+    Err(directed_cycles(&blocked)
+        .into_iter()
+        .next()
+        .unwrap_or_default())
+}
+
+/// Cheapest path from `from` to `to` (Dijkstra), following links the way
+/// `adjacency` does. A link costs `1 / weight`, so with every weight at 1
+/// this is the path with the fewest hops. Returns the path and its cost.
+fn weighted_path(links: &[Link], from: i64, to: i64) -> Option<(Vec<i64>, f64)> {
+    let mut edges: HashMap<i64, Vec<(i64, f64)>> = HashMap::new();
+    for link in links {
+        let cost = 1.0 / link.weight;
+        edges
+            .entry(link.source_id)
+            .or_default()
+            .push((link.target_id, cost));
+        if !link.directed {
+            edges
+                .entry(link.target_id)
+                .or_default()
+                .push((link.source_id, cost));
+        }
+    }
+
+    /// Queue entry ordered so the cheapest pops first, then the lowest id.
+    struct Frontier(f64, i64);
+    impl PartialEq for Frontier {
+        fn eq(&self, other: &Self) -> bool {
+            self.cmp(other).is_eq()
+        }
+    }
+    impl Eq for Frontier {}
+    impl PartialOrd for Frontier {
+        fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+    impl Ord for Frontier {
+        fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+            other.0.total_cmp(&self.0).then(other.1.cmp(&self.1))
+        }
+    }
+
+    let mut best = HashMap::from([(from, 0.0)]);
+    let mut previous = HashMap::new();
+    let mut queue = BinaryHeap::from([Frontier(0.0, from)]);
+    while let Some(Frontier(cost, current)) = queue.pop() {
+        if current == to {
+            let mut path = vec![to];
+            let mut cursor = to;
+            while cursor != from {
+                cursor = previous[&cursor];
+                path.push(cursor);
+            }
+            path.reverse();
+            return Some((path, cost));
+        }
+        if cost > best[&current] {
+            continue;
+        }
+
+        for &(next, step) in edges.get(&current).into_iter().flatten() {
+            let reached = cost + step;
+            if best.get(&next).is_none_or(|known| reached < *known) {
+                best.insert(next, reached);
+                previous.insert(next, current);
+                queue.push(Frontier(reached, next));
+            }
+        }
+    }
+
+    None
+}
+
+fn bfs_path(adjacency: &HashMap<i64, Vec<i64>>, from: i64, to: i64) -> Option<Vec<i64>> {
+    let mut previous = HashMap::new();
+    let mut queue = VecDeque::from([from]);
+    previous.insert(from, from);
+
+    while let Some(current) = queue.pop_front() {
+        if current == to {
+            let mut path = vec![to];
+            let mut cursor = to;
+            while cursor != from {
+                cursor = previous[&cursor];
+                path.push(cursor);
+            }
+            path.reverse();
+            return Some(path);
+        }
+
+        for next in adjacency.get(&current).into_iter().flatten() {
+            if !previous.contains_key(next) {
+                previous.insert(*next, current);
+                queue.push_back(*next);
+            }
+        }
+    }
+
+    None
+}
+
+/// Concentric rings around the most connected note, highest degree first.
+fn ring_layout(notes: &[Note], links: &[Link]) -> Vec<(i64, f64, f64)> {
+    let mut ids = notes.iter().map(|n| n.id).collect::<Vec<_>>();
+    ids.sort_by_key(|id| {
+        let degree = links
+            .iter()
+            .filter(|edge| edge.source_id == *id || edge.target_id == *id)
+            .count();
+        Reverse(degree)
+    });
+
+    let mut positions = Vec::with_capacity(ids.len());
+    let mut cursor = 0usize;
+    let mut ring = 0usize;
+
+    while cursor < ids.len() {
+        if ring == 0 {
+            positions.push((ids[cursor], 0.0, 0.0));
+            cursor += 1;
+            ring += 1;
+            continue;
+        }
+
+        let slots = ring * 6;
+        let radius = ring as f64 * 180.0;
+
+        for slot in 0..slots {
+            if cursor >= ids.len() {
+                break;
+            }
+
+            let angle = (slot as f64 / slots as f64) * std::f64::consts::TAU;
+            positions.push((ids[cursor], radius * angle.cos(), radius * angle.sin()));
+            cursor += 1;
+        }
+
+        ring += 1;
+    }
+
+    positions
+}
+
+const FORCE_ITERATIONS: usize = 300;
+/// Every iteration compares all pairs of notes; past this size
+/// `algorithm=force` is refused.
+const FORCE_LAYOUT_MAX_NOTES: usize = 2000;
+const FORCE_IDEAL_DISTANCE: f64 = 180.0;
+const FORCE_GRAVITY: f64 = 0.1;
+
+/// Fruchterman-Reingold simulation seeded from the current positions: links
+/// act as springs, every pair of notes repels, and a weak pull towards the
+/// origin keeps disconnected clusters on screen. The result is centered.
+fn force_layout(notes: &[Note], links: &[Link]) -> Vec<(i64, f64, f64)> {
+    let count = notes.len();
+    let index = notes
+        .iter()
+        .enumerate()
+        .map(|(i, note)| (note.id, i))
+        .collect::<HashMap<_, _>>();
+
+    let mut positions = notes
+        .iter()
+        .map(|note| {
+            if note.x.is_finite() && note.y.is_finite() {
+                (note.x, note.y)
+            } else {
+                (0.0, 0.0)
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // Coincident notes would have no direction to repel along, so spread
+    // them on a small spiral first.
+    let mut occupied = HashSet::new();
+    for (i, position) in positions.iter_mut().enumerate() {
+        if !occupied.insert((position.0.to_bits(), position.1.to_bits())) {
+            let angle = i as f64 * 2.399_963;
+            let radius = 10.0 + i as f64;
+            position.0 += radius * angle.cos();
+            position.1 += radius * angle.sin();
+        }
+    }
+
+    let edges = links
+        .iter()
+        .filter_map(|link| {
+            Some((
+                *index.get(&link.source_id)?,
+                *index.get(&link.target_id)?,
+                link.weight,
+            ))
+        })
+        .collect::<Vec<_>>();
+
+    let k = FORCE_IDEAL_DISTANCE;
+    let mut temperature = k * (count as f64).sqrt();
+    let cooling = temperature / FORCE_ITERATIONS as f64;
+
+    for _ in 0..FORCE_ITERATIONS {
+        let mut displacement = vec![(0.0f64, 0.0f64); count];
+
+        for i in 0..count {
+            for j in (i + 1)..count {
+                let dx = positions[i].0 - positions[j].0;
+                let dy = positions[i].1 - positions[j].1;
+                let distance = dx.hypot(dy).max(0.01);
+                let force = k * k / distance;
+                let (fx, fy) = (dx / distance * force, dy / distance * force);
+                displacement[i].0 += fx;
+                displacement[i].1 += fy;
+                displacement[j].0 -= fx;
+                displacement[j].1 -= fy;
+            }
+        }
+
+        // Heavier links are stiffer springs.
+        for &(a, b, weight) in &edges {
+            let dx = positions[a].0 - positions[b].0;
+            let dy = positions[a].1 - positions[b].1;
+            let distance = dx.hypot(dy).max(0.01);
+            let force = weight * distance * distance / k;
+            let (fx, fy) = (dx / distance * force, dy / distance * force);
+            displacement[a].0 -= fx;
+            displacement[a].1 -= fy;
+            displacement[b].0 += fx;
+            displacement[b].1 += fy;
+        }
+
+        for (position, delta) in positions.iter_mut().zip(displacement) {
+            let dx = delta.0 - position.0 * FORCE_GRAVITY;
+            let dy = delta.1 - position.1 * FORCE_GRAVITY;
+            let length = dx.hypot(dy).max(0.01);
+            let step = length.min(temperature);
+            position.0 += dx / length * step;
+            position.1 += dy / length * step;
+        }
+
+        temperature = (temperature - cooling).max(1.0);
+    }
+
+    let center_x = positions.iter().map(|p| p.0).sum::<f64>() / count as f64;
+    let center_y = positions.iter().map(|p| p.1).sum::<f64>() / count as f64;
+
+    notes
+        .iter()
+        .zip(positions)
+        .map(|(note, (x, y))| (note.id, x - center_x, y - center_y))
+        .collect()
+}
+
+const TREE_LEVEL_SPACING: f64 = 160.0;
+const TREE_SIBLING_SPACING: f64 = 220.0;
+
+/// Top-down tree rooted at `root`: y follows BFS depth (links followed in
+/// their direction) and each level is spread along x in BFS order so
+/// siblings stay together. Notes reached again through a cycle are leaves;
+/// notes unreachable from the root go on one extra row below the tree.
+fn tree_layout(notes: &[Note], links: &[Link], root: i64) -> Vec<(i64, f64, f64)> {
+    let adjacency = adjacency(links);
+
+    let mut levels: Vec<Vec<i64>> = vec![vec![root]];
+    let mut visited = HashSet::from([root]);
+    loop {
+        let mut next_level = Vec::new();
+        for current in levels.last().into_iter().flatten() {
+            for &child in adjacency.get(current).into_iter().flatten() {
+                if visited.insert(child) {
+                    next_level.push(child);
+                }
+            }
+        }
+        if next_level.is_empty() {
+            break;
+        }
+        levels.push(next_level);
+    }
+
+    let unreached = notes
+        .iter()
+        .map(|note| note.id)
+        .filter(|id| !visited.contains(id))
+        .collect::<Vec<_>>();
+    if !unreached.is_empty() {
+        levels.push(unreached);
+    }
+
+    let mut positions = Vec::with_capacity(notes.len());
+    for (depth, level) in levels.iter().enumerate() {
+        let width = (level.len() - 1) as f64 * TREE_SIBLING_SPACING;
+        for (slot, id) in level.iter().enumerate() {
+            let x = slot as f64 * TREE_SIBLING_SPACING - width / 2.0;
+            positions.push((*id, x, depth as f64 * TREE_LEVEL_SPACING));
+        }
+    }
+
+    positions
+}
+
+const SNIPPET_MAX_CHARS: usize = 150;
+const SNIPPET_MARK_START: &str = "<mark>";
+const SNIPPET_MARK_END: &str = "</mark>";
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+/// XML 1.0 cannot represent most control characters at all, so they are
+/// dropped before escaping.
+fn escape_xml(text: &str) -> String {
+    let cleaned = text
+        .chars()
+        .filter(|ch| !ch.is_control() || matches!(ch, '\t' | '\n' | '\r'))
+        .collect::<String>();
+    escape_html(&cleaned)
+}
+
+/// Renders the Markdown subset notes use: ATX headings, paragraphs, fenced
+/// code, block quotes, flat lists, rules, emphasis, code spans, links, images
+/// and `[[wikilinks]]`. Raw HTML is escaped rather than passed through and
+/// links with a scheme other than http, https or mailto are dropped, so the
+/// output is safe to insert into a page as is.
+fn render_markdown(content: &str) -> String {
+    let lines = content.lines().collect::<Vec<_>>();
+    let mut html = String::new();
+    render_blocks(&lines, 0, &mut html);
+    html
+}
+
+/// Block quotes nested deeper than this render their markers as text, so a
+/// note of nothing but `>` can't recurse the server off its stack.
+const MARKDOWN_MAX_QUOTE_DEPTH: usize = 32;
+
+fn render_blocks(lines: &[&str], depth: usize, html: &mut String) {
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i].trim_start();
+        if line.is_empty() {
+            i += 1;
+            continue;
+        }
+
+        if let Some(fence) = ["```", "~~~"].into_iter().find(|f| line.starts_with(f)) {
+            let language = line[fence.len()..].trim();
+            let mut code = String::new();
+            i += 1;
+            while i < lines.len() && !lines[i].trim_start().starts_with(fence) {
+                code.push_str(lines[i]);
+                code.push('\n');
+                i += 1;
+            }
+            i += 1;
+            if language.is_empty() {
+                html.push_str("<pre><code>");
+            } else {
+                html.push_str(&format!(
+                    "<pre><code class=\"language-{}\">",
+                    escape_html(language)
+                ));
+            }
+            html.push_str(&escape_html(&code));
+            html.push_str("</code></pre>\n");
+        } else if let Some((level, text)) = markdown_heading(line) {
+            html.push_str(&format!("<h{level}>"));
+            render_inline(text, html);
+            html.push_str(&format!("</h{level}>\n"));
+            i += 1;
+        } else if is_markdown_rule(line) {
+            html.push_str("<hr>\n");
+            i += 1;
+        } else if line.starts_with('>') && depth < MARKDOWN_MAX_QUOTE_DEPTH {
+            let mut quoted = Vec::new();
+            while let Some(rest) = lines.get(i).and_then(|l| l.trim_start().strip_prefix('>')) {
+                quoted.push(rest.strip_prefix(' ').unwrap_or(rest));
+                i += 1;
+            }
+            html.push_str("<blockquote>\n");
+            render_blocks(&quoted, depth + 1, html);
+            html.push_str("</blockquote>\n");
+        } else if let Some((start, _)) = markdown_list_item(line) {
+            let ordered = start.is_some();
+            match start {
+                Some(start) if start != 1 => html.push_str(&format!("<ol start=\"{start}\">\n")),
+                Some(_) => html.push_str("<ol>\n"),
+                None => html.push_str("<ul>\n"),
+            }
+            while let Some((_, text)) = lines
+                .get(i)
+                .and_then(|l| markdown_list_item(l.trim_start()))
+                .filter(|(item_start, _)| item_start.is_some() == ordered)
+            {
+                let mut item = text.to_string();
+                i += 1;
+                // Indented lines continue the item.
+                while let Some(next) = lines
+                    .get(i)
+                    .filter(|l| l.starts_with([' ', '\t']) && !l.trim().is_empty())
+                {
+                    item.push('\n');
+                    item.push_str(next.trim());
+                    i += 1;
+                }
+                html.push_str("<li>");
+                render_inline(&item, html);
+                html.push_str("</li>\n");
+            }
+            html.push_str(if ordered { "</ol>\n" } else { "</ul>\n" });
+        } else {
+            let mut paragraph = vec![line];
+            i += 1;
+            while let Some(next) = lines.get(i).map(|l| l.trim_start()) {
+                if next.is_empty() || starts_markdown_block(next) {
+                    break;
+                }
+                paragraph.push(next);
+                i += 1;
+            }
+            html.push_str("<p>");
+            render_inline(&paragraph.join("\n"), html);
+            html.push_str("</p>\n");
+        }
+    }
+}
+
+fn starts_markdown_block(line: &str) -> bool {
+    line.starts_with("```")
+        || line.starts_with("~~~")
+        || line.starts_with('>')
+        || markdown_heading(line).is_some()
+        || is_markdown_rule(line)
+        || markdown_list_item(line).is_some()
+}
+
+fn markdown_heading(line: &str) -> Option<(usize, &str)> {
+    let level = line.bytes().take_while(|&b| b == b'#').count();
+    let rest = &line[level..];
+    if !(1..=6).contains(&level) || !(rest.is_empty() || rest.starts_with([' ', '\t'])) {
+        return None;
+    }
+    Some((level, rest.trim().trim_end_matches('#').trim_end()))
+}
+
+fn is_markdown_rule(line: &str) -> bool {
+    let marks = line
+        .chars()
+        .filter(|ch| !ch.is_whitespace())
+        .collect::<String>();
+    marks.len() >= 3
+        && ['-', '*', '_']
+            .into_iter()
+            .any(|mark| marks.chars().all(|ch| ch == mark))
+}
+
+/// `Some(number)` for an ordered item, `None` for a bullet, and the item
+/// text.
+fn markdown_list_item(line: &str) -> Option<(Option<u64>, &str)> {
+    if let Some(text) = ["- ", "* ", "+ "]
+        .into_iter()
+        .find_map(|bullet| line.strip_prefix(bullet))
+    {
+        return Some((None, text));
+    }
+    let digits = line.bytes().take_while(u8::is_ascii_digit).count();
+    if digits == 0 || digits > 9 {
+        return None;
+    }
+    let text = line[digits..]
+        .strip_prefix(". ")
+        .or_else(|| line[digits..].strip_prefix(") "))?;
+    Some((line[..digits].parse().ok(), text))
+}
 
-        if !self.column_exists("notes", "parent_id")? {
-            self.conn.execute(
-                "ALTER TABLE notes ADD COLUMN parent_id INTEGER REFERENCES notes(id) ON DELETE SET NULL",
-                [],
-            )?;
+fn render_inline(text: &str, html: &mut String) {
+    let mut i = 0;
+    while i < text.len() {
+        let rest = &text[i..];
+        let ch = rest.chars().next().unwrap_or_default();
+
+        if ch == '\\' && rest[1..].starts_with(|c: char| c.is_ascii_punctuation()) {
+            html.push_str(&escape_html(&rest[1..2]));
+            i += 2;
+            continue;
         }
 
-        self.conn.execute(
-            "CREATE INDEX IF NOT EXISTS idx_notes_parent_id ON notes(parent_id)",
-            [],
-        )?;
+        if ch == '`' {
+            if let Some(end) = rest[1..].find('`') {
+                html.push_str("<code>");
+                html.push_str(&escape_html(&rest[1..1 + end]));
+                html.push_str("</code>");
+                i += end + 2;
+                continue;
+            }
+        }
 
-        Ok(())
-    }
+        if let Some(inner) = rest.strip_prefix("[[") {
+            if let Some(end) = inner.find("]]").filter(|&end| end > 0) {
+                let title = inner[..end].trim();
+                html.push_str(&format!(
+                    "<a class=\"wikilink\" data-title=\"{0}\">{0}</a>",
+                    escape_html(title)
+                ));
+                i += end + 4;
+                continue;
+            }
+        }
 
-    fn column_exists(&self, table: &str, column: &str) -> anyhow::Result<bool> {
-        let pragma = format!("PRAGMA table_info({table})");
-        let mut stmt = self.conn.prepare(&pragma)?;
-        let mut rows = stmt.query([])?;
-        while let Some(row) = rows.next()? {
-            let name: String = row.get(1)?;
-            if name == column {
-                return Ok(true);
+        let image = rest.starts_with("![");
+        if image || ch == '[' {
+            let label_start = if image { 2 } else { 1 };
+            if let Some((label, url, len)) = markdown_link(&rest[label_start..]) {
+                if image {
+                    if is_safe_url(url) {
+                        html.push_str(&format!(
+                            "<img src=\"{}\" alt=\"{}\">",
+                            escape_html(url),
+                            escape_html(label)
+                        ));
+                    } else {
+                        html.push_str(&escape_html(label));
+                    }
+                } else if is_safe_url(url) {
+                    html.push_str(&format!("<a href=\"{}\">", escape_html(url)));
+                    render_inline(label, html);
+                    html.push_str("</a>");
+                } else {
+                    render_inline(label, html);
+                }
+                i += label_start + len;
+                continue;
             }
         }
-        Ok(false)
+
+        if ch == '*' || ch == '_' {
+            let after_word = text[..i]
+                .chars()
+                .next_back()
+                .is_some_and(char::is_alphanumeric);
+            let strong = if ch == '*' { "**" } else { "__" };
+            let (marker, tag) = if rest.starts_with(strong) {
+                (strong, "strong")
+            } else {
+                (&rest[..1], "em")
+            };
+            let closing = rest[marker.len()..]
+                .find(marker)
+                .filter(|&end| end > 0)
+                .filter(|_| ch == '*' || !after_word);
+            if let Some(end) = closing {
+                html.push_str(&format!("<{tag}>"));
+                render_inline(&rest[marker.len()..marker.len() + end], html);
+                html.push_str(&format!("</{tag}>"));
+                i += end + 2 * marker.len();
+                continue;
+            }
+        }
+
+        html.push_str(&escape_html(&rest[..ch.len_utf8()]));
+        i += ch.len_utf8();
     }
+}
 
-    fn list_notes(&self) -> anyhow::Result<Vec<Note>> {
-        let mut stmt = self.conn.prepare(
-            r#"
-            SELECT id, title, subtitle, content, x, y, parent_id, updated_at
-            FROM notes
-            ORDER BY updated_at DESC
-            "#,
+/// Splits `label](url)...` into the label, the url and the length of both
+/// with their brackets. Parentheses inside the url must balance.
+fn markdown_link(text: &str) -> Option<(&str, &str, usize)> {
+    let label_end = text.find(']')?;
+    let rest = text[label_end + 1..].strip_prefix('(')?;
+    let mut depth = 0;
+    let url_end = rest.find(|ch| {
+        match ch {
+            '(' => depth += 1,
+            ')' if depth == 0 => return true,
+            ')' => depth -= 1,
+            _ => {}
+        }
+        false
+    })?;
+    Some((
+        &text[..label_end],
+        rest[..url_end].trim(),
+        label_end + url_end + 3,
+    ))
+}
+
+/// Relative urls are allowed; absolute ones only with a harmless scheme.
+/// Whitespace and control characters are ignored the way browsers ignore
+/// them, so `java\tscript:` does not slip through.
+fn is_safe_url(url: &str) -> bool {
+    let url = url
+        .chars()
+        .filter(|ch| !ch.is_whitespace() && !ch.is_control())
+        .collect::<String>()
+        .to_ascii_lowercase();
+    match url.find(['/', '?', '#', ':']) {
+        Some(end) if url[end..].starts_with(':') => {
+            matches!(&url[..end], "http" | "https" | "mailto")
+        }
+        _ => true,
+    }
+}
+
+/// Writes the live graph as GraphML. Node positions use the `x`/`y` keys
+/// Gephi picks up as coordinates and the title doubles as the node label.
+fn write_graphml(store: &impl Queries, out: &mut impl io::Write) -> anyhow::Result<()> {
+    out.write_all(
+        br#"<?xml version="1.0" encoding="UTF-8"?>
+<graphml xmlns="http://graphml.graphdrawing.org/xmlns" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" xsi:schemaLocation="http://graphml.graphdrawing.org/xmlns http://graphml.graphdrawing.org/xmlns/1.0/graphml.xsd">
+  <key id="label" for="node" attr.name="label" attr.type="string"/>
+  <key id="subtitle" for="node" attr.name="subtitle" attr.type="string"/>
+  <key id="x" for="node" attr.name="x" attr.type="double"/>
+  <key id="y" for="node" attr.name="y" attr.type="double"/>
+  <key id="weight" for="edge" attr.name="weight" attr.type="double"/>
+  <graph id="graphalfred" edgedefault="undirected">
+"#,
+    )?;
+
+    store.for_each_note(|note| {
+        writeln!(
+            out,
+            r#"    <node id="n{}"><data key="label">{}</data><data key="subtitle">{}</data><data key="x">{}</data><data key="y">{}</data></node>"#,
+            note.id,
+            escape_xml(&note.title),
+            escape_xml(&note.subtitle),
+            note.x,
+            note.y
+        )?;
+        Ok(())
+    })?;
+
+    let mut edge_index = 0usize;
+    store.for_each_link(|link| {
+        writeln!(
+            out,
+            r#"    <edge id="e{edge_index}" source="n{}" target="n{}" directed="{}"><data key="weight">{}</data></edge>"#,
+            link.source_id, link.target_id, link.directed, link.weight
         )?;
+        edge_index += 1;
+        Ok(())
+    })?;
 
-        let rows = stmt.query_map([], map_note_row)?;
-        let notes = rows.collect::<Result<Vec<_>, _>>()?;
+    out.write_all(b"  </graph>\n</graphml>\n")?;
+    Ok(())
+}
 
-        Ok(notes)
+/// Size of a file, 0 if it does not exist.
+fn file_size(path: &FsPath) -> io::Result<u64> {
+    match std::fs::metadata(path) {
+        Ok(metadata) => Ok(metadata.len()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(err) => Err(err),
     }
+}
 
-    fn get_note(&self, id: i64) -> anyhow::Result<Option<Note>> {
-        let mut stmt = self.conn.prepare(
-            r#"
-            SELECT id, title, subtitle, content, x, y, parent_id, updated_at
-            FROM notes
-            WHERE id = ?1
-            "#,
-        )?;
+/// Total size of the files directly inside `dir`.
+fn dir_size(dir: &FsPath) -> io::Result<u64> {
+    let mut total = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let metadata = entry?.metadata()?;
+        if metadata.is_file() {
+            total += metadata.len();
+        }
+    }
+    Ok(total)
+}
 
-        stmt.query_row([id], map_note_row)
-            .optional()
-            .map_err(Into::into)
+/// NaN or infinite coordinates would break layouts and viewport queries.
+fn check_position(x: f64, y: f64) -> StoreResult<()> {
+    for (field, value) in [("x", x), ("y", y)] {
+        if !value.is_finite() {
+            return Err(StoreError::Validation(format!(
+                "{field} must be a finite number, got {value}"
+            )));
+        }
     }
+    Ok(())
+}
 
-    fn note_exists(&self, id: i64) -> anyhow::Result<bool> {
-        let exists = self.conn.query_row(
-            "SELECT EXISTS(SELECT 1 FROM notes WHERE id = ?1)",
-            [id],
-            |row| row.get::<_, i64>(0),
-        )? == 1;
+const MIN_LINK_WEIGHT: f64 = 0.001;
+const MAX_LINK_WEIGHT: f64 = 1000.0;
+const LINK_WEIGHT_RANGE: &str = "weight must be between 0.001 and 1000";
 
-        Ok(exists)
+/// Link weights divide path lengths and scale spring forces, so they are
+/// kept in a range where neither can overflow.
+fn check_weight(weight: f64) -> StoreResult<()> {
+    if (MIN_LINK_WEIGHT..=MAX_LINK_WEIGHT).contains(&weight) {
+        Ok(())
+    } else {
+        Err(StoreError::Validation(format!(
+            "{LINK_WEIGHT_RANGE}, got {weight:?}"
+        )))
     }
+}
 
-    fn note_parent_id(&self, id: i64) -> anyhow::Result<Option<i64>> {
-        self.conn
-            .query_row("SELECT parent_id FROM notes WHERE id = ?1", [id], |row| {
-                row.get(0)
-            })
-            .optional()
-            .map(|value| value.flatten())
-            .map_err(Into::into)
+/// Serializes note metadata for storage. Only objects are accepted, so
+/// every attribute has a name to filter on.
+fn normalize_metadata(metadata: serde_json::Value) -> StoreResult<String> {
+    if !metadata.is_object() {
+        return Err(StoreError::Validation(
+            "metadata must be a JSON object".to_string(),
+        ));
     }
+    Ok(metadata.to_string())
+}
 
-    fn notes_share_scope(&self, a: i64, b: i64) -> anyhow::Result<bool> {
-        Ok(self.note_parent_id(a)? == self.note_parent_id(b)?)
+/// Turns `metadata.<key>=<value>` query parameters into SQLite JSON paths
+/// and the values to compare against. Dots in the key reach into nested
+/// objects.
+fn metadata_filters(params: &[(String, String)]) -> StoreResult<Vec<(String, String)>> {
+    let mut filters = Vec::new();
+    for (key, value) in params {
+        let Some(key) = key.strip_prefix("metadata.") else {
+            continue;
+        };
+        let mut path = String::from("$");
+        for segment in key.split('.') {
+            if segment.is_empty() || segment.contains('"') {
+                return Err(StoreError::Validation(format!(
+                    "invalid metadata filter key: metadata.{key}"
+                )));
+            }
+            path.push_str(&format!(".\"{segment}\""));
+        }
+        filters.push((path, value.clone()));
     }
+    Ok(filters)
+}
 
-    fn list_links(&self) -> anyhow::Result<Vec<Link>> {
-        let mut stmt = self.conn.prepare(
-            r#"
-            SELECT source_id, target_id
-            FROM links
-            ORDER BY source_id ASC, target_id ASC
-            "#,
-        )?;
+/// Accepts `#rgb`, `#rrggbb` or `#rrggbbaa`. Blank values mean no color.
+fn normalize_color(color: Option<String>) -> StoreResult<Option<String>> {
+    let Some(color) = color else {
+        return Ok(None);
+    };
+    let color = color.trim();
+    if color.is_empty() {
+        return Ok(None);
+    }
 
-        let rows = stmt.query_map([], |row| {
-            Ok(Link {
-                source_id: row.get(0)?,
-                target_id: row.get(1)?,
-            })
-        })?;
+    let valid = color.strip_prefix('#').is_some_and(|digits| {
+        matches!(digits.len(), 3 | 6 | 8) && digits.chars().all(|ch| ch.is_ascii_hexdigit())
+    });
+    if !valid {
+        return Err(StoreError::Validation(format!(
+            "invalid color {color}: expected a hex value like #3366ff"
+        )));
+    }
 
-        let links = rows.collect::<Result<Vec<_>, _>>()?;
-        Ok(links)
+    Ok(Some(color.to_string()))
+}
+
+fn normalize_icon(icon: Option<String>) -> Option<String> {
+    icon.map(|icon| icon.trim().to_string())
+        .filter(|icon| !icon.is_empty())
+}
+
+/// Titles referenced as `[[Title]]` in note content. Obsidian's
+/// `[[Title|alias]]` and `[[Title#heading]]` forms resolve to `Title`.
+fn wikilink_titles(content: &str) -> Vec<&str> {
+    let mut titles = Vec::new();
+    let mut rest = content;
+    while let Some(start) = rest.find("[[") {
+        rest = &rest[start + 2..];
+        let Some(end) = rest.find("]]") else {
+            break;
+        };
+        let mut inner = &rest[..end];
+        rest = &rest[end + 2..];
+
+        if let Some(nested) = inner.rfind("[[") {
+            inner = &inner[nested + 2..];
+        }
+        if inner.contains('\n') {
+            continue;
+        }
+
+        let title = inner.split(['|', '#']).next().unwrap_or_default().trim();
+        if !title.is_empty() {
+            titles.push(title);
+        }
     }
+    titles
+}
 
-    fn graph(&self) -> anyhow::Result<GraphResponse> {
-        Ok(GraphResponse {
-            notes: self.list_notes()?,
-            links: self.list_links()?,
+/// File name for a note, with characters that are unsafe in paths or would
+/// break a `[[wikilink]]` replaced.
+fn markdown_file_stem(title: &str) -> String {
+    let stem = title
+        .chars()
+        .map(|ch| {
+            if ch.is_control()
+                || matches!(
+                    ch,
+                    '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' | '[' | ']' | '#' | '^'
+                )
+            {
+                '-'
+            } else {
+                ch
+            }
         })
+        .collect::<String>();
+    let stem = stem.trim().trim_start_matches('.');
+
+    if stem.is_empty() {
+        "Untitled".to_string()
+    } else {
+        stem.to_string()
     }
+}
 
-    fn create_note(&mut self, payload: CreateNoteRequest) -> anyhow::Result<Note> {
-        let title = payload.title.trim();
-        if title.is_empty() {
-            return Err(anyhow!("title cannot be empty"));
+/// Gives every note a unique file stem. Collisions are compared
+/// case-insensitively, as on the default macOS file system, and resolved with
+/// " (2)", " (3)", ... suffixes in id order.
+fn markdown_file_stems(titles: &[(i64, String)]) -> HashMap<i64, String> {
+    let mut taken = HashSet::new();
+    let mut stems = HashMap::with_capacity(titles.len());
+    for (id, title) in titles {
+        let base = markdown_file_stem(title);
+        let mut stem = base.clone();
+        let mut suffix = 2;
+        while !taken.insert(stem.to_lowercase()) {
+            stem = format!("{base} ({suffix})");
+            suffix += 1;
         }
+        stems.insert(*id, stem);
+    }
+    stems
+}
 
-        let subtitle = payload.subtitle.unwrap_or_default();
-        let content = payload.content.unwrap_or_default();
+/// Writes the same document as `graph()`, one note or link at a time.
+fn write_json_export(store: &impl Queries, out: &mut impl io::Write) -> anyhow::Result<()> {
+    out.write_all(br#"{"notes":["#)?;
+    let mut first = true;
+    store.for_each_note(|note| {
+        if !std::mem::take(&mut first) {
+            out.write_all(b",")?;
+        }
+        serde_json::to_writer(&mut *out, &note)?;
+        Ok(())
+    })?;
 
-        let (x, y) = match (payload.x, payload.y) {
-            (Some(x), Some(y)) => (x, y),
-            _ => self.default_spawn_position()?,
+    out.write_all(br#"],"links":["#)?;
+    let mut first = true;
+    store.for_each_link(|link| {
+        if !std::mem::take(&mut first) {
+            out.write_all(b",")?;
+        }
+        serde_json::to_writer(&mut *out, &link)?;
+        Ok(())
+    })?;
+
+    out.write_all(b"]}\n")?;
+    Ok(())
+}
+
+/// Writes one JSON object per line: every note, then every link, each tagged
+/// with its `kind`.
+fn write_ndjson_export(store: &impl Queries, out: &mut impl io::Write) -> anyhow::Result<()> {
+    store.for_each_note(|note| {
+        serde_json::to_writer(&mut *out, &ExportRecord::Note(&note))?;
+        out.write_all(b"\n")?;
+        Ok(())
+    })?;
+    store.for_each_link(|link| {
+        serde_json::to_writer(&mut *out, &ExportRecord::Link(&link))?;
+        out.write_all(b"\n")?;
+        Ok(())
+    })?;
+    Ok(())
+}
+
+/// Collects the lines of an NDJSON import into a document. Blank lines are
+/// skipped; notes and links may come in any order.
+fn parse_ndjson_import(body: &[u8]) -> StoreResult<ImportDocument> {
+    let mut document = ImportDocument {
+        notes: Vec::new(),
+        links: Vec::new(),
+    };
+    for (index, line) in body.split(|&byte| byte == b'\n').enumerate() {
+        let line = line.trim_ascii();
+        if line.is_empty() {
+            continue;
+        }
+        let record = serde_json::from_slice(line).map_err(|err| {
+            StoreError::Validation(format!("invalid NDJSON on line {}: {err}", index + 1))
+        })?;
+        match record {
+            ImportRecord::Note(note) => document.notes.push(*note),
+            ImportRecord::Link(link) => document.links.push(link),
+        }
+    }
+    Ok(document)
+}
+
+/// Writes a zip with one Markdown file per live note: the content as the body
+/// followed by a `## Links` list of `[[wikilinks]]` to the notes it links to.
+/// Directed links only show up on their source note.
+fn write_markdown_zip(store: &impl Queries, out: &mut impl io::Write) -> anyhow::Result<()> {
+    let mut titles = Vec::new();
+    store.for_each_note(|note| {
+        titles.push((note.id, note.title));
+        Ok(())
+    })?;
+    let stems = markdown_file_stems(&titles);
+    let adjacency = adjacency(&store.list_links()?);
+
+    let mut archive = ZipWriter::new(io::Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    store.for_each_note(|note| {
+        // A note created between the two passes has no stem; it is left out.
+        let Some(stem) = stems.get(&note.id) else {
+            return Ok(());
         };
 
-        if let Some(parent_id) = payload.parent_id {
-            if !self.note_exists(parent_id)? {
-                return Err(anyhow!("parent note {parent_id} not found"));
+        let mut linked = adjacency
+            .get(&note.id)
+            .into_iter()
+            .flatten()
+            .filter_map(|other_id| stems.get(other_id))
+            .collect::<Vec<_>>();
+        linked.sort();
+        linked.dedup();
+
+        let mut body = note.content;
+        if !linked.is_empty() {
+            if !body.is_empty() {
+                body.push_str(if body.ends_with('\n') { "\n" } else { "\n\n" });
+            }
+            body.push_str("## Links\n\n");
+            for other in linked {
+                body.push_str(&format!("- [[{other}]]\n"));
             }
         }
 
-        self.conn.execute(
-            r#"
-            INSERT INTO notes (title, subtitle, content, x, y, parent_id)
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6)
-            "#,
-            params![title, subtitle, content, x, y, payload.parent_id],
+        archive.start_file(format!("{stem}.md"), options)?;
+        archive.write_all(body.as_bytes())?;
+        Ok(())
+    })?;
+
+    out.write_all(&archive.finish()?.into_inner())?;
+    Ok(())
+}
+
+/// Leading slice of the content, used when the match was not in the content.
+fn content_preview(content: &str) -> String {
+    let preview = content.chars().take(SNIPPET_MAX_CHARS).collect::<String>();
+    escape_html(preview.trim())
+}
+
+/// A raw search query split into free text and filter tokens.
+#[derive(Debug, Default)]
+struct SearchFilters {
+    text: String,
+    tags: Vec<String>,
+    updated_after: Option<String>,
+    updated_before: Option<String>,
+}
+
+/// Pulls `tag:<name>`, `updatedAfter:<date>` and `updatedBefore:<date>`
+/// tokens (prefixes are case-insensitive) out of `query`. Every other word,
+/// including unknown `prefix:value` tokens, stays in `text`.
+fn parse_search_filters(query: &str) -> SearchFilters {
+    let mut filters = SearchFilters::default();
+    let mut words = Vec::new();
+
+    for word in query.split_whitespace() {
+        let Some((prefix, value)) = word.split_once(':').filter(|(_, value)| !value.is_empty())
+        else {
+            words.push(word);
+            continue;
+        };
+        match prefix.to_ascii_lowercase().as_str() {
+            "tag" => {
+                if !filters
+                    .tags
+                    .iter()
+                    .any(|tag| tag.eq_ignore_ascii_case(value))
+                {
+                    filters.tags.push(value.to_string());
+                }
+            }
+            "updatedafter" => filters.updated_after = Some(value.to_string()),
+            "updatedbefore" => filters.updated_before = Some(value.to_string()),
+            _ => words.push(word),
+        }
+    }
+
+    filters.text = words.join(" ");
+    filters
+}
+
+const NOTE_SEARCH_MAX_MATCHES: usize = 1000;
+
+/// Non-overlapping occurrences of `query` in `content`, ignoring ASCII case
+/// like the SQL fallback does.
+fn content_matches(content: &str, query: &str) -> NoteSearchResponse {
+    let haystack = content.to_ascii_lowercase();
+    let needle = query.to_ascii_lowercase();
+    let length = query.chars().count();
+
+    let mut matches = Vec::new();
+    let mut total = 0;
+    let (mut line, mut column, mut offset, mut scanned) = (1, 1, 0, 0);
+    for (start, _) in haystack.match_indices(&needle) {
+        total += 1;
+        if matches.len() == NOTE_SEARCH_MAX_MATCHES {
+            continue;
+        }
+        for ch in content[scanned..start].chars() {
+            offset += 1;
+            if ch == '\n' {
+                line += 1;
+                column = 1;
+            } else {
+                column += 1;
+            }
+        }
+        scanned = start;
+        matches.push(ContentMatch {
+            line,
+            column,
+            offset,
+            length,
+        });
+    }
+
+    NoteSearchResponse { matches, total }
+}
+
+/// Snippet for LIKE fallback hits: a window around the first
+/// case-insensitive occurrence of the query, highlighted like tantivy's.
+fn substring_snippet(content: &str, query: &str) -> String {
+    let Some(start) = content
+        .to_ascii_lowercase()
+        .find(&query.to_ascii_lowercase())
+    else {
+        return content_preview(content);
+    };
+    let end = start + query.len();
+
+    let before = content[..start]
+        .chars()
+        .rev()
+        .take(SNIPPET_MAX_CHARS / 2)
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+        .collect::<String>();
+    let after = content[end..]
+        .chars()
+        .take(SNIPPET_MAX_CHARS / 2)
+        .collect::<String>();
+
+    format!(
+        "{}{SNIPPET_MARK_START}{}{SNIPPET_MARK_END}{}",
+        escape_html(before.trim_start()),
+        escape_html(&content[start..end]),
+        escape_html(after.trim_end()),
+    )
+}
+
+/// One page of ranked matches plus how many documents matched in total.
+struct SearchPage {
+    matches: Vec<SearchMatch>,
+    total: usize,
+}
+
+struct SearchMatch {
+    id: i64,
+    score: f32,
+    /// Highlighted content fragment, or `None` when the content itself did not
+    /// match (e.g. a title-only hit).
+    snippet: Option<String>,
+}
+
+/// When buffered index changes get committed: as soon as `max_pending`
+/// operations are queued, or otherwise by the background task that runs
+/// every `max_delay`. Searches may therefore miss the newest writes for up
+/// to `max_delay`.
+#[derive(Debug, Clone, Copy)]
+struct CommitPolicy {
+    max_pending: usize,
+    max_delay: Duration,
+}
+
+/// Read side of the search index. Cheap to clone and safe to share between
+/// request handlers; every clone observes the writer's reloads.
+#[derive(Clone)]
+struct SearchReader {
+    index: Index,
+    index_reader: IndexReader,
+    /// Set by every commit. The next search reloads the reader once, so
+    /// commits no longer pay for a reload nobody may need.
+    stale: Arc<AtomicBool>,
+    id_field: Field,
+    title_field: Field,
+    subtitle_field: Field,
+    content_field: Field,
+    tags_field: Field,
+    workspace_field: Field,
+}
+
+impl SearchReader {
+    /// A searcher that sees every commit made so far.
+    fn searcher(&self) -> anyhow::Result<Searcher> {
+        if self.stale.swap(false, Ordering::AcqRel) {
+            self.index_reader.reload()?;
+        }
+        Ok(self.index_reader.searcher())
+    }
+
+    fn mark_stale(&self) {
+        self.stale.store(true, Ordering::Release);
+    }
+
+    /// Makes sure a searcher can be taken and the index metadata on disk
+    /// still parses.
+    fn check(&self) -> anyhow::Result<()> {
+        self.searcher()?;
+        self.index
+            .load_metas()
+            .context("failed to read search index metadata")?;
+        Ok(())
+    }
+
+    /// Relative weight of a match in each field; titles rank highest.
+    const TITLE_BOOST: f32 = 3.0;
+    const SUBTITLE_BOOST: f32 = 2.0;
+    const CONTENT_BOOST: f32 = 1.0;
+    const TAGS_BOOST: f32 = 2.0;
+
+    fn text_fields(&self) -> Vec<Field> {
+        vec![
+            self.title_field,
+            self.subtitle_field,
+            self.content_field,
+            self.tags_field,
+        ]
+    }
+
+    fn field_boost(&self, field: Field) -> f32 {
+        if field == self.title_field {
+            Self::TITLE_BOOST
+        } else if field == self.subtitle_field {
+            Self::SUBTITLE_BOOST
+        } else if field == self.tags_field {
+            Self::TAGS_BOOST
+        } else {
+            Self::CONTENT_BOOST
+        }
+    }
+
+    /// Runs the query through the standard parser first. When that finds
+    /// nothing and `fuzziness` is non-zero, every query term is retried as a
+    /// fuzzy term allowing up to `fuzziness` edits.
+    /// Ranks matches in `workspace` and returns the best `limit`. When `keep`
+    /// is given, every match is ranked and only those it accepts count
+    /// towards the page and the total.
+    fn search_ids(
+        &self,
+        raw_query: &str,
+        workspace: i64,
+        limit: usize,
+        fuzziness: u8,
+        keep: Option<&dyn Fn(i64) -> anyhow::Result<bool>>,
+    ) -> anyhow::Result<SearchPage> {
+        let query = raw_query.trim();
+        if query.is_empty() {
+            return Ok(SearchPage {
+                matches: Vec::new(),
+                total: 0,
+            });
+        }
+
+        let mut parser = QueryParser::for_index(&self.index, self.text_fields());
+        for field in self.text_fields() {
+            parser.set_field_boost(field, self.field_boost(field));
+        }
+
+        let escaped = query.replace('"', " ");
+        let mut tantivy_query = parser
+            .parse_query(query)
+            .or_else(|_| parser.parse_query(&format!("\"{escaped}\"")))?;
+
+        let searcher = self.searcher()?;
+        let mut count = searcher.search(
+            &self.in_workspace(tantivy_query.box_clone(), workspace),
+            &Count,
+        )?;
+
+        if count == 0 && fuzziness > 0 {
+            tantivy_query = Box::new(self.fuzzy_query(query, fuzziness)?);
+            count = searcher.search(
+                &self.in_workspace(tantivy_query.box_clone(), workspace),
+                &Count,
+            )?;
+        }
+
+        // A filter can reject any of the matches, so it has to see all of them.
+        let ranked = if keep.is_some() { count } else { limit };
+        let docs = searcher.search(
+            &self.in_workspace(tantivy_query.box_clone(), workspace),
+            &TopDocs::with_limit(ranked.max(1)),
+        )?;
+
+        let mut snippets = SnippetGenerator::create(&searcher, &tantivy_query, self.content_field)?;
+        snippets.set_max_num_chars(SNIPPET_MAX_CHARS);
+
+        let mut matches = Vec::with_capacity(docs.len().min(limit));
+        let mut total = 0;
+        for (score, address) in docs {
+            let doc: TantivyDocument = searcher.doc(address)?;
+            let Some(id) = doc
+                .get_first(self.id_field)
+                .and_then(|field| field.as_u64())
+                .map(|id| id as i64)
+            else {
+                continue;
+            };
+            if let Some(keep) = keep {
+                if !keep(id)? {
+                    continue;
+                }
+            }
+
+            total += 1;
+            if matches.len() < limit {
+                let mut snippet = snippets.snippet_from_doc(&doc);
+                snippet.set_snippet_prefix_postfix(SNIPPET_MARK_START, SNIPPET_MARK_END);
+                matches.push(SearchMatch {
+                    id,
+                    score,
+                    snippet: (!snippet.is_empty()).then(|| snippet.to_html()),
+                });
+            }
+        }
+
+        Ok(SearchPage {
+            matches,
+            total: if keep.is_some() { total } else { count },
+        })
+    }
+
+    /// Other notes of `note`'s workspace ranked by how much text they share
+    /// with it ("more like this"), best `limit` first. Ids in `exclude` are
+    /// skipped.
+    fn similar_ids(
+        &self,
+        note: &Note,
+        limit: usize,
+        exclude: &HashSet<i64>,
+    ) -> anyhow::Result<Vec<(i64, f32)>> {
+        let text = |value: &str| vec![OwnedValue::Str(value.to_string())];
+        let query = MoreLikeThisQuery::builder()
+            // Notes are short and graphs small, so every term counts.
+            .with_min_doc_frequency(1)
+            .with_min_term_frequency(1)
+            .with_document_fields(vec![
+                (self.title_field, text(&note.title)),
+                (self.subtitle_field, text(&note.subtitle)),
+                (self.content_field, text(&note.content)),
+                (
+                    self.tags_field,
+                    note.tags.iter().cloned().map(OwnedValue::Str).collect(),
+                ),
+            ]);
+
+        let searcher = self.searcher()?;
+        let docs = searcher.search(
+            &self.in_workspace(Box::new(query), note.workspace_id),
+            &TopDocs::with_limit(limit + exclude.len() + 1),
         )?;
 
-        let id = self.conn.last_insert_rowid();
-        let note = self
-            .get_note(id)?
-            .ok_or_else(|| anyhow!("inserted note could not be read"))?;
+        let mut similar = Vec::with_capacity(limit);
+        for (score, address) in docs {
+            let doc: TantivyDocument = searcher.doc(address)?;
+            let Some(id) = doc
+                .get_first(self.id_field)
+                .and_then(|field| field.as_u64())
+                .map(|id| id as i64)
+            else {
+                continue;
+            };
+            if id != note.id && !exclude.contains(&id) {
+                similar.push((id, score));
+            }
+            if similar.len() == limit {
+                break;
+            }
+        }
+        Ok(similar)
+    }
+
+    /// Restricts `query` to documents of one workspace without changing scores.
+    fn in_workspace(&self, query: Box<dyn TantivyQuery>, workspace: i64) -> BooleanQuery {
+        let workspace_term = Term::from_field_u64(self.workspace_field, workspace as u64);
+        BooleanQuery::new(vec![
+            (Occur::Must, query),
+            (
+                Occur::Must,
+                Box::new(ConstScoreQuery::new(
+                    Box::new(TermQuery::new(workspace_term, IndexRecordOption::Basic)),
+                    0.0,
+                )),
+            ),
+        ])
+    }
 
-        if let Some(related_ids) = payload.related_ids {
-            for related_id in related_ids {
-                if related_id != id {
-                    self.upsert_link_raw(id, related_id)?;
-                }
+    fn fuzzy_query(&self, query: &str, fuzziness: u8) -> anyhow::Result<BooleanQuery> {
+        let mut clauses: Vec<(Occur, Box<dyn TantivyQuery>)> = Vec::new();
+        for field in self.text_fields() {
+            let mut analyzer = self.index.tokenizer_for_field(field)?;
+            let mut tokens = analyzer.token_stream(query);
+            while let Some(token) = tokens.next() {
+                let term = Term::from_field_text(field, &token.text);
+                let fuzzy = FuzzyTermQuery::new(term, fuzziness, true);
+                clauses.push((
+                    Occur::Should,
+                    Box::new(BoostQuery::new(Box::new(fuzzy), self.field_boost(field))),
+                ));
             }
         }
+        Ok(BooleanQuery::new(clauses))
+    }
+}
 
-        self.search.upsert_note(&note)?;
-
-        Ok(note)
+/// Name the analyzer of every text field is registered under.
+const SEARCH_TOKENIZER: &str = "note_text";
+
+/// Splits on non-alphanumerics, lowercases, reduces words to their stem
+/// (`running` and `runs` both become `run`) and folds accents (`Café` becomes
+/// `cafe`), so searches ignore case, word forms and diacritics. The index is
+/// rebuilt on every start, so a new `language` applies from the next one.
+fn search_analyzer(language: Option<Language>) -> TextAnalyzer {
+    let mut builder = TextAnalyzer::builder(SimpleTokenizer::default())
+        .filter(RemoveLongFilter::limit(40))
+        .filter(LowerCaser)
+        .dynamic();
+    if let Some(language) = language {
+        builder = builder.filter_dynamic(Stemmer::new(language));
     }
+    builder.filter_dynamic(AsciiFoldingFilter).build()
+}
 
-    fn default_spawn_position(&self) -> anyhow::Result<(f64, f64)> {
-        let count = self
-            .conn
-            .query_row("SELECT COUNT(*) FROM notes", [], |row| row.get::<_, i64>(0))?
-            as usize;
+/// Lowercases and folds accents in a whole string the way `search_analyzer`
+/// does per word. Backs the `fold()` SQL function used by the LIKE fallback.
+fn fold_text(text: &str) -> String {
+    let mut analyzer = TextAnalyzer::builder(RawTokenizer::default())
+        .filter(LowerCaser)
+        .filter(AsciiFoldingFilter)
+        .build();
+    let mut folded = String::with_capacity(text.len());
+    analyzer
+        .token_stream(text)
+        .process(&mut |token| folded.push_str(&token.text));
+    folded
+}
 
-        let ring = (count / 8) + 1;
-        let slot = count % 8;
-        let angle = (slot as f64 / 8.0) * std::f64::consts::TAU;
-        let radius = (ring as f64) * 140.0;
+/// Registers `fold(text)` on a connection; see `fold_text`.
+fn register_sql_functions(conn: &Connection) -> rusqlite::Result<()> {
+    conn.create_scalar_function(
+        "fold",
+        1,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| Ok(ctx.get::<Option<String>>(0)?.map(|text| fold_text(&text))),
+    )
+}
 
-        Ok((radius * angle.cos(), radius * angle.sin()))
-    }
+struct SearchIndex {
+    dir: PathBuf,
+    writer: IndexWriter,
+    reader: SearchReader,
+    commit_policy: CommitPolicy,
+    pending: usize,
+}
 
-    fn update_note(&mut self, id: i64, payload: UpdateNoteRequest) -> anyhow::Result<Note> {
-        if payload.title.trim().is_empty() {
-            return Err(anyhow!("title cannot be empty"));
-        }
+impl SearchIndex {
+    fn open(
+        index_dir: &FsPath,
+        commit_policy: CommitPolicy,
+        language: Option<Language>,
+        buffer_bytes: usize,
+    ) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(index_dir)?;
 
-        if let Some(Some(parent_id)) = payload.parent_id {
-            if parent_id == id {
-                return Err(anyhow!("a note cannot be its own parent"));
-            }
-            if !self.note_exists(parent_id)? {
-                return Err(anyhow!("parent note {parent_id} not found"));
+        let schema = Self::build_schema();
+        let index = if index_dir.join("meta.json").exists() {
+            let existing = Index::open_in_dir(index_dir)?;
+            if existing.schema() == schema {
+                existing
+            } else {
+                // The index is derived data and gets rebuilt from SQLite on
+                // open, so an outdated schema is simply recreated.
+                drop(existing);
+                std::fs::remove_dir_all(index_dir)?;
+                std::fs::create_dir_all(index_dir)?;
+                Index::create_in_dir(index_dir, schema)?
             }
-        }
-
-        let updated = if let Some(parent_id) = payload.parent_id {
-            self.conn.execute(
-                r#"
-                UPDATE notes
-                SET title = ?1,
-                    subtitle = ?2,
-                    content = ?3,
-                    x = ?4,
-                    y = ?5,
-                    parent_id = ?6
-                WHERE id = ?7
-                "#,
-                params![
-                    payload.title.trim(),
-                    payload.subtitle,
-                    payload.content,
-                    payload.x,
-                    payload.y,
-                    parent_id,
-                    id
-                ],
-            )?
         } else {
-            self.conn.execute(
-                r#"
-                UPDATE notes
-                SET title = ?1,
-                    subtitle = ?2,
-                    content = ?3,
-                    x = ?4,
-                    y = ?5
-                WHERE id = ?6
-                "#,
-                params![
-                    payload.title.trim(),
-                    payload.subtitle,
-                    payload.content,
-                    payload.x,
-                    payload.y,
-                    id
-                ],
-            )?
+            Index::create_in_dir(index_dir, schema)?
         };
 
-        if updated == 0 {
-            return Err(anyhow!("note {id} not found"));
-        }
-
-        self.prune_links_outside_scope(id)?;
-
-        if let Some(related_ids) = payload.related_ids {
-            self.sync_related_links(id, &related_ids)?;
-        }
+        index
+            .tokenizers()
+            .register(SEARCH_TOKENIZER, search_analyzer(language));
 
-        let note = self
-            .get_note(id)?
-            .ok_or_else(|| anyhow!("updated note {id} not found"))?;
+        let schema = index.schema();
+        let id_field = schema
+            .get_field("id")
+            .map_err(|_| anyhow!("search schema missing id field"))?;
+        let title_field = schema
+            .get_field("title")
+            .map_err(|_| anyhow!("search schema missing title field"))?;
+        let subtitle_field = schema
+            .get_field("subtitle")
+            .map_err(|_| anyhow!("search schema missing subtitle field"))?;
+        let content_field = schema
+            .get_field("content")
+            .map_err(|_| anyhow!("search schema missing content field"))?;
+        let tags_field = schema
+            .get_field("tags")
+            .map_err(|_| anyhow!("search schema missing tags field"))?;
+        let workspace_field = schema
+            .get_field("workspace")
+            .map_err(|_| anyhow!("search schema missing workspace field"))?;
+
+        // Tantivy splits the budget across up to 8 indexing threads, using
+        // fewer when each would get under 15 MB, and refuses a smaller budget.
+        let writer = index.writer(buffer_bytes).with_context(|| {
+            format!("opening the search index writer with {buffer_bytes} bytes")
+        })?;
+        // The background reload catches up on its own; `stale` covers the
+        // window before it does.
+        let index_reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::OnCommitWithDelay)
+            .try_into()?;
 
-        self.search.upsert_note(&note)?;
+        Ok(Self {
+            dir: index_dir.to_path_buf(),
+            writer,
+            reader: SearchReader {
+                index,
+                index_reader,
+                stale: Arc::new(AtomicBool::new(false)),
+                id_field,
+                title_field,
+                subtitle_field,
+                content_field,
+                tags_field,
+                workspace_field,
+            },
+            commit_policy,
+            pending: 0,
+        })
+    }
 
-        Ok(note)
+    fn build_schema() -> Schema {
+        let mut schema_builder = Schema::builder();
+        schema_builder.add_u64_field("id", STORED | INDEXED);
+        let text = TextOptions::default()
+            .set_indexing_options(
+                TextFieldIndexing::default()
+                    .set_tokenizer(SEARCH_TOKENIZER)
+                    .set_index_option(IndexRecordOption::WithFreqsAndPositions),
+            )
+            .set_stored();
+        schema_builder.add_text_field("title", text.clone());
+        schema_builder.add_text_field("subtitle", text.clone());
+        schema_builder.add_text_field("content", text.clone());
+        schema_builder.add_text_field("tags", text);
+        schema_builder.add_u64_field("workspace", INDEXED);
+        schema_builder.build()
     }
 
-    fn update_note_position(
-        &mut self,
-        id: i64,
-        payload: UpdatePositionRequest,
-    ) -> anyhow::Result<Note> {
-        let updated = self.conn.execute(
-            r#"
-            UPDATE notes
-            SET x = ?1,
-                y = ?2
-            WHERE id = ?3
-            "#,
-            params![payload.x, payload.y, id],
-        )?;
+    fn note_document(&self, note: &Note) -> TantivyDocument {
+        let fields = &self.reader;
+        let mut document = doc!(
+            fields.id_field => note.id as u64,
+            fields.title_field => note.title.clone(),
+            fields.subtitle_field => note.subtitle.clone(),
+            fields.content_field => note.content.clone(),
+            fields.workspace_field => note.workspace_id as u64,
+        );
+        for tag in &note.tags {
+            document.add_text(fields.tags_field, tag);
+        }
+        document
+    }
 
-        if updated == 0 {
-            return Err(anyhow!("note {id} not found"));
+    fn rebuild(&mut self, notes: &[Note]) -> anyhow::Result<()> {
+        self.writer.delete_all_documents()?;
+        for note in notes {
+            self.writer.add_document(self.note_document(note))?;
         }
+        self.writer.commit()?;
+        self.reader.mark_stale();
+        self.pending = 0;
+        Ok(())
+    }
 
-        let note = self
-            .get_note(id)?
-            .ok_or_else(|| anyhow!("updated note {id} not found"))?;
-        Ok(note)
+    fn upsert_note(&mut self, note: &Note) -> anyhow::Result<()> {
+        self.upsert_notes(std::slice::from_ref(note))
     }
 
-    fn delete_note(&mut self, id: i64) -> anyhow::Result<bool> {
-        let affected = self.conn.execute("DELETE FROM notes WHERE id = ?1", [id])?;
-        if affected > 0 {
-            self.search.delete_note(id)?;
-            Ok(true)
-        } else {
-            Ok(false)
+    fn upsert_notes(&mut self, notes: &[Note]) -> anyhow::Result<()> {
+        for note in notes {
+            self.writer
+                .delete_term(Term::from_field_u64(self.reader.id_field, note.id as u64));
+            self.writer.add_document(self.note_document(note))?;
         }
+        self.pending += notes.len();
+        self.commit_if_due()
     }
 
-    fn create_link(&mut self, payload: LinkRequest) -> anyhow::Result<Link> {
-        self.upsert_link_raw(payload.source_id, payload.target_id)
+    fn delete_note(&mut self, id: i64) -> anyhow::Result<()> {
+        self.delete_notes(&[id])
     }
 
-    fn delete_link(&mut self, payload: LinkRequest) -> anyhow::Result<bool> {
-        let (source_id, target_id) = normalize_edge(payload.source_id, payload.target_id)?;
-        let deleted = self.conn.execute(
-            "DELETE FROM links WHERE source_id = ?1 AND target_id = ?2",
-            params![source_id, target_id],
-        )?;
-
-        Ok(deleted > 0)
+    fn delete_notes(&mut self, ids: &[i64]) -> anyhow::Result<()> {
+        for &id in ids {
+            self.writer
+                .delete_term(Term::from_field_u64(self.reader.id_field, id as u64));
+        }
+        self.pending += ids.len();
+        self.commit_if_due()
     }
 
-    fn upsert_link_raw(&self, a: i64, b: i64) -> anyhow::Result<Link> {
-        let (source_id, target_id) = normalize_edge(a, b)?;
+    fn commit_if_due(&mut self) -> anyhow::Result<()> {
+        if self.pending >= self.commit_policy.max_pending {
+            self.flush()?;
+        }
+        Ok(())
+    }
 
-        if !self.note_exists(source_id)? || !self.note_exists(target_id)? {
-            return Err(anyhow!("both notes must exist before linking"));
+    /// Commits, merges every segment into one and deletes the files the
+    /// merge left behind.
+    fn compact(&mut self) -> anyhow::Result<()> {
+        self.flush()?;
+        let segments = self.reader.index.searchable_segment_ids()?;
+        if segments.len() > 1 {
+            self.writer.merge(&segments).wait()?;
+            self.reader.mark_stale();
         }
+        self.writer.garbage_collect_files().wait()?;
+        Ok(())
+    }
 
-        if !self.notes_share_scope(source_id, target_id)? {
-            return Err(anyhow!(
-                "links can only connect notes inside the same focus layer"
-            ));
+    /// Commits every buffered change; the next search picks it up.
+    fn flush(&mut self) -> anyhow::Result<()> {
+        if self.pending == 0 {
+            return Ok(());
         }
+        self.writer.commit()?;
+        self.reader.mark_stale();
+        self.pending = 0;
+        Ok(())
+    }
+}
 
-        self.conn.execute(
-            r#"
-            INSERT OR IGNORE INTO links (source_id, target_id)
-            VALUES (?1, ?2)
-            "#,
-            params![source_id, target_id],
-        )?;
+/// Shared handler state. Writes serialize on `store`, which owns the only
+/// writable connection and the index writer; reads run on `reads` and
+/// `search` without touching that lock.
+struct AppState {
+    store: Mutex<Store>,
+    reads: ReadPool,
+    search: SearchReader,
+    events: broadcast::Sender<ChangeEnvelope>,
+    search_limits: SearchLimits,
+    rendered: RenderCache,
+}
 
-        Ok(Link {
-            source_id,
-            target_id,
-        })
-    }
+/// Most notes kept in the render cache before the least recently used
+/// entry is evicted; deleted and merged notes age out the same way.
+const RENDER_CACHE_CAPACITY: usize = 1024;
+
+/// Rendered HTML of note content, one entry per note. An entry is reused
+/// while a hash of the content matches. `version` and `updated_at` can't
+/// tell: a replacing import re-inserts notes under the same ids at version
+/// 1, and `updated_at` only has second resolution.
+#[derive(Default)]
+struct RenderCache {
+    entries: Mutex<HashMap<i64, RenderedHtml>>,
+    clock: AtomicU64,
+}
 
-    fn sync_related_links(&mut self, note_id: i64, related_ids: &[i64]) -> anyhow::Result<()> {
-        let note_scope = self.note_parent_id(note_id)?;
-        let mut desired = HashSet::new();
-        for related_id in related_ids {
-            if *related_id == note_id {
-                continue;
+struct RenderedHtml {
+    content_hash: u64,
+    html: String,
+    last_used: u64,
+}
+
+impl RenderCache {
+    fn html(&self, note: &Note) -> String {
+        let tick = self.clock.fetch_add(1, Ordering::Relaxed);
+        let mut hasher = DefaultHasher::new();
+        note.content.hash(&mut hasher);
+        let content_hash = hasher.finish();
+        if let Some(entry) = lock_recovering(&self.entries, "render cache").get_mut(&note.id) {
+            if entry.content_hash == content_hash {
+                entry.last_used = tick;
+                return entry.html.clone();
             }
-            if self.note_exists(*related_id)? && self.note_parent_id(*related_id)? == note_scope {
-                desired.insert(normalize_edge(note_id, *related_id)?);
+        }
+
+        let html = render_markdown(&note.content);
+        let mut entries = lock_recovering(&self.entries, "render cache");
+        if entries.len() >= RENDER_CACHE_CAPACITY && !entries.contains_key(&note.id) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(id, _)| *id);
+            if let Some(id) = oldest {
+                entries.remove(&id);
             }
         }
+        entries.insert(
+            note.id,
+            RenderedHtml {
+                content_hash,
+                html: html.clone(),
+                last_used: tick,
+            },
+        );
+        html
+    }
+}
 
-        let mut stmt = self.conn.prepare(
-            r#"
-            SELECT source_id, target_id
-            FROM links
-            WHERE source_id = ?1 OR target_id = ?1
-            "#,
-        )?;
+/// Read-only SQLite connections handed out to GET handlers.
+struct ReadPool {
+    connections: Vec<Mutex<Connection>>,
+    next: AtomicUsize,
+    /// One permit per connection; waiting happens here, on the runtime,
+    /// rather than on a connection mutex that would park a worker thread.
+    permits: Semaphore,
+    timeout: Duration,
+}
 
-        let current_rows = stmt.query_map([note_id], |row| {
-            Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?))
-        })?;
+/// A connection checked out of the `ReadPool`, returned to it on drop.
+struct ReadConnection<'a> {
+    // Declared before the permit so the mutex is released first.
+    conn: MutexGuard<'a, Connection>,
+    _permit: SemaphorePermit<'a>,
+}
 
-        let current = current_rows.collect::<Result<HashSet<_>, _>>()?;
+impl Deref for ReadConnection<'_> {
+    type Target = Connection;
 
-        for edge in current.difference(&desired) {
-            self.conn.execute(
-                "DELETE FROM links WHERE source_id = ?1 AND target_id = ?2",
-                params![edge.0, edge.1],
-            )?;
-        }
+    fn deref(&self) -> &Connection {
+        &self.conn
+    }
+}
 
-        for edge in desired.difference(&current) {
-            self.conn.execute(
-                "INSERT OR IGNORE INTO links (source_id, target_id) VALUES (?1, ?2)",
-                params![edge.0, edge.1],
-            )?;
-        }
+impl ReadPool {
+    fn open(db_path: &FsPath, size: usize, timeout: Duration) -> anyhow::Result<Self> {
+        let connections = (0..size)
+            .map(|_| {
+                let conn = Connection::open_with_flags(
+                    db_path,
+                    OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+                )
+                .with_context(|| format!("failed to open read connection {}", db_path.display()))?;
+                // Readers can briefly collide with a commit on the write connection.
+                conn.busy_timeout(Duration::from_secs(5))?;
+                register_sql_functions(&conn)?;
+                Ok(Mutex::new(conn))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
 
-        Ok(())
+        Ok(Self {
+            connections,
+            next: AtomicUsize::new(0),
+            permits: Semaphore::new(size),
+            timeout,
+        })
     }
 
-    fn prune_links_outside_scope(&self, note_id: i64) -> anyhow::Result<()> {
-        let note_scope = self.note_parent_id(note_id)?;
-
-        let mut stmt = self.conn.prepare(
-            r#"
-            SELECT source_id, target_id
-            FROM links
-            WHERE source_id = ?1 OR target_id = ?1
-            "#,
-        )?;
+    /// Waits up to `timeout` for a free slot, then takes the first idle
+    /// connection starting from a rotating offset. `None` means the pool
+    /// stayed exhausted, so a stuck export can't queue reads forever.
+    async fn get(&self) -> Option<ReadConnection<'_>> {
+        let permit = tokio::time::timeout(self.timeout, self.permits.acquire())
+            .await
+            .ok()?
+            .ok()?;
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let count = self.connections.len();
+        // Holding a permit means at least one connection is unlocked.
+        let conn = (0..count)
+            .find_map(|offset| {
+                let slot = &self.connections[(start + offset) % count];
+                match slot.try_lock() {
+                    Ok(conn) => Some(conn),
+                    Err(TryLockError::Poisoned(_)) => {
+                        Some(lock_recovering(slot, "read connection"))
+                    }
+                    Err(TryLockError::WouldBlock) => None,
+                }
+            })
+            .unwrap_or_else(|| {
+                lock_recovering(&self.connections[start % count], "read connection")
+            });
+        Some(ReadConnection {
+            conn,
+            _permit: permit,
+        })
+    }
+}
 
-        let current_rows = stmt.query_map([note_id], |row| {
-            Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?))
-        })?;
+struct ReadSession<'a> {
+    conn: ReadConnection<'a>,
+    search: &'a SearchReader,
+    workspace: i64,
+}
 
-        for edge in current_rows {
-            let (source_id, target_id) = edge?;
-            let other_id = if source_id == note_id {
-                target_id
-            } else {
-                source_id
-            };
+impl Queries for ReadSession<'_> {
+    fn conn(&self) -> &Connection {
+        &self.conn
+    }
 
-            if self.note_parent_id(other_id)? != note_scope {
-                self.conn.execute(
-                    "DELETE FROM links WHERE source_id = ?1 AND target_id = ?2",
-                    params![source_id, target_id],
-                )?;
-            }
-        }
+    fn search_reader(&self) -> &SearchReader {
+        self.search
+    }
 
-        Ok(())
+    fn workspace(&self) -> i64 {
+        self.workspace
     }
+}
 
-    fn search_notes(&self, query: &str, limit: usize) -> anyhow::Result<Vec<Note>> {
-        let query = query.trim();
-        if query.is_empty() {
-            return Ok(Vec::new());
-        }
+const EXPORT_CHUNK_BYTES: usize = 64 * 1024;
 
-        let ids = self.search.search_ids(query, limit)?;
-        if !ids.is_empty() {
-            let mut results = Vec::with_capacity(ids.len());
-            for id in ids {
-                if let Some(note) = self.get_note(id)? {
-                    results.push(note);
-                }
-            }
-            return Ok(results);
-        }
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
 
-        let sql = r#"
-            SELECT id, title, subtitle, content, x, y, parent_id, updated_at
-            FROM notes
-            WHERE title LIKE ?1 OR subtitle LIKE ?1 OR content LIKE ?1
-            ORDER BY updated_at DESC
-            LIMIT ?2
-        "#;
-        let term = format!("%{query}%");
+/// Buffers output of a blocking export and forwards it to the response body
+/// in chunks.
+struct ChunkWriter {
+    sender: mpsc::Sender<io::Result<Vec<u8>>>,
+    buffer: Vec<u8>,
+}
 
-        let mut stmt = self.conn.prepare(sql)?;
-        let rows = stmt.query_map(params![term, limit as i64], map_note_row)?;
-        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+impl io::Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= EXPORT_CHUNK_BYTES {
+            self.flush()?;
+        }
+        Ok(buf.len())
     }
 
-    fn auto_layout(&mut self) -> anyhow::Result<GraphResponse> {
-        let notes = self.list_notes()?;
-        if notes.is_empty() {
-            return self.graph();
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.buffer, Vec::with_capacity(EXPORT_CHUNK_BYTES));
+        self.sender
+            .blocking_send(Ok(chunk))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "export client went away"))
+    }
+}
+
+/// Runs `produce` on a blocking thread with its own read connection and
+/// streams what it writes as the response body, so an export never has to
+/// sit in memory as a whole. A failure midway aborts the body.
+fn stream_export<F>(
+    state: Arc<AppState>,
+    scope: WorkspaceQuery,
+    content_type: &'static str,
+    produce: F,
+) -> Response
+where
+    F: FnOnce(&ReadSession<'_>, &mut ChunkWriter) -> anyhow::Result<()> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel(4);
+
+    tokio::task::spawn_blocking(move || {
+        let mut writer = ChunkWriter {
+            sender,
+            buffer: Vec::with_capacity(EXPORT_CHUNK_BYTES),
+        };
+        let session = tokio::runtime::Handle::current().block_on(read_session(&state, &scope));
+        let result = match session {
+            Ok(store) => {
+                produce(&store, &mut writer).and_then(|()| writer.flush().map_err(Into::into))
+            }
+            Err(_) => Err(anyhow!("no read connection available")),
+        };
+        if let Err(err) = result {
+            error!("export failed: {err:#}");
+            let _ = writer
+                .sender
+                .blocking_send(Err(io::Error::other(err.to_string())));
         }
+    });
 
-        let links = self.list_links()?;
+    (
+        [(header::CONTENT_TYPE, content_type)],
+        Body::from_stream(ReceiverStream::new(receiver)),
+    )
+        .into_response()
+}
 
-        let mut ids = notes.iter().map(|n| n.id).collect::<Vec<_>>();
-        ids.sort_by_key(|id| {
-            let degree = links
-                .iter()
-                .filter(|edge| edge.source_id == *id || edge.target_id == *id)
-                .count();
-            Reverse(degree)
-        });
+/// Locks the store for a write scoped to the requested workspace.
+fn lock_store<'a>(
+    state: &'a AppState,
+    scope: &WorkspaceQuery,
+) -> Result<MutexGuard<'a, Store>, ApiError> {
+    let mut store = lock_recovering(&state.store, "store");
+    store.workspace = scope.resolve(&store.conn)?;
+    Ok(store)
+}
 
-        let tx = self.conn.transaction()?;
+/// Takes the guard even if a request panicked while holding the lock, so one
+/// bad request cannot fail every later one. The poison flag is cleared, which
+/// keeps the warning to once per panic; a transaction the panicking request
+/// left open was already rolled back when it unwound.
+fn lock_recovering<'a, T>(mutex: &'a Mutex<T>, name: &str) -> MutexGuard<'a, T> {
+    mutex.lock().unwrap_or_else(|poisoned| {
+        warn!("recovering {name} mutex poisoned by a panicking request");
+        mutex.clear_poison();
+        poisoned.into_inner()
+    })
+}
 
-        let mut cursor = 0usize;
-        let mut ring = 0usize;
+async fn read_session<'a>(
+    state: &'a AppState,
+    scope: &WorkspaceQuery,
+) -> Result<ReadSession<'a>, ApiError> {
+    let conn = state.reads.get().await.ok_or_else(|| {
+        ApiError::ServiceUnavailable("all read connections are busy, try again".to_string())
+    })?;
+    let workspace = scope.resolve(&conn)?;
+    Ok(ReadSession {
+        conn,
+        search: &state.search,
+        workspace,
+    })
+}
 
-        while cursor < ids.len() {
-            if ring == 0 {
-                let id = ids[cursor];
-                tx.execute("UPDATE notes SET x = 0, y = 0 WHERE id = ?1", params![id])?;
-                cursor += 1;
-                ring += 1;
-                continue;
-            }
+/// Cheap liveness probe meant to be polled: one `SELECT 1` on a read
+/// connection and one look at the search index. Answers 503 naming the
+/// failing subsystem so load balancers can drop the instance.
+async fn health(State(state): State<Arc<AppState>>) -> (StatusCode, Json<HealthResponse>) {
+    let database = match state.reads.get().await {
+        Some(conn) => conn
+            .query_row("SELECT 1", [], |row| row.get::<_, i64>(0))
+            .map(|_| ())
+            .map_err(anyhow::Error::from),
+        None => Err(anyhow!("all read connections are busy")),
+    };
+    let search = state.search.check();
+
+    let describe = |result: &anyhow::Result<()>| match result {
+        Ok(_) => "ok".to_string(),
+        Err(err) => format!("{err:#}"),
+    };
+    let healthy = database.is_ok() && search.is_ok();
+    let response = HealthResponse {
+        status: if healthy { "ok" } else { "unavailable" },
+        database: describe(&database),
+        search: describe(&search),
+    };
+
+    if healthy {
+        (StatusCode::OK, Json(response))
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, Json(response))
+    }
+}
 
-            let slots = ring * 6;
-            let radius = ring as f64 * 180.0;
+async fn list_workspaces(
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+) -> Result<Json<WorkspacesResponse>, ApiError> {
+    let store = read_session(&state, &scope).await?;
+    Ok(Json(WorkspacesResponse {
+        workspaces: store.list_workspaces()?,
+    }))
+}
 
-            for slot in 0..slots {
-                if cursor >= ids.len() {
-                    break;
-                }
+async fn create_workspace(
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+    JsonBody(payload): JsonBody<CreateWorkspaceRequest>,
+) -> Result<(StatusCode, Json<Workspace>), ApiError> {
+    let mut store = lock_store(&state, &scope)?;
+    let workspace = store.create_workspace(payload)?;
+    Ok((StatusCode::CREATED, Json(workspace)))
+}
 
-                let angle = (slot as f64 / slots as f64) * std::f64::consts::TAU;
-                let x = radius * angle.cos();
-                let y = radius * angle.sin();
+async fn delete_workspace(
+    Path(id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+) -> Result<StatusCode, ApiError> {
+    let mut store = lock_store(&state, &scope)?;
+    if store.delete_workspace(id)? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ApiError::NotFound(format!("workspace {id} not found")))
+    }
+}
 
-                tx.execute(
-                    "UPDATE notes SET x = ?1, y = ?2 WHERE id = ?3",
-                    params![x, y, ids[cursor]],
-                )?;
+async fn watch_changes(
+    ws: WebSocketUpgrade,
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+) -> Result<Response, ApiError> {
+    let workspace = read_session(&state, &scope).await?.workspace();
+    let events = state.events.subscribe();
+    Ok(ws.on_upgrade(move |socket| stream_changes(socket, events, workspace)))
+}
 
-                cursor += 1;
-            }
+/// Forwards change events of one workspace to a WebSocket client as JSON text
+/// frames until either side goes away. Incoming messages are ignored.
+async fn stream_changes(
+    mut socket: WebSocket,
+    mut events: broadcast::Receiver<ChangeEnvelope>,
+    workspace: i64,
+) {
+    loop {
+        let envelope = tokio::select! {
+            received = events.recv() => match received {
+                Ok(envelope) if envelope.workspace == workspace => envelope,
+                Ok(_) => continue,
+                // Missed events cannot be replayed; have the client refetch.
+                Err(broadcast::error::RecvError::Lagged(_)) => ChangeEnvelope {
+                    workspace,
+                    event: ChangeEvent::GraphChanged,
+                },
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+        };
 
-            ring += 1;
+        let Ok(text) = serde_json::to_string(&envelope) else {
+            continue;
+        };
+        if socket.send(Message::Text(text.into())).await.is_err() {
+            break;
         }
-
-        tx.commit()?;
-
-        self.graph()
     }
 }
 
-fn map_note_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Note> {
-    Ok(Note {
-        id: row.get(0)?,
-        title: row.get(1)?,
-        subtitle: row.get(2)?,
-        content: row.get(3)?,
-        x: row.get(4)?,
-        y: row.get(5)?,
-        parent_id: row.get(6)?,
-        updated_at: row.get(7)?,
+async fn get_graph(
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+    Query(query): Query<GraphQuery>,
+) -> Result<Response, ApiError> {
+    let tag = query
+        .tag
+        .as_deref()
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty());
+    let q = query.q.as_deref().map(str::trim).filter(|q| !q.is_empty());
+    let store = read_session(&state, &scope).await?;
+    let graph = if tag.is_some() || q.is_some() {
+        store.filtered_graph(tag, q)?
+    } else {
+        store.graph_with_degrees()?
+    };
+    Ok(match query.format {
+        GraphFormat::Links => Json(graph).into_response(),
+        GraphFormat::Adjacency => Json(AdjacencyResponse {
+            adjacency: adjacency_list(&graph.notes, &graph.links),
+            notes: graph.notes,
+        })
+        .into_response(),
     })
 }
 
-fn normalize_edge(a: i64, b: i64) -> anyhow::Result<(i64, i64)> {
-    if a == b {
-        return Err(anyhow!("a note cannot link to itself"));
-    }
+async fn list_notes(
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+    Query(query): Query<ListNotesQuery>,
+    Query(params): Query<Vec<(String, String)>>,
+) -> Result<Json<NoteListResponse>, ApiError> {
+    let limit = query.limit.unwrap_or(100).clamp(1, 500);
+    let offset = query.offset.unwrap_or(0);
+    let metadata = metadata_filters(&params)?;
+    let store = read_session(&state, &scope).await?;
+    let updated = store.updated_range(
+        query.updated_after.as_deref(),
+        query.updated_before.as_deref(),
+    )?;
+    let (notes, total) = store.list_notes_page(
+        query.tag.as_deref(),
+        &updated,
+        &metadata,
+        query.sort.unwrap_or_default(),
+        limit,
+        offset,
+    )?;
+    Ok(Json(NoteListResponse {
+        notes,
+        total,
+        limit,
+        offset,
+    }))
+}
 
-    if a < b {
-        Ok((a, b))
-    } else {
-        Ok((b, a))
-    }
+/// Looks the note up by numeric id, or by `uuid` when the path segment is
+/// not a number.
+async fn get_note(
+    Path(key): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+    Query(query): Query<GetNoteQuery>,
+) -> Result<Response, ApiError> {
+    let store = read_session(&state, &scope).await?;
+    let note = match key.parse::<i64>() {
+        Ok(id) => store.get_note(id)?,
+        Err(_) => store.get_note_by_uuid(&key)?,
+    };
+    let Some(note) = note else {
+        return Err(ApiError::NotFound(format!("note {key} not found")));
+    };
+    let etag = note_etag(&note);
+    Ok(match query.render {
+        None => (etag, Json(note)).into_response(),
+        Some(NoteRender::Html) => {
+            let content_html = state.rendered.html(&note);
+            (etag, Json(RenderedNote { note, content_html })).into_response()
+        }
+    })
 }
 
-struct SearchIndex {
-    index: Index,
-    writer: IndexWriter,
-    reader: IndexReader,
-    id_field: Field,
-    title_field: Field,
-    subtitle_field: Field,
-    content_field: Field,
+async fn note_neighbors(
+    Path(id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+) -> Result<Json<GraphResponse>, ApiError> {
+    let store = read_session(&state, &scope).await?;
+    Ok(Json(store.neighbors(id)?))
 }
 
-impl SearchIndex {
-    fn open(index_dir: &FsPath) -> anyhow::Result<Self> {
-        std::fs::create_dir_all(index_dir)?;
+async fn note_links(
+    Path(id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+) -> Result<Json<NoteLinksResponse>, ApiError> {
+    let store = read_session(&state, &scope).await?;
+    Ok(Json(store.note_links(id)?))
+}
 
-        let schema = Self::build_schema();
-        let index = if index_dir.join("meta.json").exists() {
-            Index::open_in_dir(index_dir)?
-        } else {
-            Index::create_in_dir(index_dir, schema)?
-        };
+async fn search_in_note(
+    Path(id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+    Query(query): Query<NoteSearchQuery>,
+) -> Result<Json<NoteSearchResponse>, ApiError> {
+    let store = read_session(&state, &scope).await?;
+    Ok(Json(store.search_in_note(id, &query.q)?))
+}
 
-        let schema = index.schema();
-        let id_field = schema
-            .get_field("id")
-            .map_err(|_| anyhow!("search schema missing id field"))?;
-        let title_field = schema
-            .get_field("title")
-            .map_err(|_| anyhow!("search schema missing title field"))?;
-        let subtitle_field = schema
-            .get_field("subtitle")
-            .map_err(|_| anyhow!("search schema missing subtitle field"))?;
-        let content_field = schema
-            .get_field("content")
-            .map_err(|_| anyhow!("search schema missing content field"))?;
+async fn note_subgraph(
+    Path(id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+    Query(query): Query<SubgraphQuery>,
+) -> Result<Json<GraphResponse>, ApiError> {
+    let depth = query.depth.unwrap_or(2).clamp(1, 5);
+    let store = read_session(&state, &scope).await?;
+    Ok(Json(store.subgraph(id, depth)?))
+}
 
-        let writer = index.writer(25_000_000)?;
-        let reader = index.reader()?;
+async fn nearby_notes(
+    Path(id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+    Query(query): Query<NearbyQuery>,
+) -> Result<Json<NearbyResponse>, ApiError> {
+    let radius = query.radius.unwrap_or(300.0);
+    let limit = clamp_limit(query.limit);
+    let store = read_session(&state, &scope).await?;
+    Ok(Json(NearbyResponse {
+        notes: store.nearby(id, radius, limit)?,
+    }))
+}
 
-        Ok(Self {
-            index,
-            writer,
-            reader,
-            id_field,
-            title_field,
-            subtitle_field,
-            content_field,
-        })
-    }
+async fn note_suggestions(
+    Path(id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+    Query(query): Query<LimitQuery>,
+) -> Result<Json<SuggestionsResponse>, ApiError> {
+    let limit = clamp_limit(query.limit);
+    let store = read_session(&state, &scope).await?;
+    Ok(Json(SuggestionsResponse {
+        notes: store.suggestions(id, limit)?,
+    }))
+}
 
-    fn build_schema() -> Schema {
-        let mut schema_builder = Schema::builder();
-        schema_builder.add_u64_field("id", STORED | INDEXED);
-        schema_builder.add_text_field("title", TEXT | STORED);
-        schema_builder.add_text_field("subtitle", TEXT | STORED);
-        schema_builder.add_text_field("content", TEXT | STORED);
-        schema_builder.build()
-    }
+async fn similar_notes(
+    Path(id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+    Query(query): Query<SimilarQuery>,
+) -> Result<Json<SimilarResponse>, ApiError> {
+    let limit = clamp_limit(query.limit);
+    let store = read_session(&state, &scope).await?;
+    Ok(Json(SimilarResponse {
+        notes: store.similar(id, limit, query.exclude_linked.unwrap_or(false))?,
+    }))
+}
 
-    fn rebuild(&mut self, notes: &[Note]) -> anyhow::Result<()> {
-        self.writer.delete_all_documents()?;
-        for note in notes {
-            self.writer.add_document(doc!(
-                self.id_field => note.id as u64,
-                self.title_field => note.title.clone(),
-                self.subtitle_field => note.subtitle.clone(),
-                self.content_field => note.content.clone(),
-            ))?;
-        }
-        self.writer.commit()?;
-        self.reader.reload()?;
-        Ok(())
-    }
+async fn notes_in_viewport(
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+    Query(area): Query<ViewportQuery>,
+) -> Result<Json<GraphResponse>, ApiError> {
+    let store = read_session(&state, &scope).await?;
+    Ok(Json(store.viewport(&area)?))
+}
 
-    fn upsert_note(&mut self, note: &Note) -> anyhow::Result<()> {
-        self.writer
-            .delete_term(Term::from_field_u64(self.id_field, note.id as u64));
-        self.writer.add_document(doc!(
-            self.id_field => note.id as u64,
-            self.title_field => note.title.clone(),
-            self.subtitle_field => note.subtitle.clone(),
-            self.content_field => note.content.clone(),
-        ))?;
-        self.writer.commit()?;
-        self.reader.reload()?;
-        Ok(())
-    }
+async fn list_changes(
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+    Query(query): Query<ChangesQuery>,
+) -> Result<Json<ChangesResponse>, ApiError> {
+    let store = read_session(&state, &scope).await?;
+    Ok(Json(store.changes_since(&query.since)?))
+}
 
-    fn delete_note(&mut self, id: i64) -> anyhow::Result<()> {
-        self.writer
-            .delete_term(Term::from_field_u64(self.id_field, id as u64));
-        self.writer.commit()?;
-        self.reader.reload()?;
-        Ok(())
-    }
+async fn sync(
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+    Query(query): Query<SyncQuery>,
+) -> Result<Json<SyncResponse>, ApiError> {
+    let store = read_session(&state, &scope).await?;
+    Ok(Json(store.sync_since(query.since.as_deref())?))
+}
 
-    fn search_ids(&self, raw_query: &str, limit: usize) -> anyhow::Result<Vec<i64>> {
-        let query = raw_query.trim();
-        if query.is_empty() {
-            return Ok(Vec::new());
-        }
+async fn create_note(
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+    JsonBody(payload): JsonBody<CreateNoteRequest>,
+) -> Result<(StatusCode, Json<SavedNote>), ApiError> {
+    let mut store = lock_store(&state, &scope)?;
+    let note = store.create_note(payload)?;
+    Ok((StatusCode::CREATED, Json(note)))
+}
 
-        let parser = QueryParser::for_index(
-            &self.index,
-            vec![self.title_field, self.subtitle_field, self.content_field],
-        );
+async fn create_child_note(
+    Path(id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+    JsonBody(payload): JsonBody<CreateNoteRequest>,
+) -> Result<(StatusCode, Json<ChildNoteResponse>), ApiError> {
+    let mut store = lock_store(&state, &scope)?;
+    let created = store.create_child_note(id, payload)?;
+    Ok((StatusCode::CREATED, Json(created)))
+}
 
-        let escaped = query.replace('"', " ");
-        let tantivy_query = parser
-            .parse_query(query)
-            .or_else(|_| parser.parse_query(&format!("\"{escaped}\"")))?;
+async fn create_notes_bulk(
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+    JsonBody(payload): JsonBody<Vec<CreateNoteRequest>>,
+) -> Result<(StatusCode, Json<BulkCreateResponse>), ApiError> {
+    let mut store = lock_store(&state, &scope)?;
+    let notes = store.create_notes_bulk(payload)?;
+    Ok((StatusCode::CREATED, Json(BulkCreateResponse { notes })))
+}
 
-        let searcher = self.reader.searcher();
-        let docs = searcher.search(&tantivy_query, &TopDocs::with_limit(limit))?;
+async fn delete_notes_bulk(
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+    Query(query): Query<DeleteNoteQuery>,
+    JsonBody(ids): JsonBody<Vec<i64>>,
+) -> Result<Json<BulkDeleteResponse>, ApiError> {
+    let mut store = lock_store(&state, &scope)?;
+    let deleted = store.delete_notes(&ids, query.purge.unwrap_or(false))?;
+    Ok(Json(deleted))
+}
 
-        let mut ids = Vec::with_capacity(docs.len());
-        for (_score, address) in docs {
-            let doc: TantivyDocument = searcher.doc(address)?;
-            if let Some(value) = doc
-                .get_first(self.id_field)
-                .and_then(|field| field.as_u64())
-            {
-                ids.push(value as i64);
-            }
-        }
+async fn update_note(
+    Path(id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+    headers: HeaderMap,
+    JsonBody(mut payload): JsonBody<UpdateNoteRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    if payload.version.is_none() {
+        payload.version = if_match_version(&headers)?;
+    }
+    let mut store = lock_store(&state, &scope)?;
+    let saved = store.update_note(id, payload)?;
+    Ok((note_etag(&saved.note), Json(saved)))
+}
+
+async fn patch_note(
+    Path(id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+    headers: HeaderMap,
+    JsonBody(mut payload): JsonBody<PatchNoteRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    if payload.version.is_none() {
+        payload.version = if_match_version(&headers)?;
+    }
+    let mut store = lock_store(&state, &scope)?;
+    let saved = store.patch_note(id, payload)?;
+    Ok((note_etag(&saved.note), Json(saved)))
+}
 
-        Ok(ids)
+async fn update_note_position(
+    Path(id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+    headers: HeaderMap,
+    JsonBody(mut payload): JsonBody<UpdatePositionRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    if payload.version.is_none() {
+        payload.version = if_match_version(&headers)?;
     }
+    let mut store = lock_store(&state, &scope)?;
+    let note = store.update_note_position(id, payload)?;
+    Ok((note_etag(&note), Json(note)))
 }
 
-fn lock_store<'a>(state: &'a Arc<Mutex<Store>>) -> Result<MutexGuard<'a, Store>, ApiError> {
-    state
-        .lock()
-        .map_err(|_| ApiError::Internal(anyhow!("store mutex poisoned")))
+/// A note's version doubles as its entity tag.
+fn note_etag(note: &Note) -> [(HeaderName, String); 1] {
+    [(header::ETAG, format!("\"{}\"", note.version))]
 }
 
-async fn health() -> StatusCode {
-    StatusCode::OK
+/// Expected version from an `If-Match: "<version>"` header. `*` or no header
+/// means the write is unconditional.
+fn if_match_version(headers: &HeaderMap) -> Result<Option<i64>, ApiError> {
+    let Some(value) = headers.get(header::IF_MATCH) else {
+        return Ok(None);
+    };
+    let raw = value
+        .to_str()
+        .map_err(|_| ApiError::BadRequest("invalid If-Match header".to_string()))?
+        .trim();
+    if raw == "*" {
+        return Ok(None);
+    }
+
+    raw.trim_start_matches("W/")
+        .trim_matches('"')
+        .parse::<i64>()
+        .map(Some)
+        .map_err(|_| ApiError::BadRequest(format!("invalid If-Match header: {raw}")))
 }
 
-async fn get_graph(
-    State(state): State<Arc<Mutex<Store>>>,
-) -> Result<Json<GraphResponse>, ApiError> {
-    let store = lock_store(&state)?;
-    Ok(Json(store.graph()?))
+async fn delete_note_handler(
+    Path(id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+    Query(query): Query<DeleteNoteQuery>,
+) -> Result<StatusCode, ApiError> {
+    let mut store = lock_store(&state, &scope)?;
+    let deleted = if query.purge.unwrap_or(false) {
+        store.purge_note(id)?
+    } else {
+        store.delete_note(id)?
+    };
+    if deleted {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ApiError::NotFound(format!("note {id} not found")))
+    }
 }
 
-async fn get_note(
+async fn restore_note(
     Path(id): Path<i64>,
-    State(state): State<Arc<Mutex<Store>>>,
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
 ) -> Result<Json<Note>, ApiError> {
-    let store = lock_store(&state)?;
-    match store.get_note(id)? {
-        Some(note) => Ok(Json(note)),
-        None => Err(ApiError::NotFound(format!("note {id} not found"))),
-    }
+    let mut store = lock_store(&state, &scope)?;
+    let note = store.restore_note(id)?;
+    Ok(Json(note))
 }
 
-async fn create_note(
-    State(state): State<Arc<Mutex<Store>>>,
-    Json(payload): Json<CreateNoteRequest>,
+async fn merge_notes(
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+    JsonBody(payload): JsonBody<MergeNotesRequest>,
+) -> Result<Json<Note>, ApiError> {
+    let mut store = lock_store(&state, &scope)?;
+    let note = store.merge_notes(payload.keep_id, payload.merge_id)?;
+    Ok(Json(note))
+}
+
+async fn undo(
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+) -> Result<Json<UndoResponse>, ApiError> {
+    let mut store = lock_store(&state, &scope)?;
+    let undone = store.undo()?;
+    Ok(Json(undone))
+}
+
+async fn duplicate_note(
+    Path(id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+    payload: Option<JsonBody<DuplicateNoteRequest>>,
 ) -> Result<(StatusCode, Json<Note>), ApiError> {
-    let mut store = lock_store(&state)?;
-    let note = store.create_note(payload).map_err(map_store_error)?;
+    let payload = payload.map(|JsonBody(payload)| payload).unwrap_or_default();
+    let mut store = lock_store(&state, &scope)?;
+    let note = store.duplicate_note(id, payload.copy_links.unwrap_or(false))?;
     Ok((StatusCode::CREATED, Json(note)))
 }
 
-async fn update_note(
+async fn pin_note(
     Path(id): Path<i64>,
-    State(state): State<Arc<Mutex<Store>>>,
-    Json(payload): Json<UpdateNoteRequest>,
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
 ) -> Result<Json<Note>, ApiError> {
-    let mut store = lock_store(&state)?;
-    let note = store.update_note(id, payload).map_err(map_store_error)?;
+    let mut store = lock_store(&state, &scope)?;
+    let note = store.set_note_pinned(id, true)?;
     Ok(Json(note))
 }
 
-async fn update_note_position(
+async fn unpin_note(
     Path(id): Path<i64>,
-    State(state): State<Arc<Mutex<Store>>>,
-    Json(payload): Json<UpdatePositionRequest>,
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
 ) -> Result<Json<Note>, ApiError> {
-    let mut store = lock_store(&state)?;
-    let note = store
-        .update_note_position(id, payload)
-        .map_err(map_store_error)?;
+    let mut store = lock_store(&state, &scope)?;
+    let note = store.set_note_pinned(id, false)?;
     Ok(Json(note))
 }
 
-async fn delete_note_handler(
+async fn raise_note(
     Path(id): Path<i64>,
-    State(state): State<Arc<Mutex<Store>>>,
-) -> Result<StatusCode, ApiError> {
-    let mut store = lock_store(&state)?;
-    if store.delete_note(id)? {
-        Ok(StatusCode::NO_CONTENT)
-    } else {
-        Err(ApiError::NotFound(format!("note {id} not found")))
-    }
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+) -> Result<Json<Note>, ApiError> {
+    let mut store = lock_store(&state, &scope)?;
+    let note = store.restack_note(id, true)?;
+    Ok(Json(note))
+}
+
+async fn lower_note(
+    Path(id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+) -> Result<Json<Note>, ApiError> {
+    let mut store = lock_store(&state, &scope)?;
+    let note = store.restack_note(id, false)?;
+    Ok(Json(note))
+}
+
+async fn list_trash(
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+) -> Result<Json<TrashResponse>, ApiError> {
+    let store = read_session(&state, &scope).await?;
+    Ok(Json(TrashResponse {
+        notes: store.list_trash()?,
+    }))
 }
 
 async fn create_link(
-    State(state): State<Arc<Mutex<Store>>>,
-    Json(payload): Json<LinkRequest>,
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+    Query(query): Query<CreateLinkQuery>,
+    JsonBody(payload): JsonBody<LinkRequest>,
 ) -> Result<(StatusCode, Json<Link>), ApiError> {
-    let mut store = lock_store(&state)?;
-    let link = store.create_link(payload).map_err(map_store_error)?;
-    Ok((StatusCode::CREATED, Json(link)))
+    let mut store = lock_store(&state, &scope)?;
+    let (link, created) = store.create_link(payload, query.reject.unwrap_or(false))?;
+    let status = if created {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+    Ok((status, Json(link)))
+}
+
+async fn create_links_bulk(
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+    JsonBody(payload): JsonBody<Vec<LinkRequest>>,
+) -> Result<(StatusCode, Json<BulkLinkResponse>), ApiError> {
+    let mut store = lock_store(&state, &scope)?;
+    let response = store.create_links_bulk(payload)?;
+    let status = if response.created > 0 {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+    Ok((status, Json(response)))
 }
 
 async fn delete_link_handler(
-    State(state): State<Arc<Mutex<Store>>>,
-    Json(payload): Json<LinkRequest>,
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+    JsonBody(payload): JsonBody<LinkRequest>,
 ) -> Result<StatusCode, ApiError> {
-    let mut store = lock_store(&state)?;
+    let mut store = lock_store(&state, &scope)?;
     if store.delete_link(payload)? {
         Ok(StatusCode::NO_CONTENT)
     } else {
@@ -1040,35 +9027,253 @@ async fn delete_link_handler(
 }
 
 async fn search_notes(
-    State(state): State<Arc<Mutex<Store>>>,
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
     Query(query): Query<SearchQuery>,
 ) -> Result<Json<SearchResponse>, ApiError> {
-    let limit = query.limit.unwrap_or(20).clamp(1, 100);
-    let store = lock_store(&state)?;
-    let results = store
-        .search_notes(&query.q, limit)
-        .map_err(map_store_error)?;
-    Ok(Json(SearchResponse { results }))
+    let limit = state.search_limits.clamp(query.limit);
+    let fuzziness = query.fuzziness.unwrap_or(1).min(2);
+    let store = read_session(&state, &scope).await?;
+    let updated = store.updated_range(
+        query.updated_after.as_deref(),
+        query.updated_before.as_deref(),
+    )?;
+    let response = store.search_notes(&query.q, &updated, limit, fuzziness)?;
+    Ok(Json(response))
 }
 
-async fn auto_layout(
-    State(state): State<Arc<Mutex<Store>>>,
-) -> Result<Json<GraphResponse>, ApiError> {
-    let mut store = lock_store(&state)?;
-    Ok(Json(store.auto_layout()?))
-}
-
-fn map_store_error(err: anyhow::Error) -> ApiError {
-    let message = format!("{err:#}");
-    if message.contains("not found") {
-        ApiError::NotFound(message)
-    } else if message.contains("cannot")
-        || message.contains("must")
-        || message.contains("invalid")
-        || message.contains("can only")
-    {
-        ApiError::BadRequest(message)
-    } else {
-        ApiError::Internal(err)
+async fn suggest_titles(
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+    Query(query): Query<SuggestQuery>,
+) -> Result<Json<SuggestResponse>, ApiError> {
+    let limit = query.limit.unwrap_or(10).clamp(1, 50);
+    let store = read_session(&state, &scope).await?;
+    Ok(Json(SuggestResponse {
+        suggestions: store.suggest_titles(&query.q, limit)?,
+    }))
+}
+
+async fn list_orphans(
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+    Query(query): Query<LimitQuery>,
+) -> Result<Json<OrphansResponse>, ApiError> {
+    let limit = clamp_limit(query.limit);
+    let store = read_session(&state, &scope).await?;
+    Ok(Json(OrphansResponse {
+        notes: store.list_orphans(limit)?,
+    }))
+}
+
+async fn list_duplicates(
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+) -> Result<Json<DuplicatesResponse>, ApiError> {
+    let store = read_session(&state, &scope).await?;
+    Ok(Json(DuplicatesResponse {
+        groups: store.duplicate_titles()?,
+    }))
+}
+
+async fn shortest_path(
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+    Query(query): Query<PathQuery>,
+) -> Result<Json<PathResponse>, ApiError> {
+    let store = read_session(&state, &scope).await?;
+    match store.shortest_path(query.from, query.to)? {
+        Some((path, cost)) => Ok(Json(PathResponse {
+            length: path.len() - 1,
+            path,
+            cost,
+        })),
+        None => Err(ApiError::NotFound(format!(
+            "no path from note {} to note {}",
+            query.from, query.to
+        ))),
     }
 }
+
+async fn directed_cycles_handler(
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+) -> Result<Json<CyclesResponse>, ApiError> {
+    let store = read_session(&state, &scope).await?;
+    Ok(Json(CyclesResponse {
+        cycles: store.directed_cycles()?,
+    }))
+}
+
+async fn toposort(
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+) -> Result<Response, ApiError> {
+    let store = read_session(&state, &scope).await?;
+    Ok(match store.topological_order()? {
+        Ok(order) => Json(ToposortResponse { order }).into_response(),
+        Err(cycle) => (
+            StatusCode::CONFLICT,
+            Json(CycleConflict {
+                error: "directed links contain a cycle".to_string(),
+                cycle,
+            }),
+        )
+            .into_response(),
+    })
+}
+
+async fn connected_components(
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+) -> Result<Json<ComponentsResponse>, ApiError> {
+    let store = read_session(&state, &scope).await?;
+    Ok(Json(ComponentsResponse {
+        components: store.connected_components()?,
+    }))
+}
+
+async fn communities(
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+) -> Result<Json<CommunitiesResponse>, ApiError> {
+    let store = read_session(&state, &scope).await?;
+    let groups = store.communities()?;
+
+    let mut communities = groups
+        .iter()
+        .enumerate()
+        .flat_map(|(community, ids)| {
+            ids.iter()
+                .map(move |&note_id| NoteCommunity { note_id, community })
+        })
+        .collect::<Vec<_>>();
+    communities.sort_by_key(|entry| entry.note_id);
+
+    Ok(Json(CommunitiesResponse {
+        count: groups.len(),
+        communities,
+    }))
+}
+
+async fn graph_stats(
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+) -> Result<Json<GraphStats>, ApiError> {
+    let store = read_session(&state, &scope).await?;
+    Ok(Json(store.stats()?))
+}
+
+async fn centrality(
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+    Query(query): Query<CentralityQuery>,
+) -> Result<Json<CentralityResponse>, ApiError> {
+    let metric = query.metric.unwrap_or_default();
+    let store = read_session(&state, &scope).await?;
+    Ok(Json(CentralityResponse {
+        metric,
+        scores: store.centrality(metric)?,
+    }))
+}
+
+async fn auto_layout(
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+    Query(query): Query<LayoutQuery>,
+) -> Result<Json<LayoutResponse>, ApiError> {
+    let positions = read_session(&state, &scope)
+        .await?
+        .layout_positions(query.algorithm.unwrap_or_default(), query.root)?;
+    let mut store = lock_store(&state, &scope)?;
+    Ok(Json(store.apply_layout(&positions)?))
+}
+
+async fn export_graph(
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+) -> Response {
+    stream_export(state, scope, "application/json", |store, out| {
+        write_json_export(store, out)
+    })
+}
+
+async fn export_ndjson(
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+) -> Response {
+    stream_export(state, scope, NDJSON_CONTENT_TYPE, |store, out| {
+        write_ndjson_export(store, out)
+    })
+}
+
+async fn export_graphml(
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+) -> Response {
+    stream_export(state, scope, "application/graphml+xml", |store, out| {
+        write_graphml(store, out)
+    })
+}
+
+/// The zip format needs a seekable writer, so the archive is assembled in
+/// memory (compressed) before it is sent.
+async fn export_markdown(
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+) -> impl IntoResponse {
+    (
+        [(
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"graphalfred-notes.zip\"",
+        )],
+        stream_export(state, scope, "application/zip", |store, out| {
+            write_markdown_zip(store, out)
+        }),
+    )
+}
+
+async fn import_graph(
+    State(state): State<Arc<AppState>>,
+    Query(scope): Query<WorkspaceQuery>,
+    Query(query): Query<ImportQuery>,
+    ImportBody(document): ImportBody,
+) -> Result<Json<ImportResponse>, ApiError> {
+    let mut store = lock_store(&state, &scope)?;
+    let response = store.import_graph(document, query.mode.unwrap_or_default())?;
+    Ok(Json(response))
+}
+
+/// Regenerates the search index of every workspace from SQLite, e.g. after it
+/// drifted because of a crash mid-commit.
+async fn reindex(State(state): State<Arc<AppState>>) -> Result<Json<ReindexResponse>, ApiError> {
+    let started = Instant::now();
+    let mut store = lock_recovering(&state.store, "store");
+    let indexed_documents = store.rebuild_search()?;
+    drop(store);
+    Ok(Json(ReindexResponse {
+        indexed_documents,
+        elapsed_ms: started.elapsed().as_millis(),
+    }))
+}
+
+/// Reports integrity problems across all workspaces and, with `fix=true`,
+/// removes or repairs the offending rows.
+async fn validate(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ValidateQuery>,
+) -> Result<Json<ValidationReport>, ApiError> {
+    let mut store = lock_recovering(&state.store, "store");
+    Ok(Json(store.validate(query.fix.unwrap_or(false))?))
+}
+
+/// Reclaims the disk space left by deleted notes in SQLite and the search
+/// index. Writes wait on the store lock while this runs.
+async fn compact(State(state): State<Arc<AppState>>) -> Result<Json<CompactResponse>, ApiError> {
+    let mut store = lock_recovering(&state.store, "store");
+    Ok(Json(store.compact()?))
+}
+
+fn clamp_limit(limit: Option<usize>) -> usize {
+    limit.unwrap_or(20).clamp(1, 100)
+}